@@ -0,0 +1,155 @@
+// 受信したブロックをブロック番号ごとに保持し、欠落しているブロックを管理する
+
+pub struct BlockMap {
+    file_size: u64,
+    block_size: usize,
+    blocks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    received_bytes: u64,
+}
+
+impl BlockMap {
+    // 復元したファイルは1つのVecに置くので、大きさがusizeに収まらない場合はNoneを返す
+    pub fn new(file_size: u64, block_size: usize) -> Option<Self> {
+        let num_blocks = usize::try_from(file_size).ok()?.div_ceil(block_size);
+        Some(Self {
+            file_size,
+            block_size,
+            blocks: vec![None; num_blocks],
+            received: 0,
+            received_bytes: 0,
+        })
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn received_blocks(&self) -> usize {
+        self.received
+    }
+
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.blocks.len()
+    }
+
+    // ブロックの長さ
+    pub fn block_len(&self, index: u64) -> usize {
+        let offset = index.saturating_mul(self.block_size as u64);
+        self.file_size
+            .saturating_sub(offset)
+            .min(self.block_size as u64) as usize
+    }
+
+    pub fn block(&self, index: u64) -> Option<&[u8]> {
+        self.blocks.get(usize::try_from(index).ok()?)?.as_deref()
+    }
+
+    // 新しいブロックであればtrueを返す。範囲外や長さが合わないものは無視する
+    pub fn insert(&mut self, index: u64, data: &[u8]) -> bool {
+        let i = match usize::try_from(index) {
+            Ok(i) if i < self.blocks.len() => i,
+            _ => return false,
+        };
+        if self.blocks[i].is_some() || data.len() != self.block_len(index) {
+            return false;
+        }
+        self.blocks[i] = Some(data.to_vec());
+        self.received += 1;
+        self.received_bytes += data.len() as u64;
+        true
+    }
+
+    // 欠落しているブロック番号を昇順に返す
+    pub fn missing(&self) -> impl Iterator<Item = u64> + '_ {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.is_none())
+            .map(|(i, _)| i as u64)
+    }
+
+    pub fn finish(&self) -> Option<Vec<u8>> {
+        if !self.is_complete() {
+            return None;
+        }
+        let mut out = Vec::with_capacity(self.file_size as usize);
+        for block in self.blocks.iter().flatten() {
+            out.extend_from_slice(block);
+        }
+        Some(out)
+    }
+}
+
+// 欠落ブロックの一覧を "3, 10-12, 20" のような範囲表記にする
+pub fn format_ranges(mut it: impl Iterator<Item = u64>, max_ranges: usize) -> String {
+    let mut ranges: Vec<String> = Vec::new();
+    let mut current = match it.next() {
+        Some(x) => (x, x),
+        None => return String::new(),
+    };
+    for x in it.chain(std::iter::once(u64::MAX)) {
+        if x == current.1 + 1 {
+            current.1 = x;
+            continue;
+        }
+        if ranges.len() == max_ranges {
+            ranges.push("...".to_string());
+            break;
+        }
+        ranges.push(if current.0 == current.1 {
+            current.0.to_string()
+        } else {
+            format!("{}-{}", current.0, current.1)
+        });
+        current = (x, x);
+    }
+    ranges.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_finish() {
+        let mut blocks = BlockMap::new(10, 4).unwrap();
+        assert_eq!(blocks.num_blocks(), 3);
+        assert_eq!(blocks.block_len(2), 2);
+        assert_eq!(blocks.block_len(5), 0);
+        assert!(blocks.insert(2, b"ij"));
+        assert!(!blocks.insert(2, b"ij"));
+        // 長さが合わないものや範囲外は無視する
+        assert!(!blocks.insert(1, b"ef"));
+        assert!(!blocks.insert(3, b"kl"));
+        assert!(blocks.insert(0, b"abcd"));
+        assert_eq!(blocks.missing().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(blocks.received_bytes(), 6);
+        assert_eq!(blocks.finish(), None);
+        assert!(blocks.insert(1, b"efgh"));
+        assert!(blocks.is_complete());
+        assert_eq!(blocks.block(1), Some(&b"efgh"[..]));
+        assert_eq!(blocks.finish().unwrap(), b"abcdefghij");
+    }
+
+    #[test]
+    fn empty_file_is_complete() {
+        let blocks = BlockMap::new(0, 4).unwrap();
+        assert!(blocks.is_complete());
+        assert_eq!(blocks.finish().unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(format_ranges(std::iter::empty(), 10), "");
+        assert_eq!(
+            format_ranges([3, 10, 11, 12, 20].into_iter(), 10),
+            "3, 10-12, 20"
+        );
+        assert_eq!(format_ranges([1, 3, 5, 7].into_iter(), 2), "1, 3, ...");
+    }
+}
@@ -0,0 +1,466 @@
+// 送信側と受信側の組み合わせで確実に読み取れる設定を測るキャリブレーション
+//
+// 送信側はsteps()の手順を順に、手順ごとの設定でテストパターンを表示し、最後まで表示したら繰り返す。
+// 前半はセルを小さく・QRコードを大きくしていき (密度)、後半は送信間隔を短くしていく (速度)。
+// テストパターンは制御フレームで、実際の転送と同じ大きさになるようにペイロードを擬似乱数で埋める。
+//
+//   セッションID: 送信ごとのID
+//   シーケンス番号: 周回 (32bit) << 32 | 手順 (16bit) << 16 | 手順内の番号 (16bit)
+//   ペイロード: "CAL" + 擬似乱数
+//
+// 受信側は手順ごとに読み取れた画面を数え、前後の手順の画面を読み取った時点でその手順の測定を終える。
+// 確実に読み取れた中で最も多く送れる設定を、送信側に手入力するための設定コードにする。
+//
+// 設定コード: タグ(1B) + バージョン(1B) + 誤り訂正レベル(1B) + セルのピクセル数(1B) + 送信間隔(2B, LE)
+// を再送コードと同じ表現 (CRC + Base32) にしたもの
+
+use qrcode::EcLevel;
+
+use crate::capacity::block_size_of;
+use crate::header::{build_header, parse_header, FrameType, Header, HEADER_SIZE};
+use crate::resend::{self, CodeError};
+
+const PROBE_MAGIC: &[u8; 3] = b"CAL";
+const SETTINGS_TAG: u8 = 0xc5;
+
+// 密度の手順。セルの大きい順に、それぞれバージョンとレベルの組み合わせを容量の小さい順に試す
+const DENSITY_PIXEL_SIZES: [u8; 5] = [8, 6, 5, 4, 3];
+const DENSITY_VERSIONS: [i16; 4] = [10, 20, 30, 40];
+const DENSITY_EC_LEVELS: [EcLevel; 2] = [EcLevel::M, EcLevel::L];
+const DENSITY_INTERVAL: u16 = 500;
+const DENSITY_FRAMES: u16 = 5;
+// 速度の手順。送信間隔の長い順に試す
+const SPEED_VERSION: i16 = 20;
+const SPEED_EC_LEVEL: EcLevel = EcLevel::M;
+const SPEED_PIXEL_SIZE: u8 = 5;
+const SPEED_INTERVALS: [u16; 6] = [400, 300, 200, 150, 100, 50];
+const SPEED_FRAMES: u16 = 10;
+// 表示した画面のうちこの割合以上を読み取れた手順を、確実に読み取れたとみなす [%]
+const RELIABLE_PERCENT: u32 = 90;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    pub version: i16,
+    pub ec_level: EcLevel,
+    pub pixel_size: u8,
+    // 1画面の表示時間 [ミリ秒]
+    pub interval: u16,
+    // 表示する画面数
+    pub frames: u16,
+    // 送信間隔を測る手順
+    pub speed: bool,
+}
+
+pub fn steps() -> Vec<Step> {
+    let mut steps = Vec::new();
+    for pixel_size in DENSITY_PIXEL_SIZES {
+        for version in DENSITY_VERSIONS {
+            for ec_level in DENSITY_EC_LEVELS {
+                steps.push(Step {
+                    version,
+                    ec_level,
+                    pixel_size,
+                    interval: DENSITY_INTERVAL,
+                    frames: DENSITY_FRAMES,
+                    speed: false,
+                });
+            }
+        }
+    }
+    for interval in SPEED_INTERVALS {
+        steps.push(Step {
+            version: SPEED_VERSION,
+            ec_level: SPEED_EC_LEVEL,
+            pixel_size: SPEED_PIXEL_SIZE,
+            interval,
+            frames: SPEED_FRAMES,
+            speed: true,
+        });
+    }
+    steps
+}
+
+// テストパターンの1画面
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Probe {
+    pub run: u32,
+    pub cycle: u32,
+    pub step: u16,
+    pub index: u16,
+}
+
+// テストパターンのフレームを構築する。大きさはstepの設定で送信する場合のフレームと同じ
+pub fn build_probe(probe: &Probe, step: &Step) -> Vec<u8> {
+    let block_size = block_size_of(step.version, step.ec_level) as usize;
+    let seq = (probe.cycle as u64) << 32 | (probe.step as u64) << 16 | probe.index as u64;
+    let mut frame = vec![0; block_size];
+    let payload = &mut frame[HEADER_SIZE..];
+    payload[..PROBE_MAGIC.len()].copy_from_slice(PROBE_MAGIC);
+    // 0で埋めると実際のデータとはQRコードの模様が変わり、読み取りやすさも変わるので乱数で埋める
+    let mut x = seq ^ probe.run as u64 ^ 0x9e37_79b9_7f4a_7c15;
+    for b in &mut payload[PROBE_MAGIC.len()..] {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        *b = x as u8;
+    }
+    let size = payload.len() as u16;
+    build_header(
+        Header::new(FrameType::Control, probe.run, seq, size),
+        &mut frame,
+    );
+    frame
+}
+
+// 読み取ったペイロードがテストパターンであれば返す
+pub fn parse_probe(b: &[u8]) -> Option<Probe> {
+    let h = parse_header(b).ok()?;
+    if h.frame_type != FrameType::Control || !h.payload(b).starts_with(PROBE_MAGIC) {
+        return None;
+    }
+    Some(Probe {
+        run: h.session,
+        cycle: (h.seq >> 32) as u32,
+        step: (h.seq >> 16) as u16,
+        index: h.seq as u16,
+    })
+}
+
+// 手順ごとの測定結果
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Tally {
+    // 測定を終えた間に表示された画面数
+    pub shown: u32,
+    // そのうち読み取れた画面数
+    pub decoded: u32,
+}
+
+impl Tally {
+    pub fn is_measured(&self) -> bool {
+        self.shown > 0
+    }
+
+    pub fn is_reliable(&self) -> bool {
+        self.is_measured() && self.decoded * 100 >= self.shown * RELIABLE_PERCENT
+    }
+}
+
+// 受信側で手順ごとに読み取れた画面を数える
+pub struct Calibration {
+    steps: Vec<Step>,
+    tallies: Vec<Tally>,
+    // 読み取り中の送信のIDと、手順の通し番号 (周回 * 手順数 + 手順)
+    current: Option<(u32, u64)>,
+    // 読み取り中の手順で読み取れた手順内の番号
+    seen: Vec<bool>,
+    // 読み取り中の手順は途中から読み取り始めたので数えない
+    partial: bool,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Calibration {
+    pub fn new() -> Self {
+        let steps = steps();
+        Self {
+            tallies: vec![Tally::default(); steps.len()],
+            steps,
+            current: None,
+            seen: Vec::new(),
+            partial: true,
+        }
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    pub fn tallies(&self) -> &[Tally] {
+        &self.tallies
+    }
+
+    // テストパターンを1度でも読み取った
+    pub fn is_started(&self) -> bool {
+        self.current.is_some()
+    }
+
+    // (測定を終えた手順数, 手順数)
+    pub fn progress(&self) -> (usize, usize) {
+        let done = self.tallies.iter().filter(|t| t.is_measured()).count();
+        (done, self.steps.len())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.tallies.iter().all(|t| t.is_measured())
+    }
+
+    // 読み取ったテストパターンを記録する。次の手順に進んだ場合は前の手順の測定を終える
+    pub fn observe(&mut self, probe: &Probe) {
+        let n = self.steps.len() as u64;
+        let step = match self.steps.get(probe.step as usize) {
+            Some(s) if probe.index < s.frames => *s,
+            _ => return,
+        };
+        let pos = probe.cycle as u64 * n + probe.step as u64;
+        match self.current {
+            Some((run, cur)) if run == probe.run && pos == cur => {}
+            // 次の手順に進んだ。間の手順は1画面も読み取れなかった
+            Some((run, cur)) if run == probe.run && pos > cur && pos - cur <= n => {
+                if !self.partial {
+                    self.finish(cur, self.seen.iter().filter(|&&s| s).count() as u32);
+                }
+                for skipped in cur + 1..pos {
+                    self.finish(skipped, 0);
+                }
+                self.partial = false;
+                self.seen = vec![false; step.frames as usize];
+            }
+            // 最初の読み取り、送信のやり直し、または長く読み取れなかった後は途中から数え直す
+            _ => {
+                self.partial = true;
+                self.seen = vec![false; step.frames as usize];
+            }
+        }
+        self.current = Some((probe.run, pos));
+        self.seen[probe.index as usize] = true;
+    }
+
+    fn finish(&mut self, pos: u64, decoded: u32) {
+        let i = (pos % self.steps.len() as u64) as usize;
+        let tally = &mut self.tallies[i];
+        tally.shown += self.steps[i].frames as u32;
+        tally.decoded += decoded;
+    }
+
+    // 確実に読み取れた中で1画面に最も多く送れる設定と、確実に読み取れる最短の送信間隔。
+    // 容量が同じならセルの大きい方を選ぶ。密度の手順が1つも確実でなければNone
+    pub fn recommend(&self) -> Option<Recommendation> {
+        let results = || self.steps.iter().zip(&self.tallies);
+        let (best, _) = results()
+            .filter(|(s, t)| !s.speed && t.is_reliable())
+            .max_by_key(|(s, _)| (block_size_of(s.version, s.ec_level), s.pixel_size))?;
+        // 長い順に試しているので、確実でない間隔が現れるまでの最後の間隔
+        let interval = results()
+            .filter(|(s, t)| s.speed && t.is_measured())
+            .take_while(|(_, t)| t.is_reliable())
+            .last()
+            .map_or(DENSITY_INTERVAL, |(s, _)| s.interval);
+        Some(Recommendation {
+            version: best.version,
+            ec_level: best.ec_level,
+            pixel_size: best.pixel_size,
+            interval,
+        })
+    }
+}
+
+// 送信ページに入力する設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Recommendation {
+    pub version: i16,
+    pub ec_level: EcLevel,
+    pub pixel_size: u8,
+    pub interval: u16,
+}
+
+impl Recommendation {
+    pub fn encode(&self) -> String {
+        let mut bytes = vec![
+            SETTINGS_TAG,
+            self.version as u8,
+            self.ec_level as u8,
+            self.pixel_size,
+        ];
+        bytes.extend_from_slice(&self.interval.to_le_bytes());
+        resend::encode_bytes(bytes)
+    }
+
+    pub fn decode(code: &str) -> Result<Self, CodeError> {
+        let ec_level = |v| match v {
+            0 => Some(EcLevel::L),
+            1 => Some(EcLevel::M),
+            2 => Some(EcLevel::Q),
+            3 => Some(EcLevel::H),
+            _ => None,
+        };
+        // 再送コードを入力した場合などはタグや長さが合わない
+        match resend::decode_bytes(code)?[..] {
+            [SETTINGS_TAG, version @ 1..=40, ec, pixel_size @ 1..=u8::MAX, i0, i1] => Ok(Self {
+                version: version as i16,
+                ec_level: ec_level(ec).ok_or(CodeError::Checksum)?,
+                pixel_size,
+                interval: u16::from_le_bytes([i0, i1]),
+            }),
+            _ => Err(CodeError::Checksum),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capacity::usable_version;
+
+    // 手順stepの画面を、読み取れたかどうかを表すreadの通りに記録する
+    fn show(c: &mut Calibration, cycle: u32, step: usize, read: impl Fn(u16) -> bool) {
+        let frames = c.steps()[step].frames;
+        for index in (0..frames).filter(|&i| read(i)) {
+            let probe = Probe {
+                run: 1,
+                cycle,
+                step: step as u16,
+                index,
+            };
+            c.observe(&parse_probe(&build_probe(&probe, &c.steps()[step])).unwrap());
+        }
+    }
+
+    #[test]
+    fn probe_roundtrip() {
+        let probe = Probe {
+            run: 0xdead_beef,
+            cycle: 3,
+            step: 41,
+            index: 9,
+        };
+        let step = steps()[41];
+        let frame = build_probe(&probe, &step);
+        assert_eq!(
+            frame.len(),
+            block_size_of(step.version, step.ec_level) as usize
+        );
+        assert_eq!(parse_probe(&frame), Some(probe));
+
+        // 送信待機中のフレームはテストパターンではない
+        let mut idle = vec![0; HEADER_SIZE];
+        build_header(Header::new(FrameType::Control, 0, 0, 0), &mut idle);
+        assert_eq!(parse_probe(&idle), None);
+    }
+
+    #[test]
+    fn steps_are_usable() {
+        // 送信ページで選べる設定だけを試す
+        for s in steps() {
+            assert_eq!(usable_version(s.version, s.ec_level), Some(s.version));
+            assert!(s.frames > 0);
+        }
+        // 速度の手順は密度の手順の後に並ぶ
+        let steps = steps();
+        let first_speed = steps.iter().position(|s| s.speed).unwrap();
+        assert!(steps[first_speed..].iter().all(|s| s.speed));
+    }
+
+    #[test]
+    fn counts_between_observed_steps() {
+        let mut c = Calibration::new();
+        // 最初の手順は途中から読み取り始めたかもしれないので数えない
+        show(&mut c, 0, 0, |_| true);
+        show(&mut c, 0, 1, |i| i != 2);
+        // 手順2は1画面も読み取れなかった
+        show(&mut c, 0, 3, |_| true);
+        assert!(c.is_started());
+        let t = c.tallies();
+        assert!(!t[0].is_measured());
+        assert_eq!(
+            t[1],
+            Tally {
+                shown: 5,
+                decoded: 4
+            }
+        );
+        assert_eq!(
+            t[2],
+            Tally {
+                shown: 5,
+                decoded: 0
+            }
+        );
+        // 読み取り中の手順はまだ数えない
+        assert!(!t[3].is_measured());
+        assert_eq!(c.progress(), (2, steps().len()));
+    }
+
+    #[test]
+    fn restarts_on_new_run() {
+        let mut c = Calibration::new();
+        show(&mut c, 0, 5, |_| true);
+        show(&mut c, 0, 6, |_| true);
+        show(&mut c, 0, 7, |_| true);
+        // 送信側がページを開き直した
+        let probe = Probe {
+            run: 2,
+            cycle: 0,
+            step: 0,
+            index: 0,
+        };
+        c.observe(&probe);
+        let probe = Probe { step: 1, ..probe };
+        c.observe(&probe);
+        // 読み取り中だった手順7と、新しい送信の手順0は途中までしか読み取っていないので数えない
+        assert!(c.tallies()[6].is_measured());
+        assert!(!c.tallies()[7].is_measured());
+        assert!(!c.tallies()[0].is_measured());
+        // 範囲外の番号は無視する
+        c.observe(&Probe { step: 999, ..probe });
+        c.observe(&Probe {
+            index: 999,
+            ..probe
+        });
+        assert_eq!(c.current, Some((2, 1)));
+    }
+
+    #[test]
+    fn recommends_densest_reliable_setting() {
+        let mut c = Calibration::new();
+        assert_eq!(c.recommend(), None);
+        let steps = c.steps().to_vec();
+        for cycle in 0..2 {
+            for (i, s) in steps.iter().enumerate() {
+                // 6ピクセル以上ではバージョン30まで、5ピクセル以下ではバージョン20まで読み取れる。
+                // 送信間隔は150msまで読み取れる
+                let ok = if s.speed {
+                    s.interval >= 150
+                } else {
+                    s.version <= if s.pixel_size >= 6 { 30 } else { 20 }
+                };
+                show(&mut c, cycle, i, |_| ok);
+            }
+        }
+        show(&mut c, 2, 0, |_| true);
+        assert!(c.is_complete());
+        let r = c.recommend().unwrap();
+        assert_eq!(
+            r,
+            Recommendation {
+                version: 30,
+                ec_level: EcLevel::L,
+                pixel_size: 8,
+                interval: 150,
+            }
+        );
+    }
+
+    #[test]
+    fn settings_code_roundtrip() {
+        let r = Recommendation {
+            version: 27,
+            ec_level: EcLevel::Q,
+            pixel_size: 4,
+            interval: 175,
+        };
+        let code = r.encode();
+        assert_eq!(Recommendation::decode(&code).unwrap(), r);
+        assert_eq!(Recommendation::decode(&code.to_lowercase()).unwrap(), r);
+        // 再送コードは設定コードとして受け付けない
+        let resend_code = resend::encode(7, [1, 2].into_iter());
+        assert!(Recommendation::decode(&resend_code).is_err());
+        assert!(matches!(
+            Recommendation::decode("!!"),
+            Err(CodeError::InvalidChar('!'))
+        ));
+    }
+}
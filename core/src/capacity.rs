@@ -0,0 +1,88 @@
+// QRコードのバージョン・誤り訂正レベルごとに1フレームに載るバイト数
+
+use qrcode::EcLevel;
+
+use crate::codec;
+use crate::crypto;
+use crate::header::{HEADER_SIZE, METADATA_MIN_SIZE};
+
+// メタデータの拡張フィールドが全て載る大きさ
+const EXTENSIONS_SIZE: usize = (3 + codec::EXTENSION_SIZE) + (3 + crypto::EXTENSION_SIZE);
+pub const MIN_BLOCK_SIZE: u16 = (HEADER_SIZE + METADATA_MIN_SIZE + EXTENSIONS_SIZE) as u16;
+pub const MAX_VERSION: i16 = 40;
+
+pub fn block_size_of(version: i16, ec_level: EcLevel) -> u16 {
+    BINARY_SIZE_TABLE[(version - 1) as usize][ec_level as usize]
+}
+
+// メタデータが1フレームに収まらないバージョンは使えないので、収まるバージョンまで引き上げる
+pub fn usable_version(version: i16, ec_level: EcLevel) -> Option<i16> {
+    (version.max(1)..=MAX_VERSION).find(|&v| block_size_of(v, ec_level) >= MIN_BLOCK_SIZE)
+}
+
+// [version - 1][ec level]
+const BINARY_SIZE_TABLE: [[u16; 4]; 40] = [
+    [17, 14, 11, 7],
+    [32, 26, 20, 14],
+    [53, 42, 32, 24],
+    [78, 62, 46, 34],
+    [106, 84, 60, 44],
+    [134, 106, 74, 58],
+    [154, 122, 86, 64],
+    [192, 152, 108, 84],
+    [230, 180, 130, 98],
+    [271, 213, 151, 119],
+    [321, 251, 177, 137],
+    [367, 287, 203, 155],
+    [425, 311, 241, 177],
+    [458, 362, 258, 194],
+    [520, 412, 292, 220],
+    [586, 450, 322, 250],
+    [644, 504, 364, 280],
+    [718, 560, 394, 310],
+    [792, 624, 442, 338],
+    [858, 666, 482, 382],
+    [929, 711, 509, 403],
+    [1003, 779, 565, 439],
+    [1091, 857, 611, 461],
+    [1171, 911, 661, 511],
+    [1273, 997, 715, 535],
+    [1367, 1059, 751, 593],
+    [1465, 1125, 805, 625],
+    [1528, 1190, 868, 658],
+    [1628, 1264, 908, 698],
+    [1732, 1370, 982, 742],
+    [1840, 1452, 1030, 790],
+    [1952, 1538, 1112, 842],
+    [2068, 1628, 1168, 898],
+    [2188, 1722, 1228, 958],
+    [2303, 1809, 1283, 983],
+    [2431, 1911, 1351, 1051],
+    [2563, 1989, 1423, 1093],
+    [2699, 2099, 1499, 1139],
+    [2809, 2213, 1579, 1219],
+    [2953 - 2, 2331 - 2, 1663 - 2, 1273 - 2],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usable_versions() {
+        assert_eq!(block_size_of(40, EcLevel::L), 2951);
+        // 小さいバージョンは引き上げる
+        let v = usable_version(1, EcLevel::H).unwrap();
+        assert!(block_size_of(v, EcLevel::H) >= MIN_BLOCK_SIZE);
+        assert!(block_size_of(v - 1, EcLevel::H) < MIN_BLOCK_SIZE);
+        assert_eq!(usable_version(30, EcLevel::L), Some(30));
+        assert_eq!(usable_version(41, EcLevel::L), None);
+    }
+
+    #[test]
+    fn sizes_increase_with_version() {
+        for ec in [EcLevel::L, EcLevel::M, EcLevel::Q, EcLevel::H] {
+            assert!((1..MAX_VERSION).all(|v| block_size_of(v, ec) < block_size_of(v + 1, ec)));
+        }
+    }
+}
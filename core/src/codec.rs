@@ -0,0 +1,245 @@
+// 送信前のファイルの圧縮と、受信しながらの展開
+//
+// 圧縮した場合は圧縮後のバイト列をブロックに分割して送信し、
+// メタデータの拡張フィールド(EXT_CODEC)に 圧縮方式(1B) + 元のファイルサイズ(8B) を載せる。
+
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::deflate::core::CompressorOxide;
+use miniz_oxide::deflate::stream::deflate;
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+
+// 圧縮するかどうかを判定するため、ファイル中の数か所を試しに圧縮する
+const SAMPLE_SIZE: usize = 16 * 1024;
+const SAMPLE_COUNT: usize = 4;
+// サンプルがこの割合以下に縮む場合に圧縮する
+const RATIO_THRESHOLD: f64 = 0.9;
+const LEVEL: u8 = 6;
+const DEFLATE_BUFFER_SIZE: usize = 32 * 1024;
+const INFLATE_BUFFER_SIZE: usize = 32 * 1024;
+pub const EXTENSION_SIZE: usize = 1 + 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    None = 0,
+    Deflate = 1,
+}
+
+impl Codec {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::None),
+            1 => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "なし",
+            Self::Deflate => "deflate",
+        }
+    }
+}
+
+pub fn build_extension(codec: Codec, original_size: u64) -> Vec<u8> {
+    let mut v = vec![codec as u8];
+    v.extend_from_slice(&original_size.to_le_bytes());
+    v
+}
+
+pub fn parse_extension(b: &[u8]) -> Option<(Codec, u64)> {
+    if b.len() < EXTENSION_SIZE {
+        return None;
+    }
+    let mut size = [0u8; 8];
+    size.copy_from_slice(&b[1..9]);
+    Some((Codec::from_u8(b[0])?, u64::from_le_bytes(size)))
+}
+
+// サンプルが十分に縮む場合に圧縮する。
+// ファイルを少しずつ読み込む場合は最初に読み込んだ部分から判定する
+pub fn worth_compressing(data: &[u8]) -> bool {
+    let step = (data.len() / SAMPLE_COUNT).max(SAMPLE_SIZE);
+    let (mut total, mut compressed) = (0, 0);
+    for offset in (0..data.len()).step_by(step).take(SAMPLE_COUNT) {
+        let sample = &data[offset..(offset + SAMPLE_SIZE).min(data.len())];
+        total += sample.len();
+        compressed += compress_to_vec(sample, LEVEL).len();
+    }
+    total > 0 && compressed as f64 <= total as f64 * RATIO_THRESHOLD
+}
+
+// 少しずつ渡されるデータを順に圧縮する
+pub struct Deflater {
+    state: Box<CompressorOxide>,
+}
+
+impl Deflater {
+    pub fn new() -> Self {
+        let mut state = Box::<CompressorOxide>::default();
+        state.set_format_and_level(DataFormat::Raw, LEVEL);
+        Self { state }
+    }
+
+    pub fn push(&mut self, input: &[u8], out: &mut Vec<u8>) {
+        self.run(input, out, MZFlush::None);
+    }
+
+    pub fn finish(mut self, out: &mut Vec<u8>) {
+        self.run(&[], out, MZFlush::Finish);
+    }
+
+    fn run(&mut self, mut input: &[u8], out: &mut Vec<u8>, flush: MZFlush) {
+        let mut buf = vec![0u8; DEFLATE_BUFFER_SIZE];
+        loop {
+            let r = deflate(&mut self.state, input, &mut buf, flush);
+            input = &input[r.bytes_consumed..];
+            out.extend_from_slice(&buf[..r.bytes_written]);
+            match r.status {
+                Ok(MZStatus::StreamEnd) => break,
+                // 残りは圧縮器の中に溜めておき、次に渡したデータや最後の出力と一緒に書き出す
+                Ok(_) | Err(MZError::Buf)
+                    if flush == MZFlush::None
+                        && input.is_empty()
+                        && r.bytes_written < buf.len() =>
+                {
+                    break
+                }
+                Ok(_) | Err(MZError::Buf) => {}
+                Err(e) => panic!("deflate failed: {:?}", e),
+            }
+        }
+    }
+}
+
+impl Default for Deflater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 先頭から連続して受信できた部分を順に展開する
+pub struct Inflater {
+    state: Box<InflateState>,
+    output: Vec<u8>,
+    original_size: u64,
+    failed: bool,
+    done: bool,
+}
+
+impl Inflater {
+    pub fn new(original_size: u64) -> Self {
+        Self {
+            state: InflateState::new_boxed(DataFormat::Raw),
+            output: Vec::new(),
+            original_size,
+            failed: false,
+            done: false,
+        }
+    }
+
+    pub fn push(&mut self, mut input: &[u8]) {
+        let mut buf = vec![0u8; INFLATE_BUFFER_SIZE];
+        while !self.failed && !self.done {
+            let r = inflate(&mut self.state, input, &mut buf, MZFlush::None);
+            input = &input[r.bytes_consumed..];
+            self.output.extend_from_slice(&buf[..r.bytes_written]);
+            match r.status {
+                Ok(MZStatus::StreamEnd) => self.done = true,
+                // 入力が足りず進められない
+                Ok(_) | Err(MZError::Buf) => {}
+                Err(_) => self.failed = true,
+            }
+            // 元のサイズを超えて展開されるものは壊れている
+            if self.output.len() as u64 > self.original_size {
+                self.failed = true;
+            }
+            let stalled = r.bytes_consumed == 0 && r.bytes_written == 0;
+            if stalled || (input.is_empty() && r.bytes_written < buf.len()) {
+                break;
+            }
+        }
+    }
+
+    // 展開に失敗した場合やサイズが一致しない場合はNoneを返す
+    pub fn finish(self) -> Option<Vec<u8>> {
+        if self.failed || !self.done || self.output.len() as u64 != self.original_size {
+            return None;
+        }
+        Some(self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extension_roundtrip() {
+        let ext = build_extension(Codec::Deflate, 1 << 33);
+        assert_eq!(ext.len(), EXTENSION_SIZE);
+        assert_eq!(parse_extension(&ext), Some((Codec::Deflate, 1 << 33)));
+        assert_eq!(parse_extension(&ext[..4]), None);
+        assert_eq!(parse_extension(&[5; EXTENSION_SIZE]), None);
+    }
+
+    // 少しずつ渡して圧縮する
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut deflater = Deflater::new();
+        for chunk in data.chunks(1000) {
+            deflater.push(chunk, &mut out);
+        }
+        deflater.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn compress_only_when_effective() {
+        let text: Vec<u8> = b"hello, world. ".repeat(5000);
+        assert!(worth_compressing(&text));
+        let compressed = compress(&text);
+        assert!(compressed.len() < text.len() / 10);
+        // 一度に圧縮した場合と同じになる
+        assert_eq!(compressed, compress_to_vec(&text, LEVEL));
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..50000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        assert!(!worth_compressing(&noise));
+        assert!(!worth_compressing(&[]));
+    }
+
+    #[test]
+    fn inflate_in_pieces() {
+        let text: Vec<u8> = b"hello, world. ".repeat(5000);
+        let compressed = compress(&text);
+        let mut inflater = Inflater::new(text.len() as u64);
+        for chunk in compressed.chunks(7) {
+            inflater.push(chunk);
+        }
+        assert_eq!(inflater.finish().unwrap(), text);
+    }
+
+    #[test]
+    fn inflate_failures() {
+        let text: Vec<u8> = b"hello, world. ".repeat(5000);
+        let compressed = compress(&text);
+        // 元のサイズが違う
+        let mut inflater = Inflater::new(text.len() as u64 - 1);
+        inflater.push(&compressed);
+        assert_eq!(inflater.finish(), None);
+        // 途中までしかない
+        let mut inflater = Inflater::new(text.len() as u64);
+        inflater.push(&compressed[..compressed.len() / 2]);
+        assert_eq!(inflater.finish(), None);
+        // 壊れている
+        let mut inflater = Inflater::new(text.len() as u64);
+        inflater.push(&[0xff; 64]);
+        assert_eq!(inflater.finish(), None);
+    }
+}
@@ -0,0 +1,271 @@
+// パスフレーズによるファイルの暗号化
+//
+// パスフレーズからArgon2idで鍵を導出し、ChaCha20-Poly1305で64KiBごとに暗号化する。
+// 各チャンクのnonceは基準のnonceにチャンク番号をXORしたもので、
+// 追加認証データに チャンク番号(8B) + 最後のチャンクかどうか(1B) を入れて並べ替えや切り詰めを検出する。
+// メタデータの拡張フィールド(EXT_CIPHER)に
+// 方式(1B) + Argon2のメモリコスト(4B) + 反復回数(4B) + ソルト(16B) + nonce(12B) を載せる。
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+
+pub const SALT_SIZE: usize = 16;
+pub const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+// Argon2id + ChaCha20-Poly1305
+const ALGORITHM_ID: u8 = 1;
+// ブラウザ上で1秒程度で終わるパラメータ。メモリコストはKiB単位
+const M_COST: u32 = 19 * 1024;
+const T_COST: u32 = 2;
+// 受信したメタデータで過大なメモリや計算時間を要求されないようにする
+const MAX_M_COST: u32 = 256 * 1024;
+const MAX_T_COST: u32 = 16;
+pub const EXTENSION_SIZE: usize = 1 + 4 + 4 + SALT_SIZE + NONCE_SIZE;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CipherParams {
+    m_cost: u32,
+    t_cost: u32,
+    salt: [u8; SALT_SIZE],
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl CipherParams {
+    pub fn new(salt: [u8; SALT_SIZE], nonce: [u8; NONCE_SIZE]) -> Self {
+        Self {
+            m_cost: M_COST,
+            t_cost: T_COST,
+            salt,
+            nonce,
+        }
+    }
+
+    pub fn build_extension(&self) -> Vec<u8> {
+        let mut v = vec![ALGORITHM_ID];
+        v.extend_from_slice(&self.m_cost.to_le_bytes());
+        v.extend_from_slice(&self.t_cost.to_le_bytes());
+        v.extend_from_slice(&self.salt);
+        v.extend_from_slice(&self.nonce);
+        v
+    }
+
+    pub fn parse_extension(b: &[u8]) -> Option<Self> {
+        if b.len() < EXTENSION_SIZE || b[0] != ALGORITHM_ID {
+            return None;
+        }
+        let mut x = [0u8; 4];
+        x.copy_from_slice(&b[1..5]);
+        let m_cost = u32::from_le_bytes(x);
+        x.copy_from_slice(&b[5..9]);
+        let t_cost = u32::from_le_bytes(x);
+        if m_cost > MAX_M_COST || t_cost > MAX_T_COST {
+            return None;
+        }
+        let mut salt = [0u8; SALT_SIZE];
+        salt.copy_from_slice(&b[9..9 + SALT_SIZE]);
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&b[9 + SALT_SIZE..EXTENSION_SIZE]);
+        Some(Self {
+            m_cost,
+            t_cost,
+            salt,
+            nonce,
+        })
+    }
+
+    fn cipher(&self, passphrase: &str) -> Option<ChaCha20Poly1305> {
+        let params = Params::new(self.m_cost, self.t_cost, 1, Some(32)).ok()?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)
+            .ok()?;
+        Some(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+
+    fn chunk_nonce(&self, index: u64) -> Nonce {
+        let mut nonce = self.nonce;
+        for (n, i) in nonce.iter_mut().zip(index.to_le_bytes().iter()) {
+            *n ^= i;
+        }
+        nonce.into()
+    }
+}
+
+fn chunk_aad(index: u64, last: bool) -> [u8; 9] {
+    let mut aad = [0u8; 9];
+    aad[..8].copy_from_slice(&index.to_le_bytes());
+    aad[8] = last as u8;
+    aad
+}
+
+// 少しずつ渡されるデータを64KiBごとに暗号化する。
+// 最後のチャンクかどうかは次のデータが来るまで分からないので、1チャンク分を溜めておく
+pub struct Sealer {
+    params: CipherParams,
+    cipher: ChaCha20Poly1305,
+    pending: Vec<u8>,
+    index: u64,
+}
+
+impl Sealer {
+    pub fn new(params: &CipherParams, passphrase: &str) -> Self {
+        Self {
+            params: params.clone(),
+            cipher: params.cipher(passphrase).unwrap(),
+            pending: Vec::with_capacity(CHUNK_SIZE),
+            index: 0,
+        }
+    }
+
+    pub fn push(&mut self, mut data: &[u8], out: &mut Vec<u8>) {
+        while !data.is_empty() {
+            if self.pending.len() == CHUNK_SIZE {
+                self.seal(false, out);
+            }
+            let n = (CHUNK_SIZE - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..n]);
+            data = &data[n..];
+        }
+    }
+
+    // 空のデータも1チャンクとして暗号化する
+    pub fn finish(mut self, out: &mut Vec<u8>) {
+        self.seal(true, out);
+    }
+
+    fn seal(&mut self, last: bool, out: &mut Vec<u8>) {
+        let aad = chunk_aad(self.index, last);
+        let sealed = self
+            .cipher
+            .encrypt(
+                &self.params.chunk_nonce(self.index),
+                Payload {
+                    msg: &self.pending,
+                    aad: &aad,
+                },
+            )
+            .unwrap();
+        out.extend_from_slice(&sealed);
+        self.pending.clear();
+        self.index += 1;
+    }
+}
+
+// 暗号化した後の大きさ
+fn sealed_len(len: u64) -> u64 {
+    len + len.div_ceil(CHUNK_SIZE as u64).max(1) * TAG_SIZE as u64
+}
+
+pub fn encrypt(params: &CipherParams, passphrase: &str, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(sealed_len(data.len() as u64) as usize);
+    let mut sealer = Sealer::new(params, passphrase);
+    sealer.push(data, &mut out);
+    sealer.finish(&mut out);
+    out
+}
+
+// パスフレーズが違う場合やデータが改ざん・破損している場合はNoneを返す
+pub fn decrypt(params: &CipherParams, passphrase: &str, data: &[u8]) -> Option<Vec<u8>> {
+    if data.is_empty() {
+        return None;
+    }
+    let cipher = params.cipher(passphrase)?;
+    let num_chunks = data.len().div_ceil(CHUNK_SIZE + TAG_SIZE).max(1);
+    let mut out = Vec::with_capacity(data.len());
+    for (i, chunk) in data.chunks(CHUNK_SIZE + TAG_SIZE).enumerate() {
+        let aad = chunk_aad(i as u64, i + 1 == num_chunks);
+        let opened = cipher
+            .decrypt(
+                &params.chunk_nonce(i as u64),
+                Payload {
+                    msg: chunk,
+                    aad: &aad,
+                },
+            )
+            .ok()?;
+        out.extend_from_slice(&opened);
+    }
+    Some(out)
+}
+
+// 送信ごとにソルトとnonceを生成する。ブラウザではcrypto.getRandomValues()、ネイティブではOSの乱数を使う
+pub fn random_params() -> CipherParams {
+    let mut salt = [0u8; SALT_SIZE];
+    let mut nonce = [0u8; NONCE_SIZE];
+    getrandom::getrandom(&mut salt).unwrap();
+    getrandom::getrandom(&mut nonce).unwrap();
+    CipherParams::new(salt, nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> CipherParams {
+        CipherParams::new([1; SALT_SIZE], [2; NONCE_SIZE])
+    }
+
+    #[test]
+    fn extension_roundtrip() {
+        let p = random_params();
+        let ext = p.build_extension();
+        assert_eq!(ext.len(), EXTENSION_SIZE);
+        assert_eq!(CipherParams::parse_extension(&ext), Some(p));
+        assert_eq!(CipherParams::parse_extension(&ext[1..]), None);
+        // 過大なメモリコストは受け付けない
+        let mut ext = params().build_extension();
+        ext[1..5].copy_from_slice(&(MAX_M_COST + 1).to_le_bytes());
+        assert_eq!(CipherParams::parse_extension(&ext), None);
+        // 過大な反復回数も受け付けない
+        let mut ext = params().build_extension();
+        ext[5..9].copy_from_slice(&MAX_T_COST.to_le_bytes());
+        assert!(CipherParams::parse_extension(&ext).is_some());
+        ext[5..9].copy_from_slice(&(MAX_T_COST + 1).to_le_bytes());
+        assert_eq!(CipherParams::parse_extension(&ext), None);
+    }
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        let p = params();
+        // 複数のチャンクにまたがるデータと空のデータ
+        for data in [vec![3u8; CHUNK_SIZE * 2 + 100], Vec::new()] {
+            let sealed = encrypt(&p, "pass", &data);
+            let num_chunks = data.len().div_ceil(CHUNK_SIZE).max(1);
+            assert_eq!(sealed.len(), data.len() + num_chunks * TAG_SIZE);
+            assert_eq!(decrypt(&p, "pass", &sealed).unwrap(), data);
+            assert_eq!(decrypt(&p, "word", &sealed), None);
+        }
+    }
+
+    #[test]
+    fn sealing_in_pieces() {
+        let p = params();
+        // チャンクの境界で終わるデータも、最後のチャンクは最後に渡した部分になる
+        let data = vec![5u8; CHUNK_SIZE * 2];
+        let mut sealed = Vec::new();
+        let mut sealer = Sealer::new(&p, "pass");
+        for piece in data.chunks(1000) {
+            sealer.push(piece, &mut sealed);
+        }
+        sealer.finish(&mut sealed);
+        assert_eq!(sealed, encrypt(&p, "pass", &data));
+        assert_eq!(sealed.len() as u64, sealed_len(data.len() as u64));
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let p = params();
+        let data = vec![3u8; CHUNK_SIZE + 100];
+        let sealed = encrypt(&p, "pass", &data);
+        let mut modified = sealed.clone();
+        modified[10] ^= 1;
+        assert_eq!(decrypt(&p, "pass", &modified), None);
+        // 最後のチャンクを切り詰める
+        assert_eq!(decrypt(&p, "pass", &sealed[..CHUNK_SIZE + TAG_SIZE]), None);
+        assert_eq!(decrypt(&p, "pass", &[]), None);
+        let other = CipherParams::new([1; SALT_SIZE], [3; NONCE_SIZE]);
+        assert_eq!(decrypt(&other, "pass", &sealed), None);
+    }
+}
@@ -0,0 +1,754 @@
+// 読み取ったQRコードのペイロードからファイルを復元する。
+// ブラウザに依存しないので、受信ページとコマンドラインのデコーダの両方から使う
+
+use std::collections::HashSet;
+
+use sha2::{Digest, Sha256};
+
+use crate::blockmap::BlockMap;
+use crate::codec::{self, Codec, Inflater};
+use crate::crypto::{self, CipherParams};
+use crate::fountain;
+use crate::header::{
+    build_header, parse_header, parse_metadata, FrameType, Header, Mode, ParseError, DIGEST_SIZE,
+    EXT_CIPHER, EXT_CODEC, HEADER_SIZE,
+};
+
+// メタデータより先に読み取れたフレームを保持しておく上限
+const MAX_PENDING_FRAMES: usize = 4096;
+
+// ペイロードを処理した結果、受信状態に起きた変化
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    // 送信側がファイルの選択を待っている
+    Waiting,
+    // 新しい転送のメタデータを受信した
+    Metadata,
+    // 同じファイルの再送が始まったので、そのセッションに切り替えた
+    Resent,
+    // seq番目のブロックを受信した
+    Block(u64),
+    // ファウンテン符号のシンボルで新たにブロックを復元できた
+    Symbol,
+    // 逐次送信の終端を受信した
+    Eof,
+}
+
+pub struct Decoder {
+    session: Option<u32>,
+    pub file_name: String,
+    pub file_size: u64,
+    pub block_size: usize,
+    pub digest: [u8; DIGEST_SIZE],
+    pub codec: Codec,
+    pub original_size: u64,
+    pub cipher: Option<CipherParams>,
+    // 旧形式の送信側。ハッシュ値を載せていないので検証できない
+    pub legacy: bool,
+    mode: Mode,
+    // 受信途中の転送を保存して再開するためのメタデータフレーム。旧形式では復元できないので持たない
+    meta_frame: Option<Vec<u8>>,
+    blocks: Option<BlockMap>,
+    fountain: Option<fountain::Decoder>,
+    inflater: Option<Inflater>,
+    inflated_blocks: u64,
+    pub eof_seen: bool,
+    pending: Vec<(Header, Vec<u8>)>,
+    pending_keys: HashSet<(u32, u8, u64)>,
+    // 未対応のプロトコルバージョンのフレームを見つけた
+    pub incompatible: Option<u8>,
+    // メモリに置けない大きさのファイルのメタデータを見つけた
+    pub too_large: Option<u64>,
+}
+
+#[derive(Debug)]
+pub enum FinishError {
+    // 全てのブロックが揃っていない
+    Incomplete,
+    // 暗号化されているがパスフレーズが指定されていない
+    PassphraseRequired,
+    // パスフレーズが違うか、データが破損している
+    AuthFailed,
+    // 圧縮されたデータを展開できない。展開前のデータを返す
+    InflateFailed(Vec<u8>),
+    // ハッシュ値が一致しない。実際のハッシュ値と復元したデータを返す
+    DigestMismatch([u8; DIGEST_SIZE], Vec<u8>),
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self {
+            session: None,
+            file_name: String::new(),
+            file_size: 0,
+            block_size: 0,
+            digest: [0; DIGEST_SIZE],
+            codec: Codec::None,
+            original_size: 0,
+            cipher: None,
+            legacy: false,
+            mode: Mode::Sequential,
+            meta_frame: None,
+            blocks: None,
+            fountain: None,
+            inflater: None,
+            inflated_blocks: 0,
+            eof_seen: false,
+            pending: Vec::new(),
+            pending_keys: HashSet::new(),
+            incompatible: None,
+            too_large: None,
+        }
+    }
+
+    pub fn has_metadata(&self) -> bool {
+        self.session.is_some()
+    }
+
+    pub fn session(&self) -> Option<u32> {
+        self.session
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn meta_frame(&self) -> Option<&[u8]> {
+        self.meta_frame.as_deref()
+    }
+
+    // 復元できたブロック数と全体のブロック数
+    pub fn progress(&self) -> (usize, usize) {
+        match (&self.blocks, &self.fountain) {
+            (Some(b), _) => (b.received_blocks(), b.num_blocks()),
+            (_, Some(d)) => (d.decoded_blocks(), d.num_blocks()),
+            _ => (0, 0),
+        }
+    }
+
+    pub fn received_bytes(&self) -> u64 {
+        match (&self.blocks, &self.fountain) {
+            (Some(b), _) => b.received_bytes(),
+            (_, Some(d)) => {
+                (d.decoded_blocks() as u64 * self.block_size as u64).min(self.file_size)
+            }
+            _ => 0,
+        }
+    }
+
+    // ファウンテン符号で受信したシンボル数
+    pub fn received_symbols(&self) -> usize {
+        self.fountain
+            .as_ref()
+            .map(|d| d.received_symbols())
+            .unwrap_or(0)
+    }
+
+    pub fn block(&self, seq: u64) -> Option<&[u8]> {
+        self.blocks.as_ref()?.block(seq)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        match (&self.blocks, &self.fountain) {
+            (Some(b), _) => b.is_complete(),
+            (_, Some(d)) => d.is_complete(),
+            _ => false,
+        }
+    }
+
+    // 欠落しているブロック番号 (ファウンテン符号では空)
+    pub fn missing(&self) -> Vec<u64> {
+        self.blocks
+            .as_ref()
+            .map(|b| b.missing().collect())
+            .unwrap_or_default()
+    }
+
+    // QRコードのペイロードを1つ処理して、受信状態の変化を返す。
+    // メタデータより先に読み取れたフレームは保持しておき、メタデータの受信後にまとめて処理する
+    pub fn push(&mut self, payload: &[u8]) -> Result<Vec<Event>, ParseError> {
+        let header = match parse_header(payload) {
+            Ok(h) => h,
+            Err(ParseError::Incompatible(v)) => {
+                self.incompatible = Some(v);
+                return Err(ParseError::Incompatible(v));
+            }
+            Err(e) => return Err(e),
+        };
+        let data = header.payload(payload).to_vec();
+        let mut events = Vec::new();
+        match header.frame_type {
+            FrameType::Control => events.push(Event::Waiting),
+            FrameType::Meta => self.accept_meta(header, &data, &mut events),
+            _ if self.session != Some(header.session) => self.hold(header, data),
+            _ => self.recv_frame(header, &data, &mut events),
+        }
+        Ok(events)
+    }
+
+    fn accept_meta(&mut self, header: Header, data: &[u8], events: &mut Vec<Event>) {
+        if self.session == Some(header.session) {
+            return;
+        }
+        let meta = match parse_metadata(&header, data) {
+            Some(m) => m,
+            None => return,
+        };
+        let (codec, original_size) = match meta.extension(EXT_CODEC) {
+            Some(ext) => match codec::parse_extension(ext) {
+                Some(v) => v,
+                None => return,
+            },
+            None => (Codec::None, meta.file_size),
+        };
+        let cipher = match meta.extension(EXT_CIPHER) {
+            Some(ext) => match CipherParams::parse_extension(ext) {
+                Some(c) => Some(c),
+                None => return,
+            },
+            None => None,
+        };
+        if self.session.is_some() {
+            // 同じファイルの再送であれば、新しいセッションで欠落を埋める
+            let same_file = meta.digest == self.digest
+                && meta.file_size == self.file_size
+                && header.size as usize == self.block_size
+                && cipher == self.cipher;
+            if same_file && self.fountain.is_none() {
+                self.session = Some(header.session);
+                events.push(Event::Resent);
+                self.replay_pending(events);
+            }
+            return;
+        }
+        let mode = match Mode::from_u8(meta.mode) {
+            Some(m) => m,
+            None => return,
+        };
+        // 復元したファイルは1つのVecに置くので、Vecが持てる大きさ (isize::MAX) を超えるものは受け取れない
+        let size = meta.file_size.max(original_size);
+        if isize::try_from(size).is_err() {
+            self.too_large = Some(size);
+            return;
+        }
+        self.file_name = String::from_utf8_lossy(&meta.name).into_owned();
+        self.file_size = meta.file_size;
+        self.digest = meta.digest;
+        self.codec = codec;
+        self.original_size = original_size;
+        self.cipher = cipher;
+        self.legacy = header.version == 0;
+        self.mode = mode;
+        self.session = Some(header.session);
+        // メタデータのペイロードサイズがブロックサイズを表す
+        self.block_size = header.size as usize;
+        if mode == Mode::Fountain {
+            let num_blocks = self.file_size.div_ceil(self.block_size as u64) as usize;
+            self.fountain = Some(fountain::Decoder::new(num_blocks, self.block_size));
+        } else {
+            self.blocks = BlockMap::new(self.file_size, self.block_size);
+            // 先頭から連続して揃ったブロックを受信しながら展開しておく。
+            // 暗号化されている場合は復号してから展開するので、揃うまで待つ
+            if codec != Codec::None && self.cipher.is_none() {
+                self.inflater = Some(Inflater::new(original_size));
+            }
+        }
+        if header.version != 0 {
+            let mut frame = vec![0; HEADER_SIZE + data.len()];
+            frame[HEADER_SIZE..].copy_from_slice(data);
+            build_header(header, &mut frame);
+            self.meta_frame = Some(frame);
+        }
+        events.push(Event::Metadata);
+        self.replay_pending(events);
+    }
+
+    fn hold(&mut self, header: Header, data: Vec<u8>) {
+        if self.pending.len() >= MAX_PENDING_FRAMES {
+            return;
+        }
+        if self
+            .pending_keys
+            .insert((header.session, header.frame_type as u8, header.seq))
+        {
+            self.pending.push((header, data));
+        }
+    }
+
+    fn replay_pending(&mut self, events: &mut Vec<Event>) {
+        let pending = std::mem::take(&mut self.pending);
+        self.pending_keys.clear();
+        for (header, data) in pending {
+            if self.session == Some(header.session) {
+                self.recv_frame(header, &data, events);
+            } else {
+                self.hold(header, data);
+            }
+        }
+    }
+
+    fn recv_frame(&mut self, header: Header, data: &[u8], events: &mut Vec<Event>) {
+        if let Some(decoder) = self.fountain.as_mut() {
+            if header.frame_type != FrameType::Parity {
+                return;
+            }
+            if decoder.push(header.seq as u32, data) {
+                events.push(Event::Symbol);
+            }
+            return;
+        }
+        match header.frame_type {
+            FrameType::Data if self.insert_block(header.seq, data) => {
+                events.push(Event::Block(header.seq));
+            }
+            // 終端を受信しても欠落がある場合は、再送で埋まるのを待つ
+            FrameType::Eof if self.blocks.is_some() && !self.eof_seen => {
+                self.eof_seen = true;
+                events.push(Event::Eof);
+            }
+            _ => {}
+        }
+    }
+
+    // 逐次送信のブロックを追加する。保存しておいたブロックから再開する場合にも使う
+    pub fn insert_block(&mut self, seq: u64, data: &[u8]) -> bool {
+        let blocks = match self.blocks.as_mut() {
+            Some(b) => b,
+            None => return false,
+        };
+        if !blocks.insert(seq, data) {
+            return false;
+        }
+        if let Some(inflater) = self.inflater.as_mut() {
+            while let Some(b) = blocks.block(self.inflated_blocks) {
+                inflater.push(b);
+                self.inflated_blocks += 1;
+            }
+        }
+        true
+    }
+
+    // 受信したデータを復号・展開し、ハッシュ値を検証する
+    pub fn finish(&mut self, passphrase: Option<&str>) -> Result<Vec<u8>, FinishError> {
+        let data = match (&self.blocks, &self.fountain) {
+            (Some(b), _) => b.finish(),
+            (_, Some(d)) => d.finish(self.file_size),
+            _ => None,
+        }
+        .ok_or(FinishError::Incomplete)?;
+        let data = match &self.cipher {
+            Some(cipher) => {
+                let passphrase = passphrase.ok_or(FinishError::PassphraseRequired)?;
+                crypto::decrypt(cipher, passphrase, &data).ok_or(FinishError::AuthFailed)?
+            }
+            None => data,
+        };
+        let data = match self.codec {
+            Codec::None => data,
+            _ => {
+                // 受信しながら展開していなければ、まとめて展開する
+                let inflater = self.inflater.take().unwrap_or_else(|| {
+                    let mut inflater = Inflater::new(self.original_size);
+                    inflater.push(&data);
+                    inflater
+                });
+                match inflater.finish() {
+                    Some(inflated) => inflated,
+                    None => return Err(FinishError::InflateFailed(data)),
+                }
+            }
+        };
+        // 暗号化されている場合はハッシュ値の代わりに認証で検証済み
+        if self.cipher.is_none() && !self.legacy {
+            let actual: [u8; DIGEST_SIZE] = Sha256::digest(&data).into();
+            if actual != self.digest {
+                return Err(FinishError::DigestMismatch(actual, data));
+            }
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use qrcode::EcLevel;
+
+    use super::*;
+    use crate::capacity::block_size_of;
+    use crate::encoder::{Encoder, Preparer, Settings};
+    use crate::header::PROTOCOL_VERSION;
+
+    fn settings(mode: Mode, compression: bool) -> Settings {
+        Settings {
+            block_size: block_size_of(10, EcLevel::M),
+            mode,
+            meta_interval: 8,
+            compression,
+        }
+    }
+
+    // 圧縮しても縮まないデータ
+    fn noise(len: usize, mut seed: u32) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect()
+    }
+
+    // 送信側のフレームを1つずつ受信側に渡す。dropがtrueを返したフレームは取りこぼしたものとする
+    fn transfer(
+        encoder: &mut Encoder,
+        decoder: &mut Decoder,
+        max_frames: usize,
+        mut drop: impl FnMut(usize) -> bool,
+    ) -> Vec<Event> {
+        let mut events = Vec::new();
+        for i in 0..max_frames {
+            let (tiles, keep_going) = encoder.next_tiles(1);
+            if !drop(i) {
+                events.extend(decoder.push(&tiles[0]).unwrap());
+            }
+            if !keep_going || decoder.is_complete() {
+                break;
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn sequential_roundtrip() {
+        let data = noise(5000, 1);
+        let mut encoder = Encoder::new(b"a.bin", &data, &settings(Mode::Sequential, true), "", 7);
+        let mut decoder = Decoder::new();
+        assert_eq!(
+            decoder.push(encoder.meta_frame()).unwrap(),
+            vec![Event::Metadata]
+        );
+        assert_eq!(decoder.file_name, "a.bin");
+        assert_eq!(decoder.session(), Some(7));
+        let events = transfer(&mut encoder, &mut decoder, 1000, |_| false);
+        let num_blocks = encoder.num_blocks();
+        assert_eq!(
+            events
+                .iter()
+                .filter(|e| matches!(e, Event::Block(_)))
+                .count() as u64,
+            num_blocks
+        );
+        assert!(decoder.is_complete());
+        assert_eq!(decoder.received_bytes(), data.len() as u64);
+        assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    #[test]
+    fn compressed_roundtrip() {
+        let data: Vec<u8> = (0..20000).map(|i| (i / 16 % 7) as u8).collect();
+        let mut encoder = Encoder::new(b"a.txt", &data, &settings(Mode::Sequential, true), "", 7);
+        let mut decoder = Decoder::new();
+        decoder.push(encoder.meta_frame()).unwrap();
+        assert_eq!(decoder.codec, Codec::Deflate);
+        assert_eq!(decoder.original_size, data.len() as u64);
+        assert!(decoder.file_size < data.len() as u64);
+        transfer(&mut encoder, &mut decoder, 1000, |_| false);
+        assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    #[test]
+    fn empty_file() {
+        for mode in [Mode::Sequential, Mode::Loop, Mode::Fountain] {
+            let encoder = Encoder::new(b"empty", &[], &settings(mode, true), "", 7);
+            let mut decoder = Decoder::new();
+            decoder.push(encoder.meta_frame()).unwrap();
+            assert_eq!(decoder.mode(), mode);
+            assert!(decoder.is_complete());
+            assert_eq!(decoder.finish(None).unwrap(), Vec::<u8>::new());
+        }
+    }
+
+    #[test]
+    fn too_large_file_is_rejected() {
+        let encoder = Encoder::new(b"a", b"abc", &settings(Mode::Sequential, false), "", 7);
+        let mut frame = encoder.meta_frame().to_vec();
+        let header = parse_header(&frame).unwrap();
+        frame[HEADER_SIZE..HEADER_SIZE + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        build_header(header, &mut frame);
+        let mut decoder = Decoder::new();
+        assert!(decoder.push(&frame).unwrap().is_empty());
+        assert!(!decoder.has_metadata());
+        assert_eq!(decoder.too_large, Some(u64::MAX));
+    }
+
+    #[test]
+    fn frames_before_metadata_are_replayed() {
+        let data = noise(3000, 2);
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Sequential, false), "", 7);
+        let meta = encoder.meta_frame().to_vec();
+        let mut frames = Vec::new();
+        loop {
+            let (tiles, keep_going) = encoder.next_tiles(1);
+            // 終端の直前のメタデータは除く
+            frames.extend(tiles.into_iter().filter(|t| *t != meta));
+            if !keep_going {
+                break;
+            }
+        }
+        let mut decoder = Decoder::new();
+        for frame in &frames {
+            assert!(decoder.push(frame).unwrap().is_empty());
+        }
+        let events = decoder.push(&meta).unwrap();
+        assert_eq!(events[0], Event::Metadata);
+        assert_eq!(events.last(), Some(&Event::Eof));
+        assert_eq!(events.len(), frames.len() + 1);
+        assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    // 最初のメタデータを取りこぼしても、終端の直前のメタデータで揃う
+    #[test]
+    fn sequential_without_first_metadata() {
+        let data = noise(3000, 5);
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Sequential, false), "", 7);
+        let mut decoder = Decoder::new();
+        let events = transfer(&mut encoder, &mut decoder, 1000, |_| false);
+        assert_eq!(events[0], Event::Metadata);
+        assert!(decoder.is_complete());
+        assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    #[test]
+    fn loop_joined_midway() {
+        let data = noise(8000, 3);
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Loop, false), "", 7);
+        let mut decoder = Decoder::new();
+        // 最初のメタデータと先頭のブロックを見逃しても、繰り返しの次の周で揃う
+        transfer(&mut encoder, &mut decoder, 1000, |i| i < 5);
+        assert!(decoder.is_complete());
+        assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    #[test]
+    fn fountain_with_loss() {
+        let data = noise(20000, 4);
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Fountain, false), "", 7);
+        let mut decoder = Decoder::new();
+        decoder.push(encoder.meta_frame()).unwrap();
+        assert_eq!(decoder.mode(), Mode::Fountain);
+        let events = transfer(&mut encoder, &mut decoder, 10000, |i| i % 3 == 0);
+        assert!(events.contains(&Event::Symbol));
+        assert!(decoder.is_complete());
+        assert!(decoder.received_symbols() > 0);
+        assert!(decoder.missing().is_empty());
+        assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    #[test]
+    fn resend_missing_blocks() {
+        let data = noise(6000, 5);
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Sequential, false), "", 7);
+        let mut decoder = Decoder::new();
+        decoder.push(encoder.meta_frame()).unwrap();
+        let events = transfer(&mut encoder, &mut decoder, 1000, |i| i == 1 || i == 3);
+        assert_eq!(events.last(), Some(&Event::Eof));
+        assert!(decoder.eof_seen);
+        assert_eq!(decoder.missing(), vec![1, 3]);
+        assert!(matches!(decoder.finish(None), Err(FinishError::Incomplete)));
+
+        let code = crate::resend::encode(7, decoder.missing().into_iter());
+        let (session, ranges) = crate::resend::decode(&code).unwrap();
+        assert_eq!(session, 7);
+        encoder.queue_resend(&ranges);
+        let events = transfer(&mut encoder, &mut decoder, 1000, |_| false);
+        assert_eq!(events, vec![Event::Block(1), Event::Block(3)]);
+        assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    #[test]
+    fn resent_with_new_session() {
+        let data = noise(6000, 6);
+        let settings = settings(Mode::Sequential, false);
+        let mut first = Encoder::new(b"a", &data, &settings, "", 7);
+        let mut decoder = Decoder::new();
+        decoder.push(first.meta_frame()).unwrap();
+        transfer(&mut first, &mut decoder, 1000, |i| i % 2 == 0);
+        assert!(!decoder.is_complete());
+
+        // 送信側でファイルを選び直すとセッションが変わる
+        let mut second = Encoder::new(b"a", &data, &settings, "", 8);
+        let events = decoder.push(second.meta_frame()).unwrap();
+        assert_eq!(events, vec![Event::Resent]);
+        assert_eq!(decoder.session(), Some(8));
+        transfer(&mut second, &mut decoder, 1000, |_| false);
+        assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    #[test]
+    fn other_transfer_is_ignored() {
+        let settings = settings(Mode::Sequential, false);
+        let first = Encoder::new(b"a", &noise(3000, 7), &settings, "", 7);
+        let mut other = Encoder::new(b"b", &noise(3000, 8), &settings, "", 8);
+        let mut decoder = Decoder::new();
+        decoder.push(first.meta_frame()).unwrap();
+        assert!(decoder.push(other.meta_frame()).unwrap().is_empty());
+        let events = transfer(&mut other, &mut decoder, 1000, |_| false);
+        assert!(events.is_empty());
+        assert_eq!(decoder.session(), Some(7));
+        assert_eq!(decoder.received_bytes(), 0);
+    }
+
+    #[test]
+    fn restore_from_saved_blocks() {
+        let data = noise(6000, 9);
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Sequential, true), "", 7);
+        let mut decoder = Decoder::new();
+        decoder.push(encoder.meta_frame()).unwrap();
+        transfer(&mut encoder, &mut decoder, 3, |_| false);
+        let meta_frame = decoder.meta_frame().unwrap().to_vec();
+        assert_eq!(meta_frame, encoder.meta_frame());
+
+        // 保存しておいたメタデータとブロックから受信を再開する
+        let mut restored = Decoder::new();
+        restored.push(&meta_frame).unwrap();
+        for seq in 0..encoder.num_blocks() {
+            if let Some(block) = decoder.block(seq) {
+                assert!(restored.insert_block(seq, block));
+                assert!(!restored.insert_block(seq, block));
+            }
+        }
+        assert_eq!(restored.received_bytes(), decoder.received_bytes());
+        transfer(&mut encoder, &mut restored, 1000, |_| false);
+        assert_eq!(restored.finish(None).unwrap(), data);
+    }
+
+    #[test]
+    fn encrypted_roundtrip() {
+        let data = noise(3000, 10);
+        let mut encoder =
+            Encoder::new(b"a", &data, &settings(Mode::Sequential, false), "secret", 7);
+        let mut decoder = Decoder::new();
+        decoder.push(encoder.meta_frame()).unwrap();
+        assert!(decoder.cipher.is_some());
+        assert_eq!(decoder.digest, [0; DIGEST_SIZE]);
+        transfer(&mut encoder, &mut decoder, 1000, |_| false);
+        assert!(matches!(
+            decoder.finish(None),
+            Err(FinishError::PassphraseRequired)
+        ));
+        assert!(matches!(
+            decoder.finish(Some("wrong")),
+            Err(FinishError::AuthFailed)
+        ));
+        assert_eq!(decoder.finish(Some("secret")).unwrap(), data);
+    }
+
+    #[test]
+    fn prepared_in_pieces_roundtrip() {
+        // 少しずつ読み込みながら圧縮・暗号化したもの
+        let data: Vec<u8> = (0..100_000).map(|i| (i / 16 % 7) as u8).collect();
+        let mut preparer = Preparer::new(&settings(Mode::Sequential, true), "secret", false);
+        for piece in data.chunks(30_000) {
+            preparer.push(piece);
+        }
+        let mut encoder = preparer.finish(b"a", 7);
+        assert!(encoder.is_buffered());
+        let mut decoder = Decoder::new();
+        decoder.push(encoder.meta_frame()).unwrap();
+        assert_eq!(decoder.codec, Codec::Deflate);
+        assert!(decoder.file_size < data.len() as u64 / 10);
+        transfer(&mut encoder, &mut decoder, 1000, |_| false);
+        assert_eq!(decoder.finish(Some("secret")).unwrap(), data);
+    }
+
+    #[test]
+    fn digest_mismatch_returns_data() {
+        let data = noise(3000, 11);
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Sequential, false), "", 7);
+        let mut decoder = Decoder::new();
+        decoder.push(encoder.meta_frame()).unwrap();
+        // CRCを通過して壊れたブロックが紛れ込んだ場合
+        let broken = vec![0; decoder.block_size];
+        assert!(decoder.insert_block(0, &broken));
+        transfer(&mut encoder, &mut decoder, 1000, |_| false);
+        match decoder.finish(None) {
+            Err(FinishError::DigestMismatch(actual, received)) => {
+                assert_eq!(&received[..broken.len()], &broken[..]);
+                assert_eq!(&received[broken.len()..], &data[broken.len()..]);
+                let expected: [u8; DIGEST_SIZE] = Sha256::digest(&received).into();
+                assert_eq!(actual, expected);
+            }
+            r => panic!("unexpected {:?}", r.map(|d| d.len())),
+        }
+    }
+
+    // 旧形式の送信ページが表示していたフレーム。
+    // 待機中(seq=0, size=0)、ファイルサイズ + NUL終端したファイル名、seq=1からのブロック、size=0の終端
+    fn baseline_frames(name: &str, data: &[u8], block_size: usize) -> Vec<Vec<u8>> {
+        let header = |seq: u32, size: usize, frame: &mut [u8]| {
+            let x = (seq << 12) | size as u32;
+            frame[..4].copy_from_slice(&x.to_le_bytes());
+        };
+        let payload_size = block_size - 4;
+        let mut frames = Vec::new();
+        let mut frame = vec![0; block_size];
+        header(0, 0, &mut frame);
+        frames.push(frame);
+        let mut frame = vec![0; block_size];
+        header(0, payload_size, &mut frame);
+        frame[4..12].copy_from_slice(&(data.len() as u64).to_le_bytes());
+        frame[12..12 + name.len()].copy_from_slice(name.as_bytes());
+        frames.push(frame);
+        for (i, chunk) in data.chunks(payload_size).enumerate() {
+            let mut frame = vec![0; block_size];
+            header(i as u32 + 1, chunk.len(), &mut frame);
+            frame[4..4 + chunk.len()].copy_from_slice(chunk);
+            frames.push(frame);
+        }
+        let mut frame = vec![0; block_size];
+        header((data.len() / payload_size) as u32 + 1, 0, &mut frame);
+        frames.push(frame);
+        frames
+    }
+
+    #[test]
+    fn baseline_sender() {
+        let data = noise(1000, 12);
+        let frames = baseline_frames("古い.bin", &data, 104);
+        let mut decoder = Decoder::new();
+        let events: Vec<Event> = frames
+            .iter()
+            .flat_map(|f| decoder.push(f).unwrap())
+            .collect();
+        assert_eq!(events[..2], [Event::Waiting, Event::Metadata]);
+        assert_eq!(events[events.len() - 1], Event::Eof);
+        assert_eq!(decoder.file_name, "古い.bin");
+        assert!(decoder.legacy);
+        assert_eq!(decoder.missing(), Vec::<u64>::new());
+        // ハッシュ値がないので検証せずに返す
+        assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    #[test]
+    fn control_and_incompatible_frames() {
+        let mut frame = vec![0; HEADER_SIZE];
+        build_header(Header::new(FrameType::Control, 0, 0, 0), &mut frame);
+        let mut decoder = Decoder::new();
+        assert_eq!(decoder.push(&frame).unwrap(), vec![Event::Waiting]);
+
+        frame[2] = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            decoder.push(&frame),
+            Err(ParseError::Incompatible(v)) if v == PROTOCOL_VERSION + 1
+        ));
+        assert_eq!(decoder.incompatible, Some(PROTOCOL_VERSION + 1));
+        assert!(matches!(decoder.push(b"hello"), Err(ParseError::Foreign)));
+    }
+}
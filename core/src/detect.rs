@@ -0,0 +1,551 @@
+// キャプチャした画像(RGBA)からQRコードを読み取る。
+// メインスレッドとWeb Workerの両方から使うので、web-sysやyewには依存しない
+
+use quircs::Quirc;
+
+// 色の付いた画素とみなすチャンネル間の差
+const CHROMA_THRESHOLD: u8 = 96;
+// 色の付いた画素の割合がこれ以上であればカラー多重化とみなす [%]
+const CHROMATIC_RATIO: usize = 2;
+// 局所平均による二値化で、周囲の平均よりこの割合以上暗い画素を黒とする [%]
+const LOCAL_MEAN_OFFSET: u32 = 15;
+// 局所平均を求める範囲の半径の最小値 [px]
+const LOCAL_MEAN_MIN_RADIUS: usize = 8;
+
+// quircに渡す前の二値化の方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binarize {
+    // 二値化せずquirc自身のしきい値処理に任せる
+    None = 0,
+    // 周囲の平均輝度との比較 (照明ムラやグラデーションに強い)
+    LocalMean = 1,
+    // 大津の方法で画像全体のしきい値を決める
+    Otsu = 2,
+}
+
+impl Binarize {
+    pub const ALL: [Binarize; 3] = [Binarize::None, Binarize::LocalMean, Binarize::Otsu];
+
+    pub fn from_u8(v: u8) -> Option<Binarize> {
+        Binarize::ALL.iter().copied().find(|b| *b as u8 == v)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Binarize::None => "しない",
+            Binarize::LocalMean => "局所平均",
+            Binarize::Otsu => "大津の方法",
+        }
+    }
+}
+
+// 読み取り前の画像処理の設定
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub binarize: Binarize,
+    // 二値化の前にぼかしてモアレや撮像素子のノイズを抑える (カメラで撮影する場合)
+    pub smooth: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl Rect {
+    pub fn union(&self, o: &Rect) -> Rect {
+        let (x, y) = (self.x.min(o.x), self.y.min(o.y));
+        Rect {
+            x,
+            y,
+            w: (self.x + self.w).max(o.x + o.w) - x,
+            h: (self.y + self.h).max(o.y + o.h) - y,
+        }
+    }
+
+    // 周囲をmarginだけ広げる。広げた結果はw×hの画像の中に収める
+    pub fn expand(&self, margin: u32, w: u32, h: u32) -> Rect {
+        let (x, y) = (self.x.saturating_sub(margin), self.y.saturating_sub(margin));
+        Rect {
+            x,
+            y,
+            w: (self.x + self.w + margin).min(w) - x,
+            h: (self.y + self.h + margin).min(h) - y,
+        }
+    }
+}
+
+pub struct Detected {
+    // 読み取れたQRコードのペイロード
+    pub payloads: Vec<Vec<u8>>,
+    // RGBの各チャンネルから読み取れた
+    pub color: bool,
+    pub errors: Vec<String>,
+    // 読み取れたQRコード全体を囲む矩形
+    pub bounds: Option<Rect>,
+}
+
+impl Detected {
+    // 切り出した画像を読み取った場合に、元の画像の座標に戻す
+    pub fn translate(&mut self, x: u32, y: u32) {
+        if let Some(b) = self.bounds.as_mut() {
+            b.x += x;
+            b.y += y;
+        }
+    }
+}
+
+pub fn detect(decoder: &mut Quirc, w: usize, h: usize, rgba: &[u8], opts: Options) -> Detected {
+    let mut ret = Detected {
+        payloads: Vec::new(),
+        color: false,
+        errors: Vec::new(),
+        bounds: None,
+    };
+    // 色の付いた画素が多ければR, G, Bのチャンネルごとに別々のQRコードとして読み取る。
+    // チャンネルごとに読み取れなかった場合はモノクロとして読み取る
+    if is_chromatic(rgba) {
+        for c in 0..3 {
+            let plane: Vec<u8> = rgba.chunks_exact(4).map(|px| px[c]).collect();
+            let plane = prepare(plane, w, h, opts);
+            decode_plane(decoder, w, h, &plane, &mut ret);
+        }
+        ret.color = !ret.payloads.is_empty();
+    }
+    if ret.payloads.is_empty() {
+        let gs = prepare(luma(rgba), w, h, opts);
+        decode_plane(decoder, w, h, &gs, &mut ret);
+    }
+    ret
+}
+
+// グリッド状に並んだ複数のQRコードも、それぞれ独立したフレームとして扱う
+fn decode_plane(decoder: &mut Quirc, w: usize, h: usize, plane: &[u8], out: &mut Detected) {
+    for code in decoder.identify(w, h, plane) {
+        let code = match code {
+            Ok(c) => c,
+            Err(e) => {
+                out.errors.push(format!("{:?}", e));
+                continue;
+            }
+        };
+        match code.decode() {
+            Ok(decoded) => {
+                out.payloads.push(decoded.payload);
+                let (xs, ys) = (
+                    code.corners.iter().map(|p| p.x.max(0) as u32),
+                    code.corners.iter().map(|p| p.y.max(0) as u32),
+                );
+                let (x0, x1) = (xs.clone().min().unwrap(), xs.max().unwrap());
+                let (y0, y1) = (ys.clone().min().unwrap(), ys.max().unwrap());
+                let r = Rect {
+                    x: x0,
+                    y: y0,
+                    w: x1 - x0 + 1,
+                    h: y1 - y0 + 1,
+                };
+                out.bounds = Some(out.bounds.map_or(r, |b| b.union(&r)));
+            }
+            Err(e) => out.errors.push(format!("{:?}", e)),
+        }
+    }
+}
+
+fn prepare(plane: Vec<u8>, w: usize, h: usize, opts: Options) -> Vec<u8> {
+    let mut plane = if opts.smooth {
+        smooth(&plane, w, h)
+    } else {
+        plane
+    };
+    binarize(&mut plane, w, h, opts.binarize);
+    plane
+}
+
+// 3x3の平均でぼかす。縦横に分けて計算する
+pub fn smooth(plane: &[u8], w: usize, h: usize) -> Vec<u8> {
+    if w < 3 || h < 3 {
+        return plane.to_vec();
+    }
+    let mut rows = vec![0u16; w * h];
+    for y in 0..h {
+        let row = &plane[y * w..(y + 1) * w];
+        for x in 0..w {
+            let (x0, x1) = (x.saturating_sub(1), (x + 1).min(w - 1));
+            rows[y * w + x] = row[x0] as u16 + row[x] as u16 + row[x1] as u16;
+        }
+    }
+    let mut out = vec![0u8; w * h];
+    for y in 0..h {
+        let (y0, y1) = (y.saturating_sub(1), (y + 1).min(h - 1));
+        for x in 0..w {
+            let sum = rows[y0 * w + x] + rows[y * w + x] + rows[y1 * w + x];
+            out[y * w + x] = ((sum + 4) / 9) as u8;
+        }
+    }
+    out
+}
+
+// RGBAをBT.601の輝度に変換する
+pub fn luma(rgba: &[u8]) -> Vec<u8> {
+    rgba.chunks_exact(4)
+        .map(|px| ((77 * px[0] as u32 + 150 * px[1] as u32 + 29 * px[2] as u32 + 128) >> 8) as u8)
+        .collect()
+}
+
+// 黒を0、白を255に二値化する
+pub fn binarize(plane: &mut [u8], w: usize, h: usize, method: Binarize) {
+    match method {
+        Binarize::None => {}
+        Binarize::LocalMean => local_mean(plane, w, h),
+        Binarize::Otsu => {
+            let t = otsu_threshold(plane);
+            plane
+                .iter_mut()
+                .for_each(|p| *p = if *p <= t { 0 } else { 255 });
+        }
+    }
+}
+
+// しきい値以下を黒とした場合に、黒と白のクラス間分散が最大になるしきい値
+pub fn otsu_threshold(plane: &[u8]) -> u8 {
+    let mut hist = [0u64; 256];
+    plane.iter().for_each(|&p| hist[p as usize] += 1);
+    let total = plane.len() as f64;
+    let sum: f64 = hist
+        .iter()
+        .enumerate()
+        .map(|(i, &n)| i as f64 * n as f64)
+        .sum();
+    let (mut best, mut best_var) = (0u8, -1.0);
+    let (mut n0, mut sum0) = (0.0, 0.0);
+    for (t, &n) in hist.iter().enumerate() {
+        n0 += n as f64;
+        sum0 += t as f64 * n as f64;
+        let n1 = total - n0;
+        if n0 == 0.0 || n1 == 0.0 {
+            continue;
+        }
+        let d = sum0 / n0 - (sum - sum0) / n1;
+        let var = n0 * n1 * d * d;
+        if var > best_var {
+            best = t as u8;
+            best_var = var;
+        }
+    }
+    best
+}
+
+// 積分画像で周囲の平均輝度を求め、それより一定割合暗い画素を黒とする
+fn local_mean(plane: &mut [u8], w: usize, h: usize) {
+    if w == 0 || h == 0 {
+        return;
+    }
+    // ファインダパターンの中心(3x3モジュール)より十分広く取らないと、中心が白になる
+    let r = (w.min(h) / 8).max(LOCAL_MEAN_MIN_RADIUS);
+    let stride = w + 1;
+    // 各画素より左上の輝度の合計
+    let mut integral = vec![0u32; stride * (h + 1)];
+    for y in 0..h {
+        let mut row = 0u32;
+        for x in 0..w {
+            row += plane[y * w + x] as u32;
+            integral[(y + 1) * stride + x + 1] = integral[y * stride + x + 1].wrapping_add(row);
+        }
+    }
+    for y in 0..h {
+        let (y0, y1) = (y.saturating_sub(r), (y + r + 1).min(h));
+        for x in 0..w {
+            let (x0, x1) = (x.saturating_sub(r), (x + r + 1).min(w));
+            // 大きな画像では積分画像が桁あふれするが、範囲内の合計はu32に収まるので剰余演算で求まる
+            let sum = integral[y1 * stride + x1]
+                .wrapping_add(integral[y0 * stride + x0])
+                .wrapping_sub(integral[y0 * stride + x1])
+                .wrapping_sub(integral[y1 * stride + x0]);
+            let area = ((x1 - x0) * (y1 - y0)) as u64;
+            let p = &mut plane[y * w + x];
+            *p = if (*p as u64) * area * 100 < sum as u64 * (100 - LOCAL_MEAN_OFFSET) as u64 {
+                0
+            } else {
+                255
+            };
+        }
+    }
+}
+
+// 間引いた画素のうち、色の付いたものが一定以上あるか
+fn is_chromatic(d: &[u8]) -> bool {
+    let (mut total, mut chromatic) = (0, 0);
+    for px in d.chunks_exact(4).step_by(16) {
+        let max = px[0].max(px[1]).max(px[2]);
+        let min = px[0].min(px[1]).min(px[2]);
+        total += 1;
+        if max - min >= CHROMA_THRESHOLD {
+            chromatic += 1;
+        }
+    }
+    chromatic * 100 >= total * CHROMATIC_RATIO && chromatic > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qrcode::{Color, QrCode};
+
+    // 1モジュールあたりのピクセル数
+    const CELL: usize = 4;
+    // 周囲の余白 [モジュール]
+    const QUIET: usize = 4;
+
+    // QRコードを輝度の画像にする。(幅, 高さ, 画素) を返す
+    fn render(data: &[u8], dark: u8, light: u8) -> (usize, usize, Vec<u8>) {
+        render_cells(data, dark, light, CELL)
+    }
+
+    fn render_cells(data: &[u8], dark: u8, light: u8, cell: usize) -> (usize, usize, Vec<u8>) {
+        let code = QrCode::new(data).unwrap();
+        let colors = code.to_colors();
+        let n = code.width();
+        let size = (n + QUIET * 2) * cell;
+        let mut plane = vec![light; size * size];
+        for y in 0..size {
+            for x in 0..size {
+                let (mx, my) = (
+                    (x / cell).wrapping_sub(QUIET),
+                    (y / cell).wrapping_sub(QUIET),
+                );
+                if mx < n && my < n && colors[my * n + mx] == Color::Dark {
+                    plane[y * size + x] = dark;
+                }
+            }
+        }
+        (size, size, plane)
+    }
+
+    fn to_rgba(plane: &[u8]) -> Vec<u8> {
+        plane.iter().flat_map(|&p| [p, p, p, 255]).collect()
+    }
+
+    // 再現性のある疑似乱数(xorshift)で±amplitudeのノイズを加える
+    fn add_noise(plane: &mut [u8], amplitude: i32) {
+        let mut s = 0x2545_f491_u32;
+        for p in plane.iter_mut() {
+            s ^= s << 13;
+            s ^= s >> 17;
+            s ^= s << 5;
+            let n = (s % (amplitude as u32 * 2 + 1)) as i32 - amplitude;
+            *p = (*p as i32 + n).clamp(0, 255) as u8;
+        }
+    }
+
+    // カメラで撮影した場合を模して、台形にゆがめ、細かい縞模様と横方向のぶれを加える
+    fn camera(plane: &[u8], w: usize, h: usize, light: u8) -> Vec<u8> {
+        let mut out = vec![light; w * h];
+        let (cx, cy) = (w as f32 / 2.0, h as f32 / 2.0);
+        for y in 0..h {
+            for x in 0..w {
+                // 下側が遠くなるように傾けた射影変換
+                let d = 1.0 + 0.2 * (y as f32 - cy) / h as f32;
+                let (sx, sy) = (cx + (x as f32 - cx) / d, cy + (y as f32 - cy) / d);
+                if (0.0..w as f32).contains(&sx) && (0.0..h as f32).contains(&sy) {
+                    out[y * w + x] = plane[sy as usize * w + sx as usize];
+                }
+            }
+        }
+        for (i, p) in out.iter_mut().enumerate() {
+            let (x, y) = (i % w, i / w);
+            let d = if (x + y) % 3 == 0 { -36 } else { 18 };
+            *p = (*p as i32 + d).clamp(0, 255) as u8;
+        }
+        let mut blurred = out.clone();
+        for y in 0..h {
+            for x in 1..w - 1 {
+                let i = y * w + x;
+                blurred[i] = ((out[i - 1] as u32 + out[i] as u32 + out[i + 1] as u32) / 3) as u8;
+            }
+        }
+        blurred
+    }
+
+    fn decode(w: usize, h: usize, plane: &[u8], method: Binarize) -> Detected {
+        let opts = Options {
+            binarize: method,
+            smooth: false,
+        };
+        detect(&mut Quirc::default(), w, h, &to_rgba(plane), opts)
+    }
+
+    #[test]
+    fn luma_keeps_intermediate_levels() {
+        let rgba = [
+            0, 0, 0, 255, 255, 255, 255, 255, 128, 128, 128, 255, 255, 0, 0, 255,
+        ];
+        assert_eq!(luma(&rgba), vec![0, 255, 128, 77]);
+    }
+
+    #[test]
+    fn otsu_splits_bimodal_histogram() {
+        let mut plane = vec![60u8; 500];
+        plane.extend(vec![190u8; 300]);
+        let t = otsu_threshold(&plane);
+        assert!((60..190).contains(&t), "threshold {}", t);
+        binarize(&mut plane, 800, 1, Binarize::Otsu);
+        assert!(plane[..500].iter().all(|&p| p == 0));
+        assert!(plane[500..].iter().all(|&p| p == 255));
+    }
+
+    #[test]
+    fn binarized_values_are_black_or_white() {
+        let (w, h, mut plane) = render(b"binarize", 80, 180);
+        add_noise(&mut plane, 20);
+        for method in [Binarize::LocalMean, Binarize::Otsu] {
+            let mut p = plane.clone();
+            binarize(&mut p, w, h, method);
+            assert!(p.iter().all(|&v| v == 0 || v == 255), "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn decode_clean_image() {
+        let (w, h, plane) = render(b"clean", 0, 255);
+        for method in Binarize::ALL {
+            let d = decode(w, h, &plane, method);
+            assert_eq!(d.payloads, vec![b"clean".to_vec()], "{:?}", method);
+            assert!(!d.color);
+        }
+    }
+
+    #[test]
+    fn decode_low_contrast_image() {
+        // 以前の輝度変換では暗い灰色も白になっていた
+        let (w, h, plane) = render(b"low contrast", 90, 170);
+        for method in Binarize::ALL {
+            let d = decode(w, h, &plane, method);
+            assert_eq!(d.payloads, vec![b"low contrast".to_vec()], "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn decode_noisy_blurred_image() {
+        let (w, h, mut plane) = render(b"noisy and blurred", 50, 200);
+        add_noise(&mut plane, 30);
+        let plane = smooth(&plane, w, h);
+        for method in [Binarize::LocalMean, Binarize::Otsu] {
+            let d = decode(w, h, &plane, method);
+            assert_eq!(
+                d.payloads,
+                vec![b"noisy and blurred".to_vec()],
+                "{:?}",
+                method
+            );
+        }
+    }
+
+    #[test]
+    fn decode_camera_image_with_smoothing() {
+        let (w, h, plane) = render_cells(b"camera", 30, 220, 6);
+        let mut plane = camera(&plane, w, h, 220);
+        add_noise(&mut plane, 20);
+        let opts = Options {
+            binarize: Binarize::LocalMean,
+            smooth: true,
+        };
+        let d = detect(&mut Quirc::default(), w, h, &to_rgba(&plane), opts);
+        assert_eq!(d.payloads, vec![b"camera".to_vec()]);
+    }
+
+    #[test]
+    fn decode_uneven_illumination_with_local_mean() {
+        // 左から右へ暗くなる照明ムラ。画像全体のしきい値では右側の白が黒になる
+        let (w, h, mut plane) = render(b"gradient", 0, 255);
+        for y in 0..h {
+            for x in 0..w {
+                let p = &mut plane[y * w + x];
+                let gain = 215 - 150 * x / w;
+                *p = (*p as usize * gain / 255 + 40 * (w - x) / w) as u8;
+            }
+        }
+        let d = decode(w, h, &plane, Binarize::LocalMean);
+        assert_eq!(d.payloads, vec![b"gradient".to_vec()]);
+    }
+
+    #[test]
+    fn decode_rgb_multiplexed_image() {
+        let planes: Vec<_> = [&b"red"[..], b"green", b"blue"]
+            .iter()
+            .map(|d| render(d, 0, 255))
+            .collect();
+        let (w, h) = (planes[0].0, planes[0].1);
+        assert!(planes.iter().all(|p| p.0 == w && p.1 == h));
+        let rgba: Vec<u8> = (0..w * h)
+            .flat_map(|i| [planes[0].2[i], planes[1].2[i], planes[2].2[i], 255])
+            .collect();
+        for method in Binarize::ALL {
+            let opts = Options {
+                binarize: method,
+                smooth: false,
+            };
+            let d = detect(&mut Quirc::default(), w, h, &rgba, opts);
+            assert!(d.color, "{:?}", method);
+            assert_eq!(
+                d.payloads,
+                vec![b"red".to_vec(), b"green".to_vec(), b"blue".to_vec()],
+                "{:?}",
+                method
+            );
+        }
+    }
+
+    #[test]
+    fn bounds_cover_code_and_translate() {
+        let (w, h, plane) = render(b"bounds", 0, 255);
+        let mut d = decode(w, h, &plane, Binarize::LocalMean);
+        let b = d.bounds.unwrap();
+        let margin = (QUIET * CELL) as u32;
+        assert!(
+            b.x.abs_diff(margin) <= 2 && b.y.abs_diff(margin) <= 2,
+            "{:?}",
+            b
+        );
+        assert!(b.w.abs_diff(w as u32 - margin * 2) <= 3, "{:?}", b);
+        d.translate(100, 50);
+        let t = d.bounds.unwrap();
+        assert_eq!((t.x, t.y, t.w, t.h), (b.x + 100, b.y + 50, b.w, b.h));
+    }
+
+    #[test]
+    fn rect_expand_is_clamped() {
+        let r = Rect {
+            x: 10,
+            y: 20,
+            w: 30,
+            h: 40,
+        };
+        assert_eq!(
+            r.expand(16, 50, 100),
+            Rect {
+                x: 0,
+                y: 4,
+                w: 50,
+                h: 72
+            }
+        );
+        let u = r.union(&Rect {
+            x: 60,
+            y: 0,
+            w: 10,
+            h: 10,
+        });
+        assert_eq!(
+            u,
+            Rect {
+                x: 10,
+                y: 0,
+                w: 60,
+                h: 60
+            }
+        );
+    }
+}
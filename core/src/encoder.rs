@@ -0,0 +1,614 @@
+// ファイルを送信するフレームのペイロードに変換する
+
+use std::collections::VecDeque;
+
+use sha2::{Digest, Sha256};
+
+use crate::codec::{self, Codec, Deflater};
+use crate::crypto::{self, Sealer};
+use crate::fountain;
+use crate::header::{
+    build_header, build_metadata, FrameType, Header, Metadata, Mode, DIGEST_SIZE, EXT_CIPHER,
+    EXT_CODEC, HEADER_SIZE,
+};
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // 1フレームの大きさ (ヘッダを含む)
+    pub block_size: u16,
+    pub mode: Mode,
+    pub meta_interval: u16,
+    pub compression: bool,
+}
+
+// ファイルを先頭から少しずつ受け取り、ハッシュ値を計算しながら圧縮・暗号化する。
+// ファイル全体を一度にメモリに置かなくても、最初のメタデータにハッシュ値を載せられる
+pub struct Preparer {
+    settings: Settings,
+    hasher: Sha256,
+    original_len: u64,
+    // 圧縮するかは最初に受け取った部分で決める
+    deflater: Option<Option<Deflater>>,
+    cipher: Option<(crypto::CipherParams, Sealer)>,
+    // 元のファイルのまま送る場合も内容を保持するか
+    buffered: bool,
+    content: Vec<u8>,
+}
+
+impl Preparer {
+    // passphraseが空であれば暗号化しない。
+    // bufferedがfalseで、圧縮も暗号化もせずに逐次・繰り返しで送る場合は内容を保持しないので、
+    // 送信時にplan_tilesで指定されたブロックを呼び出し側が読み込む
+    pub fn new(settings: &Settings, passphrase: &str, buffered: bool) -> Self {
+        let cipher = (!passphrase.is_empty()).then(|| {
+            let params = crypto::random_params();
+            let sealer = Sealer::new(&params, passphrase);
+            (params, sealer)
+        });
+        Self {
+            settings: settings.clone(),
+            hasher: Sha256::new(),
+            original_len: 0,
+            deflater: (!settings.compression).then_some(None),
+            cipher,
+            buffered,
+            content: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.hasher.update(data);
+        self.original_len += data.len() as u64;
+        // サンプルで圧縮すると決めた後は、元のファイルを保持しないので最後まで圧縮する
+        let deflater = self
+            .deflater
+            .get_or_insert_with(|| codec::worth_compressing(data).then(Deflater::new));
+        match (deflater, &mut self.cipher) {
+            (Some(d), Some((_, sealer))) => {
+                let mut compressed = Vec::new();
+                d.push(data, &mut compressed);
+                sealer.push(&compressed, &mut self.content);
+            }
+            (Some(d), None) => d.push(data, &mut self.content),
+            (None, Some((_, sealer))) => sealer.push(data, &mut self.content),
+            (None, None) => {
+                if self.buffered || self.settings.mode == Mode::Fountain {
+                    self.content.extend_from_slice(data);
+                }
+            }
+        }
+    }
+
+    // 最初に表示するメタデータのフレームを構築する
+    pub fn finish(mut self, name: &[u8], session: u32) -> Encoder {
+        let mut digest: [u8; DIGEST_SIZE] = self.hasher.finalize().into();
+        let mut extensions = Vec::new();
+        let mut external = false;
+        // 圧縮した場合は圧縮後のデータをブロックに分割して送る
+        if let Some(Some(deflater)) = self.deflater {
+            extensions.push((
+                EXT_CODEC,
+                codec::build_extension(Codec::Deflate, self.original_len),
+            ));
+            match &mut self.cipher {
+                Some((_, sealer)) => {
+                    let mut compressed = Vec::new();
+                    deflater.finish(&mut compressed);
+                    sealer.push(&compressed, &mut self.content);
+                }
+                None => deflater.finish(&mut self.content),
+            }
+        } else if self.cipher.is_none() {
+            external = !self.buffered && self.settings.mode != Mode::Fountain;
+        }
+        // 暗号化する場合は、ハッシュ値から元のファイルを推測できないように載せない
+        if let Some((params, sealer)) = self.cipher {
+            sealer.finish(&mut self.content);
+            extensions.push((EXT_CIPHER, params.build_extension()));
+            digest = [0; DIGEST_SIZE];
+        }
+        let content_len = if external {
+            self.original_len
+        } else {
+            self.content.len() as u64
+        };
+        let mode = self.settings.mode;
+        let block_size = self.settings.block_size as usize;
+        let payload_size = block_size - HEADER_SIZE;
+        let content = if external {
+            Content::External
+        } else if mode == Mode::Fountain {
+            Content::Fountain(fountain::Encoder::new(self.content, payload_size))
+        } else {
+            Content::Memory(self.content)
+        };
+
+        let mut meta_frame = vec![0; block_size];
+        build_metadata(
+            &Metadata {
+                file_size: content_len,
+                mode: mode as u8,
+                digest,
+                name: name.to_vec(),
+                extensions,
+            },
+            &mut meta_frame[HEADER_SIZE..],
+        );
+        build_header(
+            Header::new(FrameType::Meta, session, 0, payload_size as u16),
+            &mut meta_frame[..],
+        );
+        Encoder {
+            block_size,
+            mode,
+            meta_interval: self.settings.meta_interval,
+            session,
+            content,
+            content_len,
+            meta_frame,
+            read_offset: 0,
+            frame_count: 0,
+            symbol_id: 0,
+            resend_queue: VecDeque::new(),
+            meta_before_eof: false,
+        }
+    }
+}
+
+// 送信するデータの置き場所
+enum Content {
+    // 圧縮・暗号化したデータや、呼び出し側から一度に受け取ったファイル
+    Memory(Vec<u8>),
+    // 元のファイルのまま送るので、呼び出し側がブロックを読み込む
+    External,
+    Fountain(fountain::Encoder),
+}
+
+// 1画面に表示するフレーム
+#[derive(Debug, PartialEq)]
+pub enum Tile {
+    Frame(Vec<u8>),
+    // 元のファイルのoffsetからlenバイトを読み込み、block_frameでフレームにする
+    Block { seq: u64, offset: u64, len: usize },
+}
+
+pub struct Encoder {
+    block_size: usize,
+    mode: Mode,
+    meta_interval: u16,
+    session: u32,
+    content: Content,
+    content_len: u64,
+    meta_frame: Vec<u8>,
+    read_offset: u64,
+    frame_count: u64,
+    symbol_id: u32,
+    resend_queue: VecDeque<u64>,
+    // 逐次送信で、終端の直前のメタデータを送った
+    meta_before_eof: bool,
+}
+
+impl Encoder {
+    // メモリ上のファイルを圧縮・暗号化して、最初に表示するメタデータのフレームを構築する。
+    // passphraseが空であれば暗号化しない
+    pub fn new(
+        name: &[u8],
+        original: &[u8],
+        settings: &Settings,
+        passphrase: &str,
+        session: u32,
+    ) -> Self {
+        let mut preparer = Preparer::new(settings, passphrase, true);
+        preparer.push(original);
+        preparer.finish(name, session)
+    }
+
+    pub fn session(&self) -> u32 {
+        self.session
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    // 送信するデータをすべて保持していて、next_tilesでフレームを構築できるか
+    pub fn is_buffered(&self) -> bool {
+        !matches!(self.content, Content::External)
+    }
+
+    // 圧縮・暗号化した後の送信するデータの大きさ
+    pub fn content_len(&self) -> u64 {
+        self.content_len
+    }
+
+    pub fn num_blocks(&self) -> u64 {
+        self.content_len.div_ceil(self.payload_size() as u64)
+    }
+
+    fn payload_size(&self) -> usize {
+        self.block_size - HEADER_SIZE
+    }
+
+    pub fn meta_frame(&self) -> &[u8] {
+        &self.meta_frame
+    }
+
+    // 逐次・繰り返しでは今の周で送ったブロック数、ファウンテン符号では送ったシンボル数
+    pub fn sent_blocks(&self) -> u64 {
+        match self.content {
+            Content::Fountain(_) => self.symbol_id as u64,
+            _ => self.read_offset.div_ceil(self.payload_size() as u64),
+        }
+    }
+
+    // 再送を待っているブロック数
+    pub fn queued_resends(&self) -> usize {
+        self.resend_queue.len()
+    }
+
+    // 1画面分のフレームを構築する。終端に達した場合は残りを空けて、falseを返す。
+    // 送信するデータをすべて保持している場合のみ使える
+    pub fn next_tiles(&mut self, num_tiles: usize) -> (Vec<Vec<u8>>, bool) {
+        let (tiles, keep_going) = self.plan_tiles(num_tiles);
+        let frames = tiles
+            .into_iter()
+            .map(|tile| match (tile, &self.content) {
+                (Tile::Frame(frame), _) => frame,
+                (Tile::Block { seq, offset, len }, Content::Memory(content)) => {
+                    let offset = offset as usize;
+                    self.block_frame(seq, &content[offset..offset + len])
+                }
+                (Tile::Block { .. }, _) => panic!("content is not buffered"),
+            })
+            .collect();
+        (frames, keep_going)
+    }
+
+    // 1画面分のフレームを決める。元のファイルのまま送るブロックは読み込む範囲だけを返す
+    pub fn plan_tiles(&mut self, num_tiles: usize) -> (Vec<Tile>, bool) {
+        let mut tiles = Vec::with_capacity(num_tiles);
+        let mut keep_going = true;
+        while keep_going && tiles.len() < num_tiles {
+            let (tile, more) = self.plan_next();
+            tiles.push(tile);
+            keep_going = more;
+        }
+        (tiles, keep_going)
+    }
+
+    // 次のフレームを決める。終端のフレームの場合はfalseを返す
+    fn plan_next(&mut self) -> (Tile, bool) {
+        self.frame_count += 1;
+        // 途中から受信を開始した受信側のために、定期的にメタデータを挟む
+        if self.mode != Mode::Sequential
+            && self
+                .frame_count
+                .is_multiple_of(self.meta_interval.max(2) as u64)
+        {
+            (Tile::Frame(self.meta_frame.clone()), true)
+        } else if self.mode == Mode::Fountain {
+            self.build_symbol()
+        } else {
+            self.plan_block()
+        }
+    }
+
+    fn plan_block(&mut self) -> (Tile, bool) {
+        // 再送を要求されたブロックを優先する
+        if let Some(seq) = self.resend_queue.pop_front() {
+            return (self.block_at(seq), true);
+        }
+        if self.mode == Mode::Loop && self.read_offset == self.content_len {
+            self.read_offset = 0;
+        }
+        // 最後のブロックが短い場合も、送り終えたら次は終端になるように切り上げる
+        let seq = self.read_offset.div_ceil(self.payload_size() as u64);
+        let tile = self.block_at(seq);
+        match tile {
+            Tile::Block { len, .. } => {
+                self.read_offset += len as u64;
+                (tile, true)
+            }
+            // 空のファイルを繰り返し送る場合は、メタデータの合間に終端を送り続ける
+            Tile::Frame(_) if self.mode == Mode::Loop => (tile, true),
+            // 最初のメタデータを取りこぼした受信側も再送コードを表示できるように、終端の直前にもう一度送る
+            Tile::Frame(_) if !self.meta_before_eof => {
+                self.meta_before_eof = true;
+                (Tile::Frame(self.meta_frame.clone()), true)
+            }
+            Tile::Frame(_) => {
+                self.meta_before_eof = false;
+                (tile, false)
+            }
+        }
+    }
+
+    // seq番目のブロック。範囲外であれば終端のフレームになる
+    fn block_at(&self, seq: u64) -> Tile {
+        let payload_size = self.payload_size() as u64;
+        let offset = seq.saturating_mul(payload_size).min(self.content_len);
+        let len = (self.content_len - offset).min(payload_size) as usize;
+        if len > 0 {
+            return Tile::Block { seq, offset, len };
+        }
+        let mut frame = vec![0; self.block_size];
+        build_header(
+            Header::new(FrameType::Eof, self.session, seq, 0),
+            &mut frame[..],
+        );
+        Tile::Frame(frame)
+    }
+
+    // seq番目のブロックの内容dataからフレームを構築する
+    pub fn block_frame(&self, seq: u64, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; self.block_size];
+        frame[HEADER_SIZE..HEADER_SIZE + data.len()].copy_from_slice(data);
+        build_header(
+            Header::new(FrameType::Data, self.session, seq, data.len() as u16),
+            &mut frame[..],
+        );
+        frame
+    }
+
+    fn build_symbol(&mut self) -> (Tile, bool) {
+        let encoder = match &self.content {
+            Content::Fountain(e) => e,
+            _ => panic!("content is not fountain encoded"),
+        };
+        // シンボル番号は1から始める
+        self.symbol_id = self.symbol_id.checked_add(1).unwrap_or(1);
+        let payload_size = self.payload_size();
+        let mut frame = vec![0; self.block_size];
+        encoder.symbol(self.symbol_id, &mut frame[HEADER_SIZE..]);
+        build_header(
+            Header::new(
+                FrameType::Parity,
+                self.session,
+                self.symbol_id as u64,
+                payload_size as u16,
+            ),
+            &mut frame[..],
+        );
+        (Tile::Frame(frame), true)
+    }
+
+    // 再送コードで指定された [start, end) のブロックを送信キューに積む
+    pub fn queue_resend(&mut self, ranges: &[(u64, u64)]) {
+        let num_blocks = self.num_blocks();
+        for &(start, end) in ranges {
+            self.resend_queue
+                .extend(start.min(num_blocks)..end.min(num_blocks));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::parse_header;
+
+    fn settings(mode: Mode) -> Settings {
+        Settings {
+            block_size: 122,
+            mode,
+            meta_interval: 4,
+            compression: false,
+        }
+    }
+
+    fn headers(tiles: &[Vec<u8>]) -> Vec<(FrameType, u64)> {
+        tiles
+            .iter()
+            .map(|t| {
+                let h = parse_header(t).unwrap();
+                (h.frame_type, h.seq)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sequential_sends_each_block_once() {
+        let data = vec![1u8; 250];
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Sequential), "", 1);
+        assert_eq!(encoder.num_blocks(), 3);
+        let (tiles, keep_going) = encoder.next_tiles(10);
+        assert!(!keep_going);
+        assert_eq!(
+            headers(&tiles),
+            vec![
+                (FrameType::Data, 0),
+                (FrameType::Data, 1),
+                (FrameType::Data, 2),
+                (FrameType::Meta, 0),
+                (FrameType::Eof, 3)
+            ]
+        );
+        assert!(tiles.iter().all(|t| t.len() == 122));
+        // 再送した後もメタデータと終端を送る
+        encoder.queue_resend(&[(1, 2)]);
+        let (tiles, keep_going) = encoder.next_tiles(10);
+        assert!(!keep_going);
+        assert_eq!(
+            headers(&tiles),
+            vec![
+                (FrameType::Data, 1),
+                (FrameType::Meta, 0),
+                (FrameType::Eof, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn loop_repeats_with_metadata() {
+        let data = vec![1u8; 200];
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Loop), "", 1);
+        let (tiles, keep_going) = encoder.next_tiles(8);
+        assert!(keep_going);
+        assert_eq!(
+            headers(&tiles),
+            vec![
+                (FrameType::Data, 0),
+                (FrameType::Data, 1),
+                (FrameType::Data, 0),
+                (FrameType::Meta, 0),
+                (FrameType::Data, 1),
+                (FrameType::Data, 0),
+                (FrameType::Data, 1),
+                (FrameType::Meta, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn fountain_symbols_start_at_one() {
+        let data = vec![1u8; 1000];
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Fountain), "", 1);
+        let (tiles, _) = encoder.next_tiles(3);
+        assert_eq!(
+            headers(&tiles),
+            vec![
+                (FrameType::Parity, 1),
+                (FrameType::Parity, 2),
+                (FrameType::Parity, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_file_keeps_mode() {
+        let mut encoder = Encoder::new(b"a", &[], &settings(Mode::Sequential), "", 1);
+        assert_eq!(encoder.num_blocks(), 0);
+        let (tiles, keep_going) = encoder.next_tiles(3);
+        assert!(!keep_going);
+        assert_eq!(
+            headers(&tiles),
+            vec![(FrameType::Meta, 0), (FrameType::Eof, 0)]
+        );
+        // 繰り返しは途中から受信を始めた受信側のためにメタデータを挟み続ける
+        let mut encoder = Encoder::new(b"a", &[], &settings(Mode::Loop), "", 1);
+        assert_eq!(encoder.mode(), Mode::Loop);
+        let (tiles, keep_going) = encoder.next_tiles(8);
+        assert!(keep_going);
+        assert_eq!(
+            headers(&tiles),
+            vec![
+                (FrameType::Eof, 0),
+                (FrameType::Eof, 0),
+                (FrameType::Eof, 0),
+                (FrameType::Meta, 0),
+                (FrameType::Eof, 0),
+                (FrameType::Eof, 0),
+                (FrameType::Eof, 0),
+                (FrameType::Meta, 0)
+            ]
+        );
+        let mut encoder = Encoder::new(b"a", &[], &settings(Mode::Fountain), "", 1);
+        assert_eq!(encoder.mode(), Mode::Fountain);
+        let (tiles, keep_going) = encoder.next_tiles(4);
+        assert!(keep_going);
+        assert_eq!(headers(&tiles)[3], (FrameType::Meta, 0));
+    }
+
+    #[test]
+    fn resend_queue_is_sent_first_and_clamped() {
+        let data = vec![1u8; 500];
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Sequential), "", 1);
+        encoder.queue_resend(&[(3, 4), (4, 100)]);
+        let (tiles, _) = encoder.next_tiles(3);
+        assert_eq!(
+            headers(&tiles),
+            vec![
+                (FrameType::Data, 3),
+                (FrameType::Data, 4),
+                (FrameType::Data, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn sent_blocks_follow_progress() {
+        let data = vec![1u8; 250];
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Sequential), "", 1);
+        assert_eq!(encoder.sent_blocks(), 0);
+        encoder.next_tiles(2);
+        assert_eq!(encoder.sent_blocks(), 2);
+        encoder.next_tiles(2);
+        assert_eq!(encoder.sent_blocks(), 3);
+        encoder.queue_resend(&[(0, 2)]);
+        assert_eq!(encoder.queued_resends(), 2);
+        encoder.next_tiles(1);
+        assert_eq!(encoder.queued_resends(), 1);
+        // 繰り返しは周の先頭に戻る
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Loop), "", 1);
+        encoder.next_tiles(3);
+        assert_eq!(encoder.sent_blocks(), 3);
+        // 4フレーム目はメタデータ
+        encoder.next_tiles(2);
+        assert_eq!(encoder.sent_blocks(), 1);
+        let mut encoder = Encoder::new(b"a", &data, &settings(Mode::Fountain), "", 1);
+        encoder.next_tiles(5);
+        assert_eq!(encoder.sent_blocks(), 4);
+    }
+
+    #[test]
+    fn external_blocks_match_buffered() {
+        let data: Vec<u8> = (0..500).map(|i| (i % 251) as u8).collect();
+        let settings = settings(Mode::Loop);
+        let mut preparer = Preparer::new(&settings, "", false);
+        for piece in data.chunks(64) {
+            preparer.push(piece);
+        }
+        let mut external = preparer.finish(b"a", 1);
+        let mut buffered = Encoder::new(b"a", &data, &settings, "", 1);
+        assert!(!external.is_buffered());
+        assert_eq!(external.meta_frame(), buffered.meta_frame());
+        external.queue_resend(&[(4, 5)]);
+        buffered.queue_resend(&[(4, 5)]);
+        let (tiles, keep_going) = external.plan_tiles(12);
+        assert!(keep_going);
+        assert_eq!(
+            tiles[..2],
+            [
+                Tile::Block {
+                    seq: 4,
+                    offset: 400,
+                    len: 100
+                },
+                Tile::Block {
+                    seq: 0,
+                    offset: 0,
+                    len: 100
+                }
+            ]
+        );
+        let frames: Vec<Vec<u8>> = tiles
+            .into_iter()
+            .map(|tile| match tile {
+                Tile::Frame(frame) => frame,
+                Tile::Block { seq, offset, len } => {
+                    external.block_frame(seq, &data[offset as usize..offset as usize + len])
+                }
+            })
+            .collect();
+        assert_eq!(frames, buffered.next_tiles(12).0);
+    }
+
+    #[test]
+    fn metadata_frame() {
+        let encoder = Encoder::new(b"name.txt", b"hello", &settings(Mode::Loop), "", 9);
+        let frame = encoder.meta_frame();
+        let h = parse_header(frame).unwrap();
+        assert_eq!(h.frame_type, FrameType::Meta);
+        assert_eq!(h.session, 9);
+        // メタデータのペイロードサイズはブロックサイズを表す
+        assert_eq!(h.size as usize, 122 - HEADER_SIZE);
+        let meta = crate::header::parse_metadata(&h, h.payload(frame)).unwrap();
+        assert_eq!(meta.name, b"name.txt");
+        assert_eq!(meta.file_size, 5);
+        assert_eq!(meta.mode, Mode::Loop as u8);
+        assert_eq!(meta.digest, <[u8; 32]>::from(Sha256::digest(b"hello")));
+    }
+}
@@ -0,0 +1,441 @@
+// LT符号(Luby Transform)によるファウンテン符号化
+//
+// 送信側はファイルをK個のブロックに分割し、シンボル番号から決まる
+// 疑似乱数でブロックの組み合わせを選んでXORしたシンボルを無限に生成する。
+// 受信側は任意の順序・欠落ありでシンボルを受け取り、K個より少し多く
+// 受け取った時点でファイル全体を復元できる。
+
+use std::collections::HashSet;
+
+// 復号が停滞した際にガウスの消去法で解く未知ブロック数の上限
+const ELIMINATION_LIMIT: usize = 1024;
+
+// ロバストソリトン分布のパラメータ
+const SOLITON_C: f64 = 0.03;
+const SOLITON_DELTA: f64 = 0.5;
+
+pub struct Encoder {
+    data: Vec<u8>,
+    block_size: usize,
+    num_blocks: usize,
+    degree_cdf: Vec<f64>,
+}
+
+impl Encoder {
+    // 大きなファイルでも複製しないように、データはそのまま受け取る
+    pub fn new(data: Vec<u8>, block_size: usize) -> Self {
+        let num_blocks = data.len().div_ceil(block_size);
+        Self {
+            data,
+            block_size,
+            num_blocks,
+            degree_cdf: degree_cdf(num_blocks),
+        }
+    }
+
+    // シンボル番号idのシンボルをoutに書き込む。最後のブロックが短い場合は残りを0とみなす
+    pub fn symbol(&self, id: u32, out: &mut [u8]) {
+        let out = &mut out[..self.block_size];
+        out.fill(0);
+        for b in neighbors(id, self.num_blocks, &self.degree_cdf) {
+            let end = ((b + 1) * self.block_size).min(self.data.len());
+            xor_into(out, &self.data[b * self.block_size..end]);
+        }
+    }
+}
+
+struct PendingSymbol {
+    neighbors: Vec<usize>,
+    data: Vec<u8>,
+}
+
+pub struct Decoder {
+    block_size: usize,
+    num_blocks: usize,
+    degree_cdf: Vec<f64>,
+    blocks: Vec<Option<Vec<u8>>>,
+    decoded: usize,
+    seen: HashSet<u32>,
+    symbols: Vec<Option<PendingSymbol>>,
+    // ブロック番号 -> そのブロックを含む未解決シンボルのインデックス
+    waiting: Vec<Vec<usize>>,
+    // 消去法を再試行するまでに必要な新しいシンボル数。
+    // 解けなかった場合は少なくとも階数の不足分のシンボルが届かないと解けない
+    elimination_wait: usize,
+    // 消去法を試みた回数
+    eliminations: usize,
+}
+
+impl Decoder {
+    pub fn new(num_blocks: usize, block_size: usize) -> Self {
+        Self {
+            block_size,
+            num_blocks,
+            degree_cdf: degree_cdf(num_blocks),
+            blocks: vec![None; num_blocks],
+            decoded: 0,
+            seen: HashSet::new(),
+            symbols: Vec::new(),
+            waiting: vec![Vec::new(); num_blocks],
+            elimination_wait: 0,
+            eliminations: 0,
+        }
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.num_blocks
+    }
+
+    pub fn decoded_blocks(&self) -> usize {
+        self.decoded
+    }
+
+    pub fn received_symbols(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.decoded == self.num_blocks
+    }
+
+    // シンボルを追加する。新たに復元できたブロックがあればtrueを返す
+    pub fn push(&mut self, id: u32, data: &[u8]) -> bool {
+        if self.is_complete() || data.len() < self.block_size || !self.seen.insert(id) {
+            return false;
+        }
+        let before = self.decoded;
+        let mut data = data[..self.block_size].to_vec();
+        let mut unknown = Vec::new();
+        for b in neighbors(id, self.num_blocks, &self.degree_cdf) {
+            match &self.blocks[b] {
+                Some(block) => xor_into(&mut data, block),
+                None => unknown.push(b),
+            }
+        }
+        match unknown.len() {
+            0 => {}
+            1 => self.resolve(unknown[0], data),
+            _ => {
+                let idx = self.symbols.len();
+                for &b in &unknown {
+                    self.waiting[b].push(idx);
+                }
+                self.symbols.push(Some(PendingSymbol {
+                    neighbors: unknown,
+                    data,
+                }));
+            }
+        }
+        self.elimination_wait = self.elimination_wait.saturating_sub(1);
+        // 剥離復号が停滞し、ブロック数以上のシンボルが届いている場合だけ消去法を試す
+        if self.decoded == before
+            && !self.is_complete()
+            && self.seen.len() >= self.num_blocks
+            && self.elimination_wait == 0
+        {
+            self.eliminate();
+        }
+        self.decoded > before
+    }
+
+    // 復元したデータを返す。未完了の場合はNone
+    pub fn finish(&self, file_size: u64) -> Option<Vec<u8>> {
+        if !self.is_complete() {
+            return None;
+        }
+        let mut out = Vec::with_capacity(self.num_blocks * self.block_size);
+        for block in &self.blocks {
+            out.extend_from_slice(block.as_ref().unwrap());
+        }
+        out.truncate(file_size as usize);
+        Some(out)
+    }
+
+    // ブロックbの内容が確定したので、それを含むシンボルを順に剥がしていく
+    fn resolve(&mut self, b: usize, data: Vec<u8>) {
+        let mut queue = vec![(b, data)];
+        while let Some((b, data)) = queue.pop() {
+            if self.blocks[b].is_some() {
+                continue;
+            }
+            for idx in std::mem::take(&mut self.waiting[b]) {
+                let sym = match self.symbols[idx].as_mut() {
+                    Some(s) => s,
+                    None => continue,
+                };
+                xor_into(&mut sym.data, &data);
+                sym.neighbors.retain(|&x| x != b);
+                if sym.neighbors.len() <= 1 {
+                    let sym = self.symbols[idx].take().unwrap();
+                    if let Some(&next) = sym.neighbors.first() {
+                        queue.push((next, sym.data));
+                    }
+                }
+            }
+            self.blocks[b] = Some(data);
+            self.decoded += 1;
+        }
+    }
+
+    // 未知ブロックが十分少ない場合はGF(2)上の連立方程式として解く。
+    // 解けなかった場合は、次に試すまでに必要なシンボル数を設定する
+    fn eliminate(&mut self) {
+        let unknowns = self.num_blocks - self.decoded;
+        if unknowns > ELIMINATION_LIMIT {
+            return;
+        }
+        let rows: Vec<usize> = (0..self.symbols.len())
+            .filter(|&i| self.symbols[i].is_some())
+            .collect();
+        if rows.len() < unknowns {
+            self.elimination_wait = unknowns - rows.len();
+            return;
+        }
+        self.eliminations += 1;
+        let mut col_of = vec![usize::MAX; self.num_blocks];
+        let mut cols = Vec::with_capacity(unknowns);
+        for (b, block) in self.blocks.iter().enumerate() {
+            if block.is_none() {
+                col_of[b] = cols.len();
+                cols.push(b);
+            }
+        }
+
+        // 係数行列と、各行が元のどの行の組み合わせかを表す行列をビット列で持つ
+        let cw = unknowns.div_ceil(64);
+        let rw = rows.len().div_ceil(64);
+        let mut coef: Vec<Vec<u64>> = Vec::with_capacity(rows.len());
+        let mut comb: Vec<Vec<u64>> = Vec::with_capacity(rows.len());
+        for (r, &idx) in rows.iter().enumerate() {
+            let mut c = vec![0u64; cw];
+            for &b in &self.symbols[idx].as_ref().unwrap().neighbors {
+                let col = col_of[b];
+                c[col / 64] ^= 1 << (col % 64);
+            }
+            let mut e = vec![0u64; rw];
+            e[r / 64] |= 1 << (r % 64);
+            coef.push(c);
+            comb.push(e);
+        }
+
+        let mut pivots = Vec::with_capacity(unknowns);
+        for col in 0..unknowns {
+            let (w, bit) = (col / 64, 1u64 << (col % 64));
+            let row = pivots.len();
+            let found = match (row..coef.len()).find(|&r| coef[r][w] & bit != 0) {
+                Some(r) => r,
+                // 階数を数えるために残りの列も消去する
+                None => continue,
+            };
+            coef.swap(row, found);
+            comb.swap(row, found);
+            let (src_c, src_e) = (coef[row].clone(), comb[row].clone());
+            for r in 0..coef.len() {
+                if r != row && coef[r][w] & bit != 0 {
+                    xor_words(&mut coef[r], &src_c);
+                    xor_words(&mut comb[r], &src_e);
+                }
+            }
+            pivots.push(row);
+        }
+        if pivots.len() < unknowns {
+            self.elimination_wait = unknowns - pivots.len();
+            return;
+        }
+
+        let mut solved = Vec::with_capacity(unknowns);
+        for (col, &row) in pivots.iter().enumerate() {
+            let mut data = vec![0u8; self.block_size];
+            for (r, &idx) in rows.iter().enumerate() {
+                if comb[row][r / 64] & (1 << (r % 64)) != 0 {
+                    xor_into(&mut data, &self.symbols[idx].as_ref().unwrap().data);
+                }
+            }
+            solved.push((cols[col], data));
+        }
+        for (b, data) in solved {
+            self.resolve(b, data);
+        }
+    }
+}
+
+fn xor_into(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+fn xor_words(dst: &mut [u64], src: &[u64]) {
+    for (d, s) in dst.iter_mut().zip(src) {
+        *d ^= s;
+    }
+}
+
+// ロバストソリトン分布の累積分布 (index i が次数 i+1 に対応)
+fn degree_cdf(k: usize) -> Vec<f64> {
+    if k <= 1 {
+        return vec![1.0];
+    }
+    let kf = k as f64;
+    let r = SOLITON_C * (kf / SOLITON_DELTA).ln() * kf.sqrt();
+    let spike = ((kf / r).floor() as usize).clamp(1, k);
+    let mut mu: Vec<f64> = (1..=k)
+        .map(|d| {
+            let rho = if d == 1 {
+                1.0 / kf
+            } else {
+                1.0 / (d as f64 * (d - 1) as f64)
+            };
+            let tau = if d < spike {
+                r / (d as f64 * kf)
+            } else if d == spike {
+                r * (r / SOLITON_DELTA).ln() / kf
+            } else {
+                0.0
+            };
+            rho + tau.max(0.0)
+        })
+        .collect();
+    let z: f64 = mu.iter().sum();
+    let mut acc = 0.0;
+    for m in mu.iter_mut() {
+        acc += *m / z;
+        *m = acc;
+    }
+    mu
+}
+
+// シンボル番号から、そのシンボルに含まれるブロック番号の集合を決定する
+fn neighbors(id: u32, k: usize, cdf: &[f64]) -> Vec<usize> {
+    if k == 0 {
+        return Vec::new();
+    }
+    let mut rng = SplitMix64(0x5244_5346_0000_0000 ^ id as u64);
+    let u = rng.next_f64();
+    let degree = (cdf.partition_point(|&c| c < u) + 1).min(k);
+    let mut ret = Vec::with_capacity(degree);
+    while ret.len() < degree {
+        let b = (rng.next_u64() % k as u64) as usize;
+        if !ret.contains(&b) {
+            ret.push(b);
+        }
+    }
+    ret
+}
+
+// 送受信で同じ系列を得るための疑似乱数生成器
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 257) as u8).collect()
+    }
+
+    // ids の順にシンボルを渡し、復元できるまでに使ったシンボル数を返す
+    fn decode(data: &[u8], block_size: usize, ids: impl Iterator<Item = u32>) -> Option<usize> {
+        let encoder = Encoder::new(data.to_vec(), block_size);
+        let mut decoder = Decoder::new(data.len().div_ceil(block_size), block_size);
+        let mut symbol = vec![0; block_size];
+        for (n, id) in ids.enumerate() {
+            encoder.symbol(id, &mut symbol);
+            decoder.push(id, &symbol);
+            if decoder.is_complete() {
+                assert_eq!(decoder.finish(data.len() as u64).unwrap(), data);
+                return Some(n + 1);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn decode_in_order() {
+        let used = decode(&data(100 * 64 - 10), 64, 1..10000).unwrap();
+        // 受信したシンボル数はブロック数より少し多い程度で済む
+        assert!(used < 100 * 2, "{}", used);
+    }
+
+    #[test]
+    fn decode_with_loss_and_reordering() {
+        let ids = (1..20000u32).filter(|id| id % 3 != 0).rev();
+        assert!(decode(&data(50 * 32), 32, ids).is_some());
+    }
+
+    #[test]
+    fn single_block() {
+        assert_eq!(decode(&data(10), 32, 1..10), Some(1));
+    }
+
+    #[test]
+    fn duplicates_and_short_symbols_are_ignored() {
+        let encoder = Encoder::new(data(40 * 16), 16);
+        let mut decoder = Decoder::new(40, 16);
+        let mut symbol = vec![0; 16];
+        encoder.symbol(1, &mut symbol);
+        decoder.push(1, &symbol);
+        assert!(!decoder.push(1, &symbol));
+        assert!(!decoder.push(2, &symbol[..8]));
+        assert_eq!(decoder.received_symbols(), 1);
+        assert_eq!(decoder.finish(40 * 16), None);
+    }
+
+    #[test]
+    fn elimination_waits_for_enough_symbols() {
+        let (num_blocks, block_size) = (800, 8);
+        let data = data(num_blocks * block_size);
+        let encoder = Encoder::new(data.clone(), block_size);
+        let mut decoder = Decoder::new(num_blocks, block_size);
+        let mut symbol = vec![0; block_size];
+        let (mut attempts, mut since, mut required) = (0, 0, 0);
+        for id in (1..100000).filter(|id| id % 3 != 0) {
+            encoder.symbol(id, &mut symbol);
+            decoder.push(id, &symbol);
+            since += 1;
+            if decoder.eliminations > attempts {
+                // ブロック数より前や、前回の不足分が届く前には試さない
+                assert!(decoder.received_symbols() >= num_blocks);
+                assert!(since >= required, "{} < {}", since, required);
+                attempts = decoder.eliminations;
+                since = 0;
+                required = decoder.elimination_wait;
+            }
+            if decoder.is_complete() {
+                break;
+            }
+        }
+        assert!(attempts > 1);
+        assert_eq!(decoder.finish(data.len() as u64).unwrap(), data);
+    }
+
+    #[test]
+    fn degree_distribution() {
+        let cdf = degree_cdf(1000);
+        assert_eq!(cdf.len(), 1000);
+        assert!((cdf[999] - 1.0).abs() < 1e-9);
+        assert!(cdf.windows(2).all(|w| w[0] <= w[1]));
+        for id in 1..100 {
+            let n = neighbors(id, 1000, &cdf);
+            let mut sorted = n.clone();
+            sorted.sort();
+            sorted.dedup();
+            assert_eq!(sorted.len(), n.len());
+            assert!(n.iter().all(|&b| b < 1000));
+        }
+    }
+}
@@ -0,0 +1,454 @@
+// フレームヘッダ (プロトコルバージョン1)
+//
+//  0: マジック "RF" (2B)
+//  2: プロトコルバージョン (1B)
+//  3: フレーム種別 (1B)
+//  4: セッションID (4B, LE)
+//  8: シーケンス番号 (8B, LE)
+// 16: ペイロードサイズ (2B, LE)
+// 18: CRC32 (4B, LE) ... CRC以外の全バイトが対象
+// 22: ペイロード
+//
+// マジックを持たないフレームは旧形式として解釈する。旧形式のヘッダは (seq << 12) | size の4B (LE) で、CRCはない。
+// 旧形式でもseqが16で割って4余り、サイズが1618のフレームは "RF" で始まるので、
+// バージョンかCRCが合わず、フレーム長がちょうどサイズ分のものは旧形式として解釈する。
+
+pub const MAGIC: [u8; 2] = *b"RF";
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 22;
+pub const LEGACY_HEADER_SIZE: usize = 4;
+
+const CRC_OFFSET: usize = 18;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameType {
+    // ファイル名やサイズなどの情報
+    Meta = 0,
+    // ファイルのブロック (seqはブロック番号)
+    Data = 1,
+    // 終端 (seqはブロック数)
+    Eof = 2,
+    // ファウンテン符号のシンボル (seqはシンボル番号)
+    Parity = 3,
+    // 送信待機中などの制御用
+    Control = 4,
+}
+
+impl FrameType {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Meta),
+            1 => Some(Self::Data),
+            2 => Some(Self::Eof),
+            3 => Some(Self::Parity),
+            4 => Some(Self::Control),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    // 0は旧形式
+    pub version: u8,
+    pub frame_type: FrameType,
+    pub session: u32,
+    pub seq: u64,
+    pub size: u16,
+}
+
+impl Header {
+    pub fn new(frame_type: FrameType, session: u32, seq: u64, size: u16) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            frame_type,
+            session,
+            seq,
+            size,
+        }
+    }
+
+    pub fn header_size(&self) -> usize {
+        if self.version == 0 {
+            LEGACY_HEADER_SIZE
+        } else {
+            HEADER_SIZE
+        }
+    }
+
+    pub fn payload<'a>(&self, b: &'a [u8]) -> &'a [u8] {
+        &b[self.header_size()..self.header_size() + self.size as usize]
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    // このツールのQRコードではない
+    Foreign,
+    // 未対応のプロトコルバージョン
+    Incompatible(u8),
+    // CRC不一致などで壊れている
+    Corrupted,
+}
+
+pub fn parse_header(b: &[u8]) -> Result<Header, ParseError> {
+    let has_magic = b.len() >= 3 && b[..2] == MAGIC;
+    if !has_magic {
+        return parse_legacy(b).ok_or(ParseError::Foreign);
+    }
+    if b[2] == PROTOCOL_VERSION {
+        if let Some(h) = parse_v1(b) {
+            return Ok(h);
+        }
+    }
+    // "RF" で始まる旧形式のフレームは必ずブロックいっぱいのデータなので、長さが合わないものは壊れたフレームとみなす
+    if let Some(h) = parse_legacy(b).filter(|h| LEGACY_HEADER_SIZE + h.size as usize == b.len()) {
+        return Ok(h);
+    }
+    if b[2] != PROTOCOL_VERSION {
+        Err(ParseError::Incompatible(b[2]))
+    } else {
+        Err(ParseError::Corrupted)
+    }
+}
+
+fn parse_v1(b: &[u8]) -> Option<Header> {
+    if b.len() < HEADER_SIZE || read_u32(&b[CRC_OFFSET..]) != frame_crc(b, CRC_OFFSET, HEADER_SIZE)
+    {
+        return None;
+    }
+    let h = Header {
+        version: b[2],
+        frame_type: FrameType::from_u8(b[3])?,
+        session: read_u32(&b[4..]),
+        seq: read_u64(&b[8..]),
+        size: (b[16] as u16) | ((b[17] as u16) << 8),
+    };
+    if HEADER_SIZE + h.size as usize > b.len() {
+        return None;
+    }
+    Some(h)
+}
+
+// 旧形式はseq=0がメタデータ(サイズ0なら待機中)、seq>=1がブロック、サイズ0が終端。
+// 終端のseqはブロック数+1 (最後のブロックが短い場合はブロック数) になっている
+fn parse_legacy(b: &[u8]) -> Option<Header> {
+    if b.len() < LEGACY_HEADER_SIZE {
+        return None;
+    }
+    let x = read_u32(b);
+    let (seq, size) = ((x >> 12) as u64, (x & 0xfff) as u16);
+    if LEGACY_HEADER_SIZE + size as usize > b.len() {
+        return None;
+    }
+    let (frame_type, seq) = match (seq, size) {
+        (0, 0) => (FrameType::Control, 0),
+        (0, _) => (FrameType::Meta, 0),
+        (_, 0) => (FrameType::Eof, seq - 1),
+        _ => (FrameType::Data, seq - 1),
+    };
+    Some(Header {
+        version: 0,
+        frame_type,
+        session: 0,
+        seq,
+        size,
+    })
+}
+
+// CRCはペイロードも含めて計算するので、ペイロードを書き込んだ後に呼び出すこと
+pub fn build_header(h: Header, output: &mut [u8]) {
+    output[..2].copy_from_slice(&MAGIC);
+    output[2] = h.version;
+    output[3] = h.frame_type as u8;
+    output[4..8].copy_from_slice(&h.session.to_le_bytes());
+    output[8..16].copy_from_slice(&h.seq.to_le_bytes());
+    output[16..18].copy_from_slice(&h.size.to_le_bytes());
+    let crc = frame_crc(output, CRC_OFFSET, HEADER_SIZE);
+    output[CRC_OFFSET..HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn frame_crc(b: &[u8], crc_offset: usize, header_size: usize) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&b[..crc_offset]);
+    hasher.update(&b[header_size..]);
+    hasher.finalize()
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    let mut x = [0u8; 4];
+    x.copy_from_slice(&b[..4]);
+    u32::from_le_bytes(x)
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    let mut x = [0u8; 8];
+    x.copy_from_slice(&b[..8]);
+    u64::from_le_bytes(x)
+}
+
+// 送信方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    // ブロックを先頭から順に1回ずつ送る
+    Sequential = 0,
+    // LT符号化したシンボルを停止するまで送り続ける
+    Fountain = 1,
+    // 全ブロックを停止するまで繰り返し送る
+    Loop = 2,
+}
+
+impl Mode {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Sequential),
+            1 => Some(Self::Fountain),
+            2 => Some(Self::Loop),
+            _ => None,
+        }
+    }
+}
+
+pub const DIGEST_SIZE: usize = 32;
+// メタデータの固定長部分
+pub const METADATA_MIN_SIZE: usize = 8 + 1 + DIGEST_SIZE + 2;
+
+// メタデータフレームのペイロード。
+// ファイルサイズ(8B) + 送信方式(1B) + SHA-256(32B) + ファイル名長(2B) + ファイル名(UTF8) + 拡張フィールド
+// 拡張フィールドは タグ(1B, 0以外) + 長さ(2B) + 値 の繰り返しで、未知のタグは読み飛ばす。
+// 旧形式は ファイルサイズ(8B) + NUL終端したファイル名(UTF8) だけで、逐次送信のみ、ハッシュ値はない。
+pub struct Metadata {
+    pub file_size: u64,
+    pub mode: u8,
+    pub digest: [u8; DIGEST_SIZE],
+    pub name: Vec<u8>,
+    pub extensions: Vec<(u8, Vec<u8>)>,
+}
+
+// 拡張フィールドのタグ
+// 圧縮方式 (file_sizeは圧縮後のサイズ、digestは元のファイルのハッシュ値になる)
+pub const EXT_CODEC: u8 = 1;
+// 暗号化のパラメータ (digestは載せずに0で埋める)
+pub const EXT_CIPHER: u8 = 2;
+
+impl Metadata {
+    pub fn extension(&self, tag: u8) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, v)| &v[..])
+    }
+}
+
+// ファイル名が収まらない場合は切り詰める
+pub fn build_metadata(m: &Metadata, output: &mut [u8]) {
+    output.fill(0);
+    output[..8].copy_from_slice(&m.file_size.to_le_bytes());
+    output[8] = m.mode;
+    output[9..9 + DIGEST_SIZE].copy_from_slice(&m.digest);
+    let ext_len: usize = m.extensions.iter().map(|(_, v)| 3 + v.len()).sum();
+    let mut offset = METADATA_MIN_SIZE;
+    let name_len = m.name.len().min(output.len() - offset - ext_len);
+    output[offset - 2..offset].copy_from_slice(&(name_len as u16).to_le_bytes());
+    output[offset..offset + name_len].copy_from_slice(&m.name[..name_len]);
+    offset += name_len;
+    for (tag, value) in &m.extensions {
+        output[offset] = *tag;
+        output[offset + 1..offset + 3].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        output[offset + 3..offset + 3 + value.len()].copy_from_slice(value);
+        offset += 3 + value.len();
+    }
+}
+
+pub fn parse_metadata(h: &Header, b: &[u8]) -> Option<Metadata> {
+    if h.version == 0 {
+        let name = b.get(8..)?;
+        let name_len = name.iter().position(|&x| x == 0).unwrap_or(name.len());
+        return Some(Metadata {
+            file_size: read_u64(b),
+            mode: Mode::Sequential as u8,
+            digest: [0; DIGEST_SIZE],
+            name: name[..name_len].to_vec(),
+            extensions: Vec::new(),
+        });
+    }
+
+    if b.len() < METADATA_MIN_SIZE {
+        return None;
+    }
+    let mut digest = [0u8; DIGEST_SIZE];
+    digest.copy_from_slice(&b[9..9 + DIGEST_SIZE]);
+    let mut m = Metadata {
+        file_size: read_u64(b),
+        mode: b[8],
+        digest,
+        name: Vec::new(),
+        extensions: Vec::new(),
+    };
+    let name_len = (b[METADATA_MIN_SIZE - 2] as usize) | ((b[METADATA_MIN_SIZE - 1] as usize) << 8);
+    let mut offset = METADATA_MIN_SIZE + name_len;
+    m.name = b.get(METADATA_MIN_SIZE..offset)?.to_vec();
+    while offset + 3 <= b.len() && b[offset] != 0 {
+        let len = (b[offset + 1] as usize) | ((b[offset + 2] as usize) << 8);
+        let value = b.get(offset + 3..offset + 3 + len)?;
+        m.extensions.push((b[offset], value.to_vec()));
+        offset += 3 + len;
+    }
+    Some(m)
+}
+
+pub fn to_hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(h: Header, payload: &[u8]) -> Vec<u8> {
+        let mut b = vec![0; HEADER_SIZE + payload.len()];
+        b[HEADER_SIZE..].copy_from_slice(payload);
+        build_header(h, &mut b);
+        b
+    }
+
+    // 旧形式の送信ページと同じようにフレームを構築する。
+    // ヘッダは (seq << 12) | size の4B (LE) で、ペイロードの後はブロックサイズまで0で埋める
+    fn legacy_frame(block_size: usize, seq: u32, payload: &[u8], size: usize) -> Vec<u8> {
+        let mut b = vec![0; block_size];
+        let x = (seq << 12) | size as u32;
+        b[0] = (x & 0xff) as u8;
+        b[1] = ((x >> 8) & 0xff) as u8;
+        b[2] = ((x >> 16) & 0xff) as u8;
+        b[3] = ((x >> 24) & 0xff) as u8;
+        b[4..4 + payload.len()].copy_from_slice(payload);
+        b
+    }
+
+    // 旧形式の最初のフレーム。ファイルサイズ(8B) + ファイル名(UTF8)で、sizeはブロックサイズ - 4
+    fn legacy_meta(block_size: usize, file_size: u64, name: &str) -> Vec<u8> {
+        let mut payload = vec![0; 8 + name.len()];
+        payload[..8].copy_from_slice(&file_size.to_le_bytes());
+        payload[8..].copy_from_slice(name.as_bytes());
+        legacy_frame(block_size, 0, &payload, block_size - 4)
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let b = frame(
+            Header::new(FrameType::Parity, 0xdead_beef, 1 << 40, 3),
+            b"abc",
+        );
+        let h = parse_header(&b).unwrap();
+        assert_eq!(h.version, PROTOCOL_VERSION);
+        assert_eq!(h.frame_type, FrameType::Parity);
+        assert_eq!(h.session, 0xdead_beef);
+        assert_eq!(h.seq, 1 << 40);
+        assert_eq!(h.payload(&b), b"abc");
+    }
+
+    #[test]
+    fn padding_after_payload() {
+        // QRコードの容量に合わせて0で埋めたフレーム
+        let mut b = vec![0; HEADER_SIZE + 10];
+        b[HEADER_SIZE..HEADER_SIZE + 3].copy_from_slice(b"abc");
+        build_header(Header::new(FrameType::Data, 1, 0, 3), &mut b);
+        let h = parse_header(&b).unwrap();
+        assert_eq!(h.payload(&b), b"abc");
+    }
+
+    #[test]
+    fn corrupted_frames() {
+        let mut b = frame(Header::new(FrameType::Data, 1, 2, 3), b"abc");
+        b[HEADER_SIZE] ^= 1;
+        assert!(matches!(parse_header(&b), Err(ParseError::Corrupted)));
+        assert!(matches!(parse_header(b"RF"), Err(ParseError::Foreign)));
+        assert!(matches!(
+            parse_header(b"https://example.com/"),
+            Err(ParseError::Foreign)
+        ));
+        let mut b = frame(Header::new(FrameType::Data, 1, 2, 3), b"abc");
+        b[2] = 9;
+        assert!(matches!(parse_header(&b), Err(ParseError::Incompatible(9))));
+    }
+
+    #[test]
+    fn legacy_frames() {
+        // 待機中
+        let h = parse_header(&legacy_frame(100, 0, &[], 0)).unwrap();
+        assert_eq!((h.version, h.frame_type), (0, FrameType::Control));
+        let b = legacy_meta(100, 42, "a.txt");
+        let h = parse_header(&b).unwrap();
+        assert_eq!(h.frame_type, FrameType::Meta);
+        assert_eq!(h.size, 96);
+        // ブロックのseqは1から始まる
+        let b = legacy_frame(100, 5, b"data", 4);
+        let h = parse_header(&b).unwrap();
+        assert_eq!((h.frame_type, h.seq), (FrameType::Data, 4));
+        assert_eq!(h.payload(&b), b"data");
+        let h = parse_header(&legacy_frame(100, 7, &[], 0)).unwrap();
+        assert_eq!(h.frame_type, FrameType::Eof);
+        // "RF" で始まる旧形式のブロック
+        let payload = vec![9; 1618];
+        let b = legacy_frame(1622, 16 * 3 + 4, &payload, 1618);
+        assert_eq!(b[..2], MAGIC);
+        let h = parse_header(&b).unwrap();
+        assert_eq!((h.version, h.frame_type, h.seq), (0, FrameType::Data, 51));
+        assert_eq!(h.payload(&b), &payload[..]);
+        // サイズがフレームに収まらない
+        assert!(matches!(
+            parse_header(&legacy_frame(10, 1, &[], 7)),
+            Err(ParseError::Foreign)
+        ));
+    }
+
+    #[test]
+    fn metadata_roundtrip() {
+        let m = Metadata {
+            file_size: 12345,
+            mode: Mode::Loop as u8,
+            digest: [7; DIGEST_SIZE],
+            name: "ファイル.txt".as_bytes().to_vec(),
+            extensions: vec![(EXT_CODEC, vec![1, 2, 3]), (9, vec![])],
+        };
+        let mut b = vec![0; 100];
+        build_metadata(&m, &mut b);
+        let h = Header::new(FrameType::Meta, 1, 0, b.len() as u16);
+        let parsed = parse_metadata(&h, &b).unwrap();
+        assert_eq!(parsed.file_size, 12345);
+        assert_eq!(parsed.mode, Mode::Loop as u8);
+        assert_eq!(parsed.digest, [7; DIGEST_SIZE]);
+        assert_eq!(parsed.name, m.name);
+        assert_eq!(parsed.extension(EXT_CODEC), Some(&[1, 2, 3][..]));
+        assert_eq!(parsed.extension(9), Some(&[][..]));
+        assert_eq!(parsed.extension(EXT_CIPHER), None);
+    }
+
+    #[test]
+    fn long_name_is_truncated() {
+        let m = Metadata {
+            file_size: 1,
+            mode: 0,
+            digest: [0; DIGEST_SIZE],
+            name: vec![b'a'; 100],
+            extensions: vec![(EXT_CODEC, vec![0; 9])],
+        };
+        let mut b = vec![0; METADATA_MIN_SIZE + 20];
+        build_metadata(&m, &mut b);
+        let h = Header::new(FrameType::Meta, 1, 0, b.len() as u16);
+        let parsed = parse_metadata(&h, &b).unwrap();
+        assert_eq!(parsed.name.len(), 20 - 12);
+        assert_eq!(parsed.extension(EXT_CODEC), Some(&[0; 9][..]));
+    }
+
+    #[test]
+    fn legacy_metadata() {
+        let b = legacy_meta(100, 1 << 33, "ファイル.txt");
+        let h = parse_header(&b).unwrap();
+        let m = parse_metadata(&h, h.payload(&b)).unwrap();
+        assert_eq!(m.file_size, 1 << 33);
+        assert_eq!(m.name, "ファイル.txt".as_bytes());
+        assert_eq!(m.mode, Mode::Sequential as u8);
+        assert!(m.extensions.is_empty());
+    }
+}
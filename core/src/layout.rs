@@ -0,0 +1,147 @@
+// 1画面に並べるQRコードの配置
+//
+// 送信ページのcanvas、コマンドラインの画像・端末出力が同じ配置で描けるように、
+// QRコードをセル単位の色に変換する。
+
+use qrcode::{Color, EcLevel, QrCode};
+
+// 並べたQRコードの間隔[セル]
+pub const GRID_GAP: u32 = 4;
+
+// 1画面に並べたQRコードをセル単位で表したもの
+pub struct Screen {
+    pub width: u32,
+    pub height: u32,
+    // [Rが暗 | Gが暗 << 1 | Bが暗 << 2] を行優先で並べたもの。間隔は0(白)になる
+    pub cells: Vec<u8>,
+}
+
+// 1画面分のフレームをQRコードにしてグリッドに並べる。
+// グリッドの左上から順に並べ、channelsが3の場合は3フレームずつR, G, Bに割り当てる
+pub fn layout(
+    tiles: &[Vec<u8>],
+    ec_level: EcLevel,
+    grid_rows: u8,
+    grid_cols: u8,
+    channels: usize,
+) -> Option<Screen> {
+    let mut codes = Vec::with_capacity(tiles.len());
+    for tile in tiles {
+        let code = QrCode::with_error_correction_level(tile, ec_level).ok()?;
+        codes.push(code.to_colors());
+    }
+    // 全フレームが同じ長さなので、QRコードの大きさも同じになる
+    let size = (codes.first()?.len() as f64).sqrt() as u32;
+    let pitch = size + GRID_GAP;
+    let width = pitch * grid_cols as u32 - GRID_GAP;
+    let height = pitch * grid_rows as u32 - GRID_GAP;
+    let mut cells = vec![0u8; (width * height) as usize];
+    for (i, group) in codes.chunks(channels).enumerate() {
+        let ox = (i as u32 % grid_cols as u32) * pitch;
+        let oy = (i as u32 / grid_cols as u32) * pitch;
+        for y in 0..size {
+            for x in 0..size {
+                let j = (y * size + x) as usize;
+                // モノクロの場合は全チャンネルに同じQRコードを描く
+                let mask = (0..3).fold(0, |m, c| {
+                    let dark = group[c % group.len()][j] == Color::Dark;
+                    m | ((dark as u8) << c)
+                });
+                cells[((oy + y) * width + ox + x) as usize] = mask;
+            }
+        }
+    }
+    Some(Screen {
+        width,
+        height,
+        cells,
+    })
+}
+
+// セルの値のRGB
+pub fn cell_color(mask: u8) -> [u8; 3] {
+    let level = |c: u8| if mask & (1 << c) != 0 { 0 } else { 255 };
+    [level(0), level(1), level(2)]
+}
+
+// 送信ページではページの余白が白になるので、画像にする場合は周囲に余白[セル]を付ける
+pub const QUIET_ZONE: u32 = 4;
+
+// セルを拡大し、周囲に余白を付けたパレット番号 (セルの値そのもの) の画像にする
+pub fn rasterize(screen: &Screen, pixel_size: u32) -> (u32, u32, Vec<u8>) {
+    let w = (screen.width + QUIET_ZONE * 2) * pixel_size;
+    let h = (screen.height + QUIET_ZONE * 2) * pixel_size;
+    let mut pixels = vec![0u8; (w * h) as usize];
+    for (y, row) in screen.cells.chunks(screen.width as usize).enumerate() {
+        for py in 0..pixel_size {
+            let top = (y as u32 + QUIET_ZONE) * pixel_size + py;
+            let offset = (top * w + QUIET_ZONE * pixel_size) as usize;
+            for (x, &cell) in row.iter().enumerate() {
+                let start = offset + x * pixel_size as usize;
+                pixels[start..start + pixel_size as usize].fill(cell);
+            }
+        }
+    }
+    (w, h, pixels)
+}
+
+// パレット番号の画像を表示される色 (RGBA) にする
+pub fn to_rgba(pixels: &[u8]) -> Vec<u8> {
+    pixels
+        .iter()
+        .flat_map(|&p| {
+            let [r, g, b] = cell_color(p);
+            [r, g, b, 255]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid_layout() {
+        let tiles = vec![vec![1u8; 10]; 3];
+        let single = layout(&tiles[..1], EcLevel::L, 1, 1, 1).unwrap();
+        let size = single.width;
+        assert_eq!(single.height, size);
+        // モノクロのセルは全チャンネルが同じ
+        assert!(single.cells.iter().all(|&c| c == 0 || c == 7));
+
+        let grid = layout(&tiles, EcLevel::L, 2, 2, 1).unwrap();
+        assert_eq!(grid.width, size * 2 + GRID_GAP);
+        assert_eq!(grid.height, size * 2 + GRID_GAP);
+        // 右下は空いたまま
+        let corner = (grid.height - 1) * grid.width + grid.width - 1;
+        assert_eq!(grid.cells[corner as usize], 0);
+    }
+
+    #[test]
+    fn color_channels() {
+        let tiles = vec![b"r".to_vec(), b"g".to_vec(), b"b".to_vec()];
+        let screen = layout(&tiles, EcLevel::L, 1, 1, 3).unwrap();
+        let codes: Vec<Vec<Color>> = tiles
+            .iter()
+            .map(|t| {
+                QrCode::with_error_correction_level(t, EcLevel::L)
+                    .unwrap()
+                    .to_colors()
+            })
+            .collect();
+        for (i, &mask) in screen.cells.iter().enumerate() {
+            for (c, code) in codes.iter().enumerate() {
+                assert_eq!(mask & (1 << c) != 0, code[i] == Color::Dark);
+            }
+        }
+        assert!(layout(&[], EcLevel::L, 1, 1, 1).is_none());
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(cell_color(0), [255, 255, 255]);
+        assert_eq!(cell_color(7), [0, 0, 0]);
+        assert_eq!(cell_color(1), [0, 255, 255]);
+        assert_eq!(cell_color(4), [255, 255, 0]);
+    }
+}
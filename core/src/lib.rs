@@ -0,0 +1,23 @@
+// QRコードでファイルを転送するプロトコルの実装
+//
+// ブラウザやファイルの読み書きに依存しないので、受信・送信ページとコマンドラインの両方から使う。
+// Encoderがファイルをフレームのペイロードに変換し、Decoderが読み取ったペイロードからファイルを復元する。
+// キャプチャした画像からのQRコードの読み取り(detect)は、受信ページとWeb Workerの両方から使う。
+
+pub mod blockmap;
+pub mod calibration;
+pub mod capacity;
+pub mod codec;
+pub mod crypto;
+pub mod decoder;
+pub mod detect;
+pub mod encoder;
+pub mod fountain;
+pub mod header;
+pub mod layout;
+pub mod pacing;
+pub mod resend;
+pub mod stats;
+
+pub use decoder::{Decoder, Event, FinishError};
+pub use encoder::{Encoder, Preparer, Settings, Tile};
@@ -0,0 +1,174 @@
+// 画面の切り替えをディスプレイのリフレッシュに合わせる。
+// requestAnimationFrameの時刻からリフレッシュ間隔を推定し、各画面を送信間隔に最も近い整数回のリフレッシュの間表示する。
+// 描画中に画面が切り替わらないので、受信側が書き換え途中の画面をキャプチャしにくくなる
+
+use std::collections::VecDeque;
+
+// リフレッシュ間隔が分かるまでは60Hzとみなす [ms]
+const DEFAULT_REFRESH_MS: f64 = 1000.0 / 60.0;
+// リフレッシュ間隔の推定に使う直近の間隔の数
+const REFRESH_SAMPLES: usize = 31;
+// これより空いた間隔はタブが隠れていたなどでリフレッシュとみなさない [ms]
+const MAX_REFRESH_MS: f64 = 100.0;
+// 実際の表示時間の移動平均の重み
+const DISPLAYED_WEIGHT: f64 = 0.1;
+
+pub struct Pacer {
+    interval: u16,
+    last_tick: Option<f64>,
+    // 直近のコールバックの間隔 [ms]
+    deltas: VecDeque<f64>,
+    // 表示中の画面を表示し始めた時刻
+    shown_at: Option<f64>,
+    // 実際に表示した時間の移動平均 [ms]
+    displayed: Option<f64>,
+}
+
+impl Pacer {
+    pub fn new(interval: u16) -> Self {
+        Self {
+            interval,
+            last_tick: None,
+            deltas: VecDeque::with_capacity(REFRESH_SAMPLES),
+            shown_at: None,
+            displayed: None,
+        }
+    }
+
+    pub fn set_interval(&mut self, interval: u16) {
+        self.interval = interval;
+    }
+
+    // 一時停止などで止めた後は、次のコールバックから数え直す
+    pub fn restart(&mut self) {
+        self.last_tick = None;
+        self.shown_at = None;
+    }
+
+    // 推定したリフレッシュ間隔 [ms]。取りこぼしたコールバックの影響を受けないように中央値を使う
+    pub fn refresh_ms(&self) -> f64 {
+        if self.deltas.len() < 5 {
+            return DEFAULT_REFRESH_MS;
+        }
+        let mut sorted: Vec<f64> = self.deltas.iter().copied().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        sorted[sorted.len() / 2]
+    }
+
+    // 1画面を表示するリフレッシュの回数
+    pub fn hold(&self) -> u32 {
+        ((self.interval as f64 / self.refresh_ms()).round() as u32).max(1)
+    }
+
+    // リフレッシュに合わせた実際の送信間隔 [ms]
+    pub fn effective_interval(&self) -> f64 {
+        self.hold() as f64 * self.refresh_ms()
+    }
+
+    // 画面を実際に表示した時間の平均 [ms]
+    pub fn displayed_ms(&self) -> Option<f64> {
+        self.displayed
+    }
+
+    // requestAnimationFrameのコールバックごとに呼ぶ。次の画面に切り替える場合はtrueを返す
+    pub fn tick(&mut self, now: f64) -> bool {
+        if let Some(last) = self.last_tick {
+            let delta = now - last;
+            if delta > 0.0 && delta < MAX_REFRESH_MS {
+                if self.deltas.len() == REFRESH_SAMPLES {
+                    self.deltas.pop_front();
+                }
+                self.deltas.push_back(delta);
+            }
+        }
+        self.last_tick = Some(now);
+        let shown_at = match self.shown_at {
+            Some(t) => t,
+            // 表示中の画面はこのリフレッシュから数える
+            None => {
+                self.shown_at = Some(now);
+                return false;
+            }
+        };
+        // コールバックを取りこぼしても、経過時間からリフレッシュの回数を数える
+        let elapsed = now - shown_at;
+        if (elapsed / self.refresh_ms()).round() < self.hold() as f64 {
+            return false;
+        }
+        self.displayed = Some(match self.displayed {
+            Some(d) => d + (elapsed - d) * DISPLAYED_WEIGHT,
+            None => elapsed,
+        });
+        self.shown_at = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // refresh[ms]ごとにコールバックを呼び、切り替えた時刻を返す
+    fn run(
+        pacer: &mut Pacer,
+        refresh: f64,
+        ticks: usize,
+        skip: impl Fn(usize) -> bool,
+    ) -> Vec<f64> {
+        (0..ticks)
+            .filter(|&i| !skip(i))
+            .map(|i| i as f64 * refresh)
+            .filter(|&t| pacer.tick(t))
+            .collect()
+    }
+
+    #[test]
+    fn holds_whole_refreshes() {
+        // 144Hzで100msは14回分
+        let mut pacer = Pacer::new(100);
+        let refresh = 1000.0 / 144.0;
+        let switches = run(&mut pacer, refresh, 300, |_| false);
+        assert_eq!(pacer.hold(), 14);
+        assert!((pacer.refresh_ms() - refresh).abs() < 1e-9);
+        let last: Vec<f64> = switches.windows(2).map(|w| w[1] - w[0]).skip(2).collect();
+        assert!(last.iter().all(|d| (d - 14.0 * refresh).abs() < 1e-6));
+        assert!((pacer.displayed_ms().unwrap() - 14.0 * refresh).abs() < 1.0);
+        assert!((pacer.effective_interval() - 14.0 * refresh).abs() < 1e-6);
+    }
+
+    #[test]
+    fn short_interval_shows_every_refresh() {
+        let mut pacer = Pacer::new(5);
+        let switches = run(&mut pacer, 1000.0 / 60.0, 20, |_| false);
+        assert_eq!(pacer.hold(), 1);
+        assert_eq!(switches.len(), 19);
+    }
+
+    #[test]
+    fn missed_callbacks_do_not_stretch_frames() {
+        // 60Hzで50msは3回分。コールバックを時々取りこぼしても3回分で切り替える
+        let mut pacer = Pacer::new(50);
+        let refresh = 1000.0 / 60.0;
+        let switches = run(&mut pacer, refresh, 200, |i| i % 7 == 3);
+        assert_eq!(pacer.hold(), 3);
+        let long = switches
+            .windows(2)
+            .filter(|w| w[1] - w[0] > 3.5 * refresh)
+            .count();
+        // 切り替えるはずのリフレッシュを取りこぼした場合だけ1回分延びる
+        assert!(long <= 200 / 7);
+        assert!(switches.len() >= 55);
+    }
+
+    #[test]
+    fn restart_counts_from_next_callback() {
+        let mut pacer = Pacer::new(100);
+        run(&mut pacer, 10.0, 50, |_| false);
+        pacer.restart();
+        // 長く止めていても、再開後すぐには切り替えない
+        assert!(!pacer.tick(10_000.0));
+        assert!(!pacer.tick(10_050.0));
+        assert!(pacer.tick(10_100.0));
+        assert_eq!(pacer.hold(), 10);
+    }
+}
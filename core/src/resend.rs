@@ -0,0 +1,193 @@
+// 受信側で欠落したブロックを、送信側に手入力で伝えるための再送コード
+//
+// セッションIDの下位16bit(2B) + 受信済み/欠落の連長(LEB128)を交互に並べたもの + CRC(2B)
+// を Crockford の Base32 で表現し、4文字ごとにハイフンで区切る。
+// 連長は受信済みから始まり、最後の欠落以降は全て受信済みとみなす。
+
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+#[derive(Debug)]
+pub enum CodeError {
+    // 使えない文字が含まれている
+    InvalidChar(char),
+    // 長さやチェックサムが合わない
+    Checksum,
+}
+
+pub fn encode(session: u32, missing: impl Iterator<Item = u64>) -> String {
+    let mut bytes = (session as u16).to_le_bytes().to_vec();
+    let mut pos = 0;
+    let mut run: Option<(u64, u64)> = None;
+    for x in missing {
+        match run {
+            Some((start, end)) if end == x => run = Some((start, x + 1)),
+            Some((start, end)) => {
+                write_varint(&mut bytes, start - pos);
+                write_varint(&mut bytes, end - start);
+                pos = end;
+                run = Some((x, x + 1));
+            }
+            None => run = Some((x, x + 1)),
+        }
+    }
+    if let Some((start, end)) = run {
+        write_varint(&mut bytes, start - pos);
+        write_varint(&mut bytes, end - start);
+    }
+    encode_bytes(bytes)
+}
+
+// セッションIDの下位16bitと、欠落しているブロック番号の範囲 [start, end) の一覧を返す
+pub fn decode(code: &str) -> Result<(u16, Vec<(u64, u64)>), CodeError> {
+    let body = decode_bytes(code)?;
+    if body.len() < 2 {
+        return Err(CodeError::Checksum);
+    }
+    let session = u16::from_le_bytes([body[0], body[1]]);
+    let mut ranges = Vec::new();
+    let mut rest = &body[2..];
+    let mut pos: u64 = 0;
+    while !rest.is_empty() {
+        let present = read_varint(&mut rest).ok_or(CodeError::Checksum)?;
+        let missing = read_varint(&mut rest).ok_or(CodeError::Checksum)?;
+        let start = pos.checked_add(present).ok_or(CodeError::Checksum)?;
+        pos = start.checked_add(missing).ok_or(CodeError::Checksum)?;
+        ranges.push((start, pos));
+    }
+    Ok((session, ranges))
+}
+
+// CRC(2B)を付けてBase32で表現し、4文字ごとにハイフンで区切る。
+// キャリブレーションの設定コードも同じ表現を使う
+pub(crate) fn encode_bytes(mut bytes: Vec<u8>) -> String {
+    let crc = crc32fast::hash(&bytes) as u16;
+    bytes.extend_from_slice(&crc.to_le_bytes());
+
+    let chars = to_base32(&bytes);
+    chars
+        .chunks(4)
+        .map(|c| c.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// encode_bytesの逆。小文字や区切りの空白、紛らわしい文字 (O, I, L) も受け付ける
+pub(crate) fn decode_bytes(code: &str) -> Result<Vec<u8>, CodeError> {
+    let mut values = Vec::with_capacity(code.len());
+    for c in code.chars() {
+        let v = match c.to_ascii_uppercase() {
+            '-' | ' ' => continue,
+            'O' => 0,
+            'I' | 'L' => 1,
+            c => match ALPHABET.iter().position(|&x| x as char == c) {
+                Some(v) => v as u8,
+                None => return Err(CodeError::InvalidChar(c)),
+            },
+        };
+        values.push(v);
+    }
+    let mut bytes = from_base32(&values);
+    if bytes.len() < 2 {
+        return Err(CodeError::Checksum);
+    }
+    let crc = bytes.split_off(bytes.len() - 2);
+    if (crc32fast::hash(&bytes) as u16).to_le_bytes()[..] != crc[..] {
+        return Err(CodeError::Checksum);
+    }
+    Ok(bytes)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(b: &mut &[u8]) -> Option<u64> {
+    let mut v: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let (&x, rest) = b.split_first()?;
+        *b = rest;
+        v |= ((x & 0x7f) as u64) << shift;
+        if x & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+fn to_base32(bytes: &[u8]) -> Vec<char> {
+    let mut out = Vec::with_capacity((bytes.len() * 8).div_ceil(5));
+    let (mut acc, mut bits) = (0u32, 0);
+    for &b in bytes {
+        acc = (acc << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((acc >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((acc << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn from_base32(values: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(values.len() * 5 / 8);
+    let (mut acc, mut bits) = (0u32, 0);
+    for &v in values {
+        acc = (acc << 5) | v as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let code = encode(0x1234_5678, [0, 1, 2, 10, 300, 301].into_iter());
+        assert!(code.split('-').all(|g| g.len() <= 4));
+        let (session, ranges) = decode(&code).unwrap();
+        assert_eq!(session, 0x5678);
+        assert_eq!(ranges, vec![(0, 3), (10, 11), (300, 302)]);
+    }
+
+    #[test]
+    fn nothing_missing() {
+        let (session, ranges) = decode(&encode(1, std::iter::empty())).unwrap();
+        assert_eq!(session, 1);
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn lenient_input() {
+        let code = encode(7, [5, 6].into_iter());
+        // 小文字や空白区切り、紛らわしい文字の読み替えを受け付ける
+        let typed = code
+            .to_lowercase()
+            .replace('-', " ")
+            .replace('0', "o")
+            .replace('1', "l");
+        assert_eq!(decode(&typed).unwrap(), (7, vec![(5, 7)]));
+    }
+
+    #[test]
+    fn invalid_codes() {
+        assert!(matches!(decode("ABCD-U"), Err(CodeError::InvalidChar('U'))));
+        assert!(matches!(decode(""), Err(CodeError::Checksum)));
+        let mut code: Vec<char> = encode(7, [5, 6].into_iter()).chars().collect();
+        code[0] = if code[0] == '2' { '3' } else { '2' };
+        let code: String = code.into_iter().collect();
+        assert!(matches!(decode(&code), Err(CodeError::Checksum)));
+    }
+}
@@ -0,0 +1,234 @@
+// 受信の統計。読み取りの試行・成功・重複と新たに受信したバイト数を1秒ごとに集計し、
+// 速度と残り時間を求める。時刻はミリ秒で渡すので、ブラウザ以外からも使える
+
+use std::collections::VecDeque;
+
+// 集計する区間の長さ [ms]
+pub const BUCKET_MS: f64 = 1000.0;
+// 保持する区間の数。受信ページのグラフの横幅になる
+pub const HISTORY_LEN: usize = 60;
+// 速度は直近のこの区間数から求める
+pub const RATE_BUCKETS: usize = 5;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counts {
+    // 画面を読み取った回数
+    pub scans: u64,
+    // 読み取れたQRコードの数
+    pub decoded: u64,
+    // 読み取れたが、既に受信したフレームだったもの
+    pub duplicates: u64,
+    // 新しいブロックやシンボルを含んでいたフレームの数
+    pub fresh: u64,
+    // 新しいフレームで受信したバイト数
+    pub bytes: u64,
+}
+
+// 1秒あたりの値
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rates {
+    pub scans: f64,
+    pub decoded: f64,
+    pub duplicates: f64,
+    pub fresh: f64,
+    pub bytes: f64,
+}
+
+pub struct Stats {
+    start: f64,
+    // 古い順に区間ごとの集計。最後が現在の区間
+    buckets: VecDeque<Counts>,
+    // 現在の区間の番号
+    current: u64,
+    pub total: Counts,
+    last_fresh: Option<f64>,
+}
+
+impl Stats {
+    pub fn new(now: f64) -> Self {
+        let mut buckets = VecDeque::with_capacity(HISTORY_LEN + 1);
+        buckets.push_back(Counts::default());
+        Self {
+            start: now,
+            buckets,
+            current: 0,
+            total: Counts::default(),
+            last_fresh: None,
+        }
+    }
+
+    // 現在の区間をnowまで進める。新しい区間に入ったらtrueを返す
+    pub fn tick(&mut self, now: f64) -> bool {
+        let index = ((now - self.start).max(0.0) / BUCKET_MS) as u64;
+        if index <= self.current {
+            return false;
+        }
+        let gap = (index - self.current).min(HISTORY_LEN as u64 + 1);
+        for _ in 0..gap {
+            self.buckets.push_back(Counts::default());
+        }
+        while self.buckets.len() > HISTORY_LEN + 1 {
+            self.buckets.pop_front();
+        }
+        self.current = index;
+        true
+    }
+
+    // 画面を1回読み取った。新しい区間に入ったらtrueを返す
+    pub fn scan(&mut self, now: f64) -> bool {
+        let next = self.tick(now);
+        self.bucket().scans += 1;
+        self.total.scans += 1;
+        next
+    }
+
+    // QRコードを1つ読み取った。新しいデータを含んでいた場合はそのバイト数を渡す
+    pub fn decoded(&mut self, now: f64, fresh: Option<u64>) {
+        self.tick(now);
+        let mut counts = Counts {
+            decoded: 1,
+            ..Counts::default()
+        };
+        match fresh {
+            Some(bytes) => {
+                counts.fresh = 1;
+                counts.bytes = bytes;
+                self.last_fresh = Some(now);
+            }
+            None => counts.duplicates = 1,
+        }
+        add(self.bucket(), &counts);
+        add(&mut self.total, &counts);
+    }
+
+    fn bucket(&mut self) -> &mut Counts {
+        self.buckets.back_mut().unwrap()
+    }
+
+    // 直近の区間の1秒あたりの値
+    pub fn rates(&self, now: f64) -> Rates {
+        let n = self.buckets.len().min(RATE_BUCKETS);
+        let mut sum = Counts::default();
+        for c in self.buckets.iter().rev().take(n) {
+            add(&mut sum, c);
+        }
+        let from = self.start + (self.current + 1 - n as u64) as f64 * BUCKET_MS;
+        // 開始直後に値が跳ね上がらないように、少なくとも1区間分で割る
+        let seconds = (now - from).max(BUCKET_MS) / 1000.0;
+        Rates {
+            scans: sum.scans as f64 / seconds,
+            decoded: sum.decoded as f64 / seconds,
+            duplicates: sum.duplicates as f64 / seconds,
+            fresh: sum.fresh as f64 / seconds,
+            bytes: sum.bytes as f64 / seconds,
+        }
+    }
+
+    // 残りのフレーム数を受信し終えるまでの秒数。受信が止まっている場合はNone
+    pub fn eta(&self, now: f64, remaining: u64) -> Option<f64> {
+        let fresh = self.rates(now).fresh;
+        if fresh > 0.0 {
+            Some(remaining as f64 / fresh)
+        } else {
+            None
+        }
+    }
+
+    // 最後に新しいフレームを受信してからの秒数
+    pub fn since_fresh(&self, now: f64) -> Option<f64> {
+        self.last_fresh.map(|t| (now - t).max(0.0) / 1000.0)
+    }
+
+    pub fn elapsed(&self, now: f64) -> f64 {
+        (now - self.start).max(0.0) / 1000.0
+    }
+
+    // 新しいフレーム1つあたりの読み取り回数。送信間隔を決める目安になる
+    pub fn reads_per_frame(&self) -> Option<f64> {
+        if self.total.fresh == 0 {
+            return None;
+        }
+        Some(self.total.decoded as f64 / self.total.fresh as f64)
+    }
+
+    // 集計を終えた区間を古い順に返す
+    pub fn history(&self) -> impl Iterator<Item = &Counts> {
+        self.buckets.iter().take(self.buckets.len() - 1)
+    }
+}
+
+fn add(sum: &mut Counts, c: &Counts) {
+    sum.scans += c.scans;
+    sum.decoded += c.decoded;
+    sum.duplicates += c.duplicates;
+    sum.fresh += c.fresh;
+    sum.bytes += c.bytes;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_per_bucket() {
+        let mut stats = Stats::new(10_000.0);
+        assert!(!stats.scan(10_000.0));
+        stats.decoded(10_100.0, Some(100));
+        stats.decoded(10_200.0, None);
+        assert!(stats.scan(11_000.0));
+        stats.decoded(11_500.0, Some(50));
+        assert_eq!(stats.history().count(), 1);
+        assert_eq!(
+            *stats.history().next().unwrap(),
+            Counts {
+                scans: 1,
+                decoded: 2,
+                duplicates: 1,
+                fresh: 1,
+                bytes: 100,
+            }
+        );
+        assert_eq!(stats.total.decoded, 3);
+        assert_eq!(stats.total.bytes, 150);
+        assert_eq!(stats.reads_per_frame(), Some(1.5));
+        assert_eq!(stats.since_fresh(12_000.0), Some(0.5));
+    }
+
+    #[test]
+    fn rates_use_recent_buckets() {
+        let mut stats = Stats::new(0.0);
+        // 最初の1秒は4回読み取り、その後は1秒に2回ずつ
+        for i in 0..4 {
+            stats.scan(i as f64 * 250.0);
+        }
+        for i in 0..20 {
+            let now = 1000.0 + i as f64 * 500.0;
+            stats.scan(now);
+            stats.decoded(now, Some(10));
+        }
+        // 10.5秒時点で直近5区間 (6〜10.5秒) を見る
+        let rates = stats.rates(10_500.0);
+        assert!((rates.scans - 10.0 / 4.5).abs() < 1e-9);
+        assert!((rates.bytes - 100.0 / 4.5).abs() < 1e-9);
+        let eta = stats.eta(10_500.0, 9).unwrap();
+        assert!((eta - 9.0 / (10.0 / 4.5)).abs() < 1e-9);
+        // 開始直後は1秒分で割る
+        let stats = Stats::new(0.0);
+        assert_eq!(stats.rates(10.0), Rates::default());
+        assert_eq!(stats.eta(10.0, 5), None);
+    }
+
+    #[test]
+    fn gaps_are_empty_buckets() {
+        let mut stats = Stats::new(0.0);
+        stats.decoded(0.0, Some(1));
+        stats.scan(3500.0);
+        let history: Vec<_> = stats.history().map(|c| c.fresh).collect();
+        assert_eq!(history, vec![1, 0, 0]);
+        // 長く止まっていた場合も保持する区間数を超えない
+        stats.scan(1_000_000.0);
+        assert_eq!(stats.history().count(), HISTORY_LEN);
+        assert!(stats.history().all(|c| *c == Counts::default()));
+        assert_eq!(stats.rates(1_000_000.0).fresh, 0.0);
+    }
+}
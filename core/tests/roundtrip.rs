@@ -0,0 +1,223 @@
+// Encoderで構築したフレームを、取りこぼしや順序の入れ替えを加えてDecoderに渡し、
+// 元のファイルが復元できることを確かめる
+
+use proptest::prelude::*;
+use qrcode::EcLevel;
+
+use rds_filetransfer_core::capacity::{block_size_of, usable_version};
+use rds_filetransfer_core::header::{
+    build_header, parse_header, FrameType, Header, Mode, HEADER_SIZE,
+};
+use rds_filetransfer_core::{resend, Decoder, Encoder, Event, Settings};
+
+fn ec_level() -> impl Strategy<Value = EcLevel> {
+    prop_oneof![
+        Just(EcLevel::L),
+        Just(EcLevel::M),
+        Just(EcLevel::Q),
+        Just(EcLevel::H)
+    ]
+}
+
+// 小さいバージョンほどブロック数が増えるので、大きいバージョンは時々だけ選ぶ
+fn settings(mode: Mode) -> impl Strategy<Value = Settings> {
+    (
+        prop_oneof![4 => 1i16..=12, 1 => 13i16..=40],
+        ec_level(),
+        2u16..=30,
+        any::<bool>(),
+    )
+        .prop_map(move |(version, ec, meta_interval, compression)| {
+            let version = usable_version(version, ec).unwrap();
+            Settings {
+                block_size: block_size_of(version, ec),
+                mode,
+                meta_interval,
+                compression,
+            }
+        })
+}
+
+// 圧縮が効く場合と効かない場合の両方を含める
+fn file() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        prop::collection::vec(any::<u8>(), 0..6000),
+        (1usize..4, 0usize..20000)
+            .prop_map(|(n, len)| (0..len).map(|i| (i / n % 5) as u8).collect()),
+    ]
+}
+
+fn frames_until_eof(encoder: &mut Encoder) -> Vec<Vec<u8>> {
+    let mut frames = vec![encoder.meta_frame().to_vec()];
+    loop {
+        let (tiles, keep_going) = encoder.next_tiles(1);
+        frames.extend(tiles);
+        if !keep_going {
+            return frames;
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // 逐次送信で取りこぼしたブロックは、再送コードで再送すれば揃う
+    #[test]
+    fn sequential_with_resend(
+        data in file(),
+        settings in settings(Mode::Sequential),
+        session in any::<u32>(),
+        lost in prop::collection::vec(any::<bool>(), 0..64),
+    ) {
+        let mut encoder = Encoder::new(b"file", &data, &settings, "", session);
+        let mut decoder = Decoder::new();
+        decoder.push(encoder.meta_frame()).unwrap();
+        for (i, frame) in frames_until_eof(&mut encoder).iter().enumerate().skip(1) {
+            if !lost.get(i).copied().unwrap_or(false) {
+                decoder.push(frame).unwrap();
+            }
+        }
+        if !decoder.is_complete() {
+            let code = resend::encode(session, decoder.missing().into_iter());
+            let (s, ranges) = resend::decode(&code).unwrap();
+            prop_assert_eq!(s, session as u16);
+            encoder.queue_resend(&ranges);
+            for frame in frames_until_eof(&mut encoder) {
+                decoder.push(&frame).unwrap();
+            }
+        }
+        prop_assert!(decoder.is_complete());
+        prop_assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    // フレームの順序が入れ替わっても、メタデータより先に届いたフレームを含めて復元できる
+    #[test]
+    fn shuffled_frames(
+        data in file(),
+        settings in settings(Mode::Sequential),
+        seed in any::<u64>(),
+    ) {
+        let mut encoder = Encoder::new(b"file", &data, &settings, "", 1);
+        let mut frames = frames_until_eof(&mut encoder);
+        let mut rng = seed | 1;
+        for i in (1..frames.len()).rev() {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            frames.swap(i, (rng % (i as u64 + 1)) as usize);
+        }
+        let mut decoder = Decoder::new();
+        let mut blocks = 0;
+        for frame in &frames {
+            let events = decoder.push(frame).unwrap();
+            blocks += events.iter().filter(|e| matches!(e, Event::Block(_))).count();
+        }
+        prop_assert_eq!(blocks as u64, encoder.num_blocks());
+        prop_assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    // 繰り返し送信は途中から受信を始めても、取りこぼしがあっても揃う
+    #[test]
+    fn loop_with_loss(
+        data in file(),
+        settings in settings(Mode::Loop),
+        skip in 0usize..100,
+        loss in 0u32..50,
+        seed in any::<u32>(),
+    ) {
+        let mut encoder = Encoder::new(b"file", &data, &settings, "", 1);
+        let mut decoder = Decoder::new();
+        let mut rng = seed | 1;
+        // メタデータはmeta_interval回に1回しか送らないので、その分も待つ
+        let max_frames =
+            (encoder.num_blocks() as usize + 1 + settings.meta_interval as usize) * 40 + skip;
+        for i in 0..max_frames {
+            let (tiles, _) = encoder.next_tiles(1);
+            rng ^= rng << 13;
+            rng ^= rng >> 17;
+            rng ^= rng << 5;
+            if i >= skip && rng % 100 >= loss {
+                decoder.push(&tiles[0]).unwrap();
+            }
+            if decoder.is_complete() {
+                break;
+            }
+        }
+        prop_assert!(decoder.is_complete());
+        prop_assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    // ファウンテン符号はどのシンボルを取りこぼしても、十分な数を受信すれば揃う
+    #[test]
+    fn fountain_with_loss(
+        data in file(),
+        settings in settings(Mode::Fountain),
+        loss in 0u32..50,
+        seed in any::<u32>(),
+    ) {
+        let mut encoder = Encoder::new(b"file", &data, &settings, "", 1);
+        let mut decoder = Decoder::new();
+        decoder.push(encoder.meta_frame()).unwrap();
+        let mut rng = seed | 1;
+        let max_frames = (encoder.num_blocks() as usize + 1) * 20;
+        for _ in 0..max_frames {
+            if decoder.is_complete() {
+                break;
+            }
+            let (tiles, _) = encoder.next_tiles(1);
+            rng ^= rng << 13;
+            rng ^= rng >> 17;
+            rng ^= rng << 5;
+            if rng % 100 >= loss {
+                decoder.push(&tiles[0]).unwrap();
+            }
+        }
+        prop_assert!(decoder.is_complete());
+        prop_assert_eq!(decoder.finish(None).unwrap(), data);
+    }
+
+    // 欠落ブロックの集合は再送コードを経由しても変わらない
+    #[test]
+    fn resend_code_roundtrip(
+        session in any::<u32>(),
+        missing in prop::collection::btree_set(0u64..1_000_000, 0..50),
+    ) {
+        let code = resend::encode(session, missing.iter().copied());
+        let (s, ranges) = resend::decode(&code).unwrap();
+        prop_assert_eq!(s, session as u16);
+        let decoded: Vec<u64> = ranges.into_iter().flat_map(|(a, b)| a..b).collect();
+        prop_assert_eq!(decoded, missing.into_iter().collect::<Vec<_>>());
+    }
+
+    // ヘッダは1ビットの反転でも壊れていると判定する
+    #[test]
+    fn header_detects_bit_flips(
+        payload in prop::collection::vec(any::<u8>(), 0..200),
+        session in any::<u32>(),
+        seq in any::<u64>(),
+        bit in any::<prop::sample::Index>(),
+    ) {
+        let mut frame = vec![0; HEADER_SIZE + payload.len()];
+        frame[HEADER_SIZE..].copy_from_slice(&payload);
+        build_header(
+            Header::new(FrameType::Data, session, seq, payload.len() as u16),
+            &mut frame,
+        );
+        let h = parse_header(&frame).unwrap();
+        prop_assert_eq!((h.session, h.seq), (session, seq));
+        prop_assert_eq!(h.payload(&frame), &payload[..]);
+        let i = bit.index(frame.len() * 8);
+        frame[i / 8] ^= 1 << (i % 8);
+        prop_assert!(parse_header(&frame).is_err());
+    }
+
+    // どんなペイロードを渡してもパニックしない
+    #[test]
+    fn arbitrary_payloads(payloads in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..300), 0..20)) {
+        let mut decoder = Decoder::new();
+        for payload in &payloads {
+            let _ = decoder.push(payload);
+        }
+        let _ = decoder.finish(None);
+    }
+}
@@ -0,0 +1,52 @@
+// QRコードの読み取りを行うWeb Worker
+//
+// 受信: [幅, 高さ, RGBAのArrayBuffer(転送), 切り出した位置x, y, 二値化の方法, ぼかすか]
+// 返信: [カラー多重化か, 読み取れたペイロード(Uint8Array)の配列, エラーメッセージの配列,
+//        読み取れたQRコードを囲む矩形[x, y, 幅, 高さ] (読み取れなかった場合はnull)]
+
+use js_sys::{Array, ArrayBuffer, Uint8Array};
+use quircs::Quirc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{DedicatedWorkerGlobalScope, MessageEvent};
+
+use rds_filetransfer_core::detect;
+
+fn main() {
+    let scope = js_sys::global().unchecked_into::<DedicatedWorkerGlobalScope>();
+    let scope2 = scope.clone();
+    let mut decoder = Quirc::default();
+    let onmessage = Closure::wrap(Box::new(move |e: MessageEvent| {
+        let req = e.data().unchecked_into::<Array>();
+        let w = req.get(0).as_f64().unwrap_or(0.0) as usize;
+        let h = req.get(1).as_f64().unwrap_or(0.0) as usize;
+        let rgba = Uint8Array::new(&req.get(2).unchecked_into::<ArrayBuffer>()).to_vec();
+        let opts = detect::Options {
+            binarize: req
+                .get(5)
+                .as_f64()
+                .and_then(|v| detect::Binarize::from_u8(v as u8))
+                .unwrap_or(detect::Binarize::None),
+            smooth: req.get(6).as_bool().unwrap_or(false),
+        };
+        let mut detected = detect::detect(&mut decoder, w, h, &rgba, opts);
+        detected.translate(
+            req.get(3).as_f64().unwrap_or(0.0) as u32,
+            req.get(4).as_f64().unwrap_or(0.0) as u32,
+        );
+        let payloads: Array = detected
+            .payloads
+            .iter()
+            .map(|p| JsValue::from(Uint8Array::from(&p[..])))
+            .collect();
+        let errors: Array = detected.errors.iter().map(JsValue::from).collect();
+        let bounds = match detected.bounds {
+            Some(b) => Array::of4(&b.x.into(), &b.y.into(), &b.w.into(), &b.h.into()).into(),
+            None => JsValue::NULL,
+        };
+        let res = Array::of4(&JsValue::from(detected.color), &payloads, &errors, &bounds);
+        scope2.post_message(&res).unwrap();
+    }) as Box<dyn FnMut(MessageEvent)>);
+    scope.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+    onmessage.forget();
+}
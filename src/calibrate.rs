@@ -0,0 +1,284 @@
+use yew::prelude::*;
+use yew::services::render::{RenderService, RenderTask};
+
+use rds_filetransfer_core::calibration::{build_probe, steps, Calibration, Probe, Step, Tally};
+use rds_filetransfer_core::layout;
+use rds_filetransfer_core::pacing::Pacer;
+
+use crate::send::{Painter, EC_LEVEL_TABLE};
+
+// リモート側でテストパターンを表示するページ。
+// ローカル側の受信ページで読み取ると、手順ごとの結果と送信ページに入力する設定コードが表示される
+pub struct CalibratePage {
+    link: ComponentLink<CalibratePage>,
+    canvas: NodeRef,
+    painter: Painter,
+    steps: Vec<Step>,
+    // 表示中のテストパターン
+    probe: Probe,
+    running: bool,
+    render_task: Option<RenderTask>,
+    // 送信ページと同じく、各手順の送信間隔をリフレッシュの整数倍に合わせる
+    pacer: Pacer,
+}
+
+pub enum Msg {
+    Start,
+    Stop,
+    Frame(f64),
+}
+
+impl CalibratePage {
+    fn render_probe(&self) -> Result<(), ()> {
+        let step = &self.steps[self.probe.step as usize];
+        let frame = build_probe(&self.probe, step);
+        let screen = layout::layout(&[frame], step.ec_level, 1, 1, 1).ok_or(())?;
+        self.painter.draw(&self.canvas, &screen, step.pixel_size)
+    }
+
+    // 手順の画面数を表示したら次の手順に、最後の手順の後は最初の手順に進む
+    fn advance(&mut self) {
+        let p = &mut self.probe;
+        p.index += 1;
+        if p.index >= self.steps[p.step as usize].frames {
+            p.index = 0;
+            p.step += 1;
+        }
+        if p.step as usize >= self.steps.len() {
+            p.step = 0;
+            p.cycle += 1;
+        }
+    }
+
+    // 次のリフレッシュで画面を切り替えるか判断する。送信間隔は表示中の手順のものを使う
+    fn request_frame(&mut self) {
+        self.pacer
+            .set_interval(self.steps[self.probe.step as usize].interval);
+        self.render_task = Some(RenderService::request_animation_frame(
+            self.link.callback(Msg::Frame),
+        ));
+    }
+
+    fn stop(&mut self) {
+        self.render_task = None;
+        self.running = false;
+    }
+}
+
+impl Component for CalibratePage {
+    type Message = Msg;
+    type Properties = ();
+
+    fn create(_props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        Self {
+            link,
+            canvas: NodeRef::default(),
+            painter: Painter::new(),
+            steps: steps(),
+            probe: Probe {
+                run: 0,
+                cycle: 0,
+                step: 0,
+                index: 0,
+            },
+            running: false,
+            render_task: None,
+            pacer: Pacer::new(0),
+        }
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        match msg {
+            Msg::Start => {
+                // 受信側が前回の表示と混ぜないように、表示ごとにIDを振る
+                self.probe = Probe {
+                    run: (js_sys::Math::random() * u32::MAX as f64) as u32 | 1,
+                    cycle: 0,
+                    step: 0,
+                    index: 0,
+                };
+                self.running = true;
+                self.render_probe().unwrap();
+                self.pacer.restart();
+                self.request_frame();
+            }
+            Msg::Stop => self.stop(),
+            Msg::Frame(now) => {
+                if !self.running {
+                    return false;
+                }
+                if !self.pacer.tick(now) {
+                    self.request_frame();
+                    return false;
+                }
+                self.advance();
+                self.render_probe().unwrap();
+                self.request_frame();
+            }
+        }
+        true
+    }
+
+    fn change(&mut self, _props: Self::Properties) -> ShouldRender {
+        false
+    }
+
+    fn destroy(&mut self) {
+        self.stop();
+    }
+
+    fn view(&self) -> Html {
+        let onclick = if self.running {
+            self.link.callback(|_| Msg::Stop)
+        } else {
+            self.link.callback(|_| Msg::Start)
+        };
+        let step = &self.steps[self.probe.step as usize];
+        html! {
+            <div class="calibrate-page">
+                <div class="header">
+                    <p>
+                        { "リモート側でこのページのテストパターンを表示し、ローカル側の受信ページで受信を開始してください。" }<br />
+                        { "QRコードの大きさと送信間隔を変えながら表示し、受信ページに読み取れた割合と、送信ページに入力する設定コードを表示します。" }<br />
+                        { format!("全ての手順を測るには、受信を開始してから1周 (約{}秒) 表示してください。", cycle_seconds(&self.steps)) }
+                    </p>
+                    <button onclick={onclick}>{ if self.running { "停止" } else { "テストパターンを表示" } }</button>
+                    {
+                        if self.running {
+                            html!{
+                                <span class="step">
+                                    { format!(
+                                        "{}周目 手順{}/{}: {}",
+                                        self.probe.cycle + 1,
+                                        self.probe.step + 1,
+                                        self.steps.len(),
+                                        describe(step),
+                                    ) }
+                                </span>
+                            }
+                        } else {
+                            html!{ <></> }
+                        }
+                    }
+                </div>
+                <canvas ref=self.canvas.clone() />
+            </div>
+        }
+    }
+}
+
+// 1周の表示にかかる時間 [秒]
+fn cycle_seconds(steps: &[Step]) -> u32 {
+    let ms: u32 = steps
+        .iter()
+        .map(|s| s.interval as u32 * s.frames as u32)
+        .sum();
+    ms.div_ceil(1000)
+}
+
+fn describe(s: &Step) -> String {
+    format!(
+        "バージョン{} EC {} {}px/cell 送信間隔{}ms",
+        s.version, EC_LEVEL_TABLE[s.ec_level as usize], s.pixel_size, s.interval
+    )
+}
+
+// 受信ページに表示する測定結果。密度の手順はセルのピクセル数ごとの行に、
+// バージョンと誤り訂正レベルの組み合わせごとの列に並べる
+pub fn results_view(c: &Calibration) -> Html {
+    let results: Vec<_> = c.steps().iter().zip(c.tallies()).collect();
+    let mut columns: Vec<(i16, usize)> = Vec::new();
+    let mut rows: Vec<u8> = Vec::new();
+    for (s, _) in results.iter().filter(|(s, _)| !s.speed) {
+        let column = (s.version, s.ec_level as usize);
+        if !columns.contains(&column) {
+            columns.push(column);
+        }
+        if !rows.contains(&s.pixel_size) {
+            rows.push(s.pixel_size);
+        }
+    }
+    let cell = |s: &Step, t: &Tally| {
+        let class = match (t.is_measured(), t.is_reliable()) {
+            (false, _) => "",
+            (true, true) => "reliable",
+            (true, false) => "unreliable",
+        };
+        let text = if t.is_measured() {
+            format!("{}/{}", t.decoded, t.shown)
+        } else {
+            "-".to_string()
+        };
+        html! { <td class={class} title={describe(s)}>{ text }</td> }
+    };
+    // 送信間隔は全て同じQRコードで測る
+    let speed = results.iter().find(|(s, _)| s.speed).map(|(s, _)| s);
+    let (done, total) = c.progress();
+    let recommendation = c.recommend();
+    html! {
+        <div class="calibration">
+            <div>{ format!("キャリブレーション: {}/{} 手順を測定しました", done, total) }</div>
+            <table>
+                <tr>
+                    <th>{ "px/cell" }</th>
+                    {
+                        for columns.iter().map(|(v, ec)| {
+                            html!{ <th>{ format!("{}-{}", v, EC_LEVEL_TABLE[*ec]) }</th> }
+                        })
+                    }
+                </tr>
+                {
+                    for rows.iter().map(|&pixel_size| {
+                        html!{
+                            <tr>
+                                <th>{ pixel_size }</th>
+                                {
+                                    for results.iter()
+                                        .filter(|(s, _)| !s.speed && s.pixel_size == pixel_size)
+                                        .map(|(s, t)| cell(s, t))
+                                }
+                            </tr>
+                        }
+                    })
+                }
+            </table>
+            <table>
+                <tr>
+                    <th>{ "送信間隔" }</th>
+                    { for results.iter().filter(|(s, _)| s.speed).map(|(s, _)| html!{ <th>{ format!("{}ms", s.interval) }</th> }) }
+                </tr>
+                <tr>
+                    <th>
+                    {
+                        match speed {
+                            Some(s) => format!("{}-{} {}px", s.version, EC_LEVEL_TABLE[s.ec_level as usize], s.pixel_size),
+                            None => String::new(),
+                        }
+                    }
+                    </th>
+                    { for results.iter().filter(|(s, _)| s.speed).map(|(s, t)| cell(s, t)) }
+                </tr>
+            </table>
+            {
+                match recommendation {
+                    Some(r) => html!{
+                        <div>
+                            { format!(
+                                "推奨設定: バージョン{} EC {} {}px/cell 送信間隔{}ms",
+                                r.version, EC_LEVEL_TABLE[r.ec_level as usize], r.pixel_size, r.interval
+                            ) }
+                            <div>
+                                { "設定コード (送信ページに入力してください): " }
+                                <span class="settings-code">{ r.encode() }</span>
+                            </div>
+                        </div>
+                    },
+                    None if done > 0 => html!{
+                        <div class="corrupted">{ "確実に読み取れた設定がまだありません" }</div>
+                    },
+                    None => html!{ <></> },
+                }
+            }
+        </div>
+    }
+}
@@ -0,0 +1,912 @@
+// ブラウザ以外(ネイティブ)で実行した場合のコマンドライン
+//
+//   rds-filetransfer decode [オプション] <画像のディレクトリ | ->
+//   rds-filetransfer encode [オプション] -o <出力先> <ファイル>
+//   rds-filetransfer send [オプション] <ファイル>
+//   rds-filetransfer simulate [オプション]
+//
+// decodeはディレクトリ内のPNG/JPEG画像をファイル名順に、"-"の場合は標準入力のY4M動画を
+// 1フレームずつ読み取り、受信ページと同じ手順でファイルを復元する。
+// encodeは送信ページが表示するのと同じ画面を1枚ずつ、連番PNG・アニメーションGIF・Y4M動画に書き出す。
+// sendは同じ画面を送信間隔ごとに端末に表示する。
+// simulateは同じ画面に画面共有で起こる劣化を加えて読み取り、設定ごとの成功率と実効速度を比べる。
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use qrcode::{EcLevel, Version};
+use quircs::Quirc;
+use sha2::{Digest, Sha256};
+
+use rds_filetransfer_core::blockmap::format_ranges;
+use rds_filetransfer_core::capacity::{block_size_of, MIN_BLOCK_SIZE};
+use rds_filetransfer_core::codec::Codec;
+use rds_filetransfer_core::detect::{self, Binarize, Options};
+use rds_filetransfer_core::header::{to_hex, Mode, ParseError, DIGEST_SIZE};
+use rds_filetransfer_core::layout::{cell_color, layout, rasterize, to_rgba};
+use rds_filetransfer_core::resend;
+use rds_filetransfer_core::{Decoder, Encoder, FinishError, Settings};
+
+use crate::send::{
+    to_ec_level, DEFAULT_EC_LEVEL, DEFAULT_INTERVAL, DEFAULT_META_INTERVAL, DEFAULT_PIXEL_SIZE,
+    DEFAULT_VERSION, EC_LEVEL_TABLE, MAX_GRID_SIZE, MAX_PIXEL_SIZE, MIN_PIXEL_SIZE,
+};
+use crate::sim::{self, Case, Impairments};
+use crate::term::{self, Graphics};
+use crate::y4m;
+
+const USAGE: &str = "使い方:
+  rds-filetransfer decode [オプション] <画像のディレクトリ | ->
+  rds-filetransfer encode [オプション] -o <出力先> <ファイル>
+  rds-filetransfer send [オプション] <ファイル>
+  rds-filetransfer simulate [オプション]
+
+decode:
+  PNG/JPEGのフレーム画像を並べたディレクトリ、または標準入力のY4M動画(\"-\")からファイルを復元する
+  -o, --output <パス>         保存先 (省略時は送信されたファイル名でカレントディレクトリに保存)
+  -p, --passphrase <文字列>   暗号化されている場合のパスフレーズ
+  --binarize <方法>           none | local-mean | otsu (既定: local-mean)
+  --smooth                    二値化の前にぼかす (カメラで撮影した場合)
+
+encode:
+  送信ページと同じQRコードの画面を画像または動画に書き出す
+  -o, --output <パス>         .gif はアニメーションGIF、.y4m または \"-\"(標準出力) はY4M動画、
+                              それ以外は連番PNGを書き出すディレクトリ
+  --qr-version <1-40>         QRコードのバージョン (既定: 40)
+  --ec <L|M|Q|H>              誤り訂正レベル (既定: L)
+  --pixels <3-16>             セルあたりのピクセル数 (既定: 5)
+  --grid <縦>x<横>            並べる数 (既定: 1x1、最大4x4)
+  --color                     RGB多重化
+  --mode <方式>               sequential | fountain | loop (既定: sequential)
+  --meta-interval <フレーム>  メタデータ間隔 (既定: 20)
+  --no-compression            圧縮しない
+  -p, --passphrase <文字列>   暗号化する場合のパスフレーズ
+  --interval <ミリ秒>         1画面の表示時間 (GIF, Y4M) (既定: 500)
+  --screens <数>              書き出す画面数 (既定: 逐次は終端まで、繰り返しは1周分、ファウンテン符号は2周分)
+
+send:
+  端末にQRコードを表示して送信する。受信ページで端末のウィンドウを選んで受信する
+  encodeと同じオプション (-o を除く) に加えて
+  --graphics <方法>           auto | blocks | sixel | kitty (既定: auto)
+                              blocksはブロック文字で描き、--pixels は使わない
+  --interval <ミリ秒>         送信間隔 (既定: 500)
+  --screens <数>              表示する画面数 (既定: 逐次は終端まで、それ以外は止めるまで)
+
+simulate:
+  ランダムなファイルを送信ページと同じ画面にして、劣化を加えてから受信ページと同じ手順で読み取る。
+  バージョン・誤り訂正レベル・ピクセル数はカンマ区切りで複数指定でき、全ての組み合わせを試す
+  --qr-version <1-40,...>     QRコードのバージョン (既定: 10,20,40)
+  --ec <L|M|Q|H,...>          誤り訂正レベル (既定: L,M)
+  --pixels <3-16,...>         セルあたりのピクセル数 (既定: 3,5)
+  --mode <方式>               sequential | fountain | loop (既定: fountain)
+  --size <バイト>             送信するファイルの大きさ (既定: 4000)
+  --trials <回数>             設定ごとに送信する回数 (既定: 3)
+  --interval <ミリ秒>         実効速度の計算に使う送信間隔 (既定: 500)
+  --scale <倍率>              表示倍率の違いによる拡大縮小 (既定: 1.0)
+  --jpeg <1-100>              JPEG圧縮の品質 (既定: 圧縮しない)
+  --blur <ピクセル>           ぼかしの半径 (既定: 0)
+  --drop <確率>               画面をキャプチャし損ねる確率 (既定: 0)
+  --duplicate <確率>          同じ画面を2回キャプチャする確率 (既定: 0)
+  --tear <確率>               更新途中の画面をキャプチャする確率 (既定: 0)";
+
+// 終了コード
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let code = match args.first().map(|s| s.as_str()) {
+        Some("decode") => decode(&args[1..]),
+        Some("encode") => encode(&args[1..]),
+        Some("send") => send(&args[1..]),
+        Some("simulate") => simulate(&args[1..]),
+        _ => {
+            eprintln!("{}", USAGE);
+            EXIT_USAGE
+        }
+    };
+    std::process::exit(code);
+}
+
+struct DecodeArgs {
+    input: String,
+    output: Option<PathBuf>,
+    passphrase: Option<String>,
+    opts: Options,
+}
+
+fn parse_decode_args(args: &[String]) -> Result<DecodeArgs, String> {
+    let mut parsed = DecodeArgs {
+        input: String::new(),
+        output: None,
+        passphrase: None,
+        opts: Options {
+            binarize: Binarize::LocalMean,
+            smooth: false,
+        },
+    };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .cloned()
+                .ok_or_else(|| format!("{} に値がありません", arg))
+        };
+        match arg.as_str() {
+            "-o" | "--output" => parsed.output = Some(PathBuf::from(value()?)),
+            "-p" | "--passphrase" => parsed.passphrase = Some(value()?),
+            "--binarize" => {
+                parsed.opts.binarize = match value()?.as_str() {
+                    "none" => Binarize::None,
+                    "local-mean" => Binarize::LocalMean,
+                    "otsu" => Binarize::Otsu,
+                    v => return Err(format!("不明な二値化の方法: {}", v)),
+                }
+            }
+            "--smooth" => parsed.opts.smooth = true,
+            a if a.starts_with('-') && a != "-" => return Err(format!("不明なオプション: {}", a)),
+            a if parsed.input.is_empty() => parsed.input = a.to_string(),
+            a => return Err(format!("入力が複数指定されています: {}", a)),
+        }
+    }
+    if parsed.input.is_empty() {
+        return Err("入力を指定してください".to_string());
+    }
+    Ok(parsed)
+}
+
+fn decode(args: &[String]) -> i32 {
+    let args = match parse_decode_args(args) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    let mut qr_decoder = Quirc::default();
+    let mut decoder = Decoder::new();
+    let (mut frames, mut recognized) = (0usize, 0usize);
+    let mut on_frame = |name: &str, w: usize, h: usize, rgba: &[u8]| {
+        frames += 1;
+        let detected = detect::detect(&mut qr_decoder, w, h, rgba, args.opts);
+        for payload in &detected.payloads {
+            match decoder.push(payload) {
+                Ok(_) => recognized += 1,
+                Err(ParseError::Foreign) => {}
+                Err(e) => eprintln!("{}: {:?}", name, e),
+            }
+        }
+        // 揃った時点で残りのフレームは読まない
+        !decoder.is_complete()
+    };
+    let result = if args.input == "-" {
+        read_y4m(&mut on_frame)
+    } else {
+        read_images(Path::new(&args.input), &mut on_frame)
+    };
+    if let Err(e) = result {
+        eprintln!("入力を読み込めません: {}", e);
+        return EXIT_FAILURE;
+    }
+    report(&mut decoder, frames, recognized, &args)
+}
+
+type FrameCallback<'a> = dyn FnMut(&str, usize, usize, &[u8]) -> bool + 'a;
+
+fn read_images(dir: &Path, on_frame: &mut FrameCallback) -> io::Result<()> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            let ext = p.extension().and_then(|e| e.to_str()).unwrap_or("");
+            ["png", "jpg", "jpeg"].contains(&ext.to_ascii_lowercase().as_str())
+        })
+        .collect();
+    paths.sort();
+    for path in paths {
+        let name = path.display().to_string();
+        let img = match image::open(&path) {
+            Ok(img) => img.to_rgba(),
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                continue;
+            }
+        };
+        let (w, h) = (img.width() as usize, img.height() as usize);
+        if !on_frame(&name, w, h, &img.into_raw()) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn read_y4m(on_frame: &mut FrameCallback) -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut reader = y4m::Reader::new(BufReader::new(stdin.lock()))?;
+    let (w, h) = (reader.width, reader.height);
+    let mut index = 0;
+    while let Some(rgba) = reader.next_frame()? {
+        if !on_frame(&format!("frame {}", index), w, h, &rgba) {
+            break;
+        }
+        index += 1;
+    }
+    Ok(())
+}
+
+// 復元した結果を保存し、検証結果を表示する
+fn report(decoder: &mut Decoder, frames: usize, recognized: usize, args: &DecodeArgs) -> i32 {
+    println!("読み取ったフレーム: {} (QRコード {}個)", frames, recognized);
+    if let Some(v) = decoder.incompatible {
+        println!("未対応のプロトコルバージョン({})のQRコードがありました", v);
+    }
+    if let Some(size) = decoder.too_large {
+        println!("ファイルが大きすぎるため受信できません ({}バイト)", size);
+    }
+    if !decoder.has_metadata() {
+        println!("メタデータを読み取れませんでした");
+        return EXIT_FAILURE;
+    }
+    println!("ファイル名: {}", decoder.file_name);
+    if decoder.codec != Codec::None {
+        println!(
+            "サイズ: {} bytes ({}圧縮 元のサイズ: {} bytes)",
+            decoder.file_size,
+            decoder.codec.name(),
+            decoder.original_size
+        );
+    } else {
+        println!("サイズ: {} bytes", decoder.file_size);
+    }
+    if decoder.cipher.is_some() {
+        println!("暗号化: あり");
+    }
+    let (done, total) = decoder.progress();
+    println!("ブロック: {}/{}", done, total);
+    let data = match decoder.finish(args.passphrase.as_deref()) {
+        Ok(data) => data,
+        Err(e) => {
+            match e {
+                FinishError::Incomplete => {
+                    let missing = decoder.missing();
+                    if let (Some(session), false) = (decoder.session(), missing.is_empty()) {
+                        println!(
+                            "欠落ブロック: {}",
+                            format_ranges(missing.iter().copied(), 10)
+                        );
+                        println!(
+                            "再送コード: {}",
+                            resend::encode(session, missing.into_iter())
+                        );
+                    }
+                    println!("受信は完了していません");
+                }
+                FinishError::PassphraseRequired => {
+                    println!("暗号化されています。--passphrase を指定してください")
+                }
+                FinishError::AuthFailed => {
+                    println!("復号できません。パスフレーズが違うか、データが破損しています")
+                }
+                FinishError::InflateFailed(_) => println!("展開できません。データが破損しています"),
+                FinishError::DigestMismatch(actual, _) => {
+                    println!("SHA-256: {} (不一致)", to_hex(&actual));
+                    println!("期待値:  {}", to_hex(&decoder.digest));
+                }
+            }
+            return EXIT_FAILURE;
+        }
+    };
+    if decoder.legacy {
+        let digest: [u8; DIGEST_SIZE] = Sha256::digest(&data).into();
+        println!("SHA-256: {} (旧形式の送信のため未検証)", to_hex(&digest));
+    } else if decoder.cipher.is_none() {
+        println!("SHA-256: {} (一致)", to_hex(&decoder.digest));
+    } else {
+        println!("認証: 成功");
+    }
+    let path = output_path(&decoder.file_name, args.output.as_deref());
+    if let Err(e) = fs::write(&path, &data) {
+        eprintln!("{}: {}", path.display(), e);
+        return EXIT_FAILURE;
+    }
+    println!("保存先: {}", path.display());
+    0
+}
+
+// 送信されたファイル名はディレクトリを含めずに使う
+fn output_path(file_name: &str, output: Option<&Path>) -> PathBuf {
+    let name = Path::new(file_name)
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_else(|| "received.bin".into());
+    match output {
+        Some(p) if p.is_dir() => p.join(name),
+        Some(p) => p.to_path_buf(),
+        None => PathBuf::from(name),
+    }
+}
+
+enum OutputFormat {
+    Png(PathBuf),
+    Gif(PathBuf),
+    // Noneは標準出力
+    Y4m(Option<PathBuf>),
+}
+
+struct EncodeArgs {
+    input: String,
+    output: Option<OutputFormat>,
+    version: i16,
+    ec_level: EcLevel,
+    pixel_size: u8,
+    grid_rows: u8,
+    grid_cols: u8,
+    color: bool,
+    mode: Mode,
+    meta_interval: u16,
+    compression: bool,
+    passphrase: String,
+    interval: u16,
+    screens: Option<u64>,
+    // 端末に表示する場合の描き方。Noneは端末から推測する
+    graphics: Option<Graphics>,
+}
+
+impl EncodeArgs {
+    fn channels(&self) -> usize {
+        if self.color {
+            3
+        } else {
+            1
+        }
+    }
+
+    // 1画面に表示するフレーム数
+    fn num_tiles(&self) -> usize {
+        self.grid_rows as usize * self.grid_cols as usize * self.channels()
+    }
+}
+
+// encodeとsendは出力先以外のオプションが共通。terminalはsendの場合
+fn parse_encode_args(args: &[String], terminal: bool) -> Result<EncodeArgs, String> {
+    let default_version = match DEFAULT_VERSION {
+        Version::Normal(v) => v,
+        _ => 40,
+    };
+    let mut parsed = EncodeArgs {
+        input: String::new(),
+        output: None,
+        version: default_version,
+        ec_level: DEFAULT_EC_LEVEL,
+        pixel_size: DEFAULT_PIXEL_SIZE,
+        grid_rows: 1,
+        grid_cols: 1,
+        color: false,
+        mode: Mode::Sequential,
+        meta_interval: DEFAULT_META_INTERVAL,
+        compression: true,
+        passphrase: String::new(),
+        interval: DEFAULT_INTERVAL,
+        screens: None,
+        graphics: None,
+    };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .cloned()
+                .ok_or_else(|| format!("{} に値がありません", arg))
+        };
+        let invalid = |v: &str| format!("{} の値が正しくありません: {}", arg, v);
+        match arg.as_str() {
+            "-o" | "--output" if !terminal => {
+                let v = value()?;
+                let path = PathBuf::from(&v);
+                let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+                parsed.output = Some(match ext.to_ascii_lowercase().as_str() {
+                    _ if v == "-" => OutputFormat::Y4m(None),
+                    "gif" => OutputFormat::Gif(path),
+                    "y4m" => OutputFormat::Y4m(Some(path)),
+                    _ => OutputFormat::Png(path),
+                });
+            }
+            "--qr-version" => {
+                let v = value()?;
+                parsed.version = match v.parse() {
+                    Ok(n @ 1..=40) => n,
+                    _ => return Err(invalid(&v)),
+                };
+            }
+            "--ec" => {
+                let v = value()?;
+                let level = EC_LEVEL_TABLE
+                    .iter()
+                    .position(|l| l.eq_ignore_ascii_case(&v))
+                    .and_then(|i| to_ec_level(i as u16));
+                parsed.ec_level = level.ok_or_else(|| invalid(&v))?;
+            }
+            "--pixels" => {
+                let v = value()?;
+                parsed.pixel_size = match v.parse() {
+                    Ok(n) if (MIN_PIXEL_SIZE..=MAX_PIXEL_SIZE).contains(&n) => n,
+                    _ => return Err(invalid(&v)),
+                };
+            }
+            "--grid" => {
+                let v = value()?;
+                let size = |s: &str| match s.parse() {
+                    Ok(n) if (1..=MAX_GRID_SIZE).contains(&n) => Some(n),
+                    _ => None,
+                };
+                let (rows, cols) = v.split_once('x').ok_or_else(|| invalid(&v))?;
+                parsed.grid_rows = size(rows).ok_or_else(|| invalid(&v))?;
+                parsed.grid_cols = size(cols).ok_or_else(|| invalid(&v))?;
+            }
+            "--color" => parsed.color = true,
+            "--mode" => {
+                parsed.mode = match value()?.as_str() {
+                    "sequential" => Mode::Sequential,
+                    "fountain" => Mode::Fountain,
+                    "loop" => Mode::Loop,
+                    v => return Err(format!("不明な送信方式: {}", v)),
+                }
+            }
+            "--meta-interval" => {
+                let v = value()?;
+                parsed.meta_interval = v.parse().map_err(|_| invalid(&v))?;
+            }
+            "--no-compression" => parsed.compression = false,
+            "-p" | "--passphrase" => parsed.passphrase = value()?,
+            "--interval" => {
+                let v = value()?;
+                parsed.interval = match v.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(invalid(&v)),
+                };
+            }
+            "--screens" => {
+                let v = value()?;
+                parsed.screens = Some(v.parse().map_err(|_| invalid(&v))?);
+            }
+            "--graphics" if terminal => {
+                parsed.graphics = match value()?.as_str() {
+                    "auto" => None,
+                    v => Some(Graphics::parse(v).ok_or_else(|| invalid(v))?),
+                }
+            }
+            a if a.starts_with('-') => return Err(format!("不明なオプション: {}", a)),
+            a if parsed.input.is_empty() => parsed.input = a.to_string(),
+            a => return Err(format!("入力が複数指定されています: {}", a)),
+        }
+    }
+    if parsed.input.is_empty() {
+        return Err("送信するファイルを指定してください".to_string());
+    }
+    if parsed.output.is_none() && !terminal {
+        return Err("出力先を指定してください".to_string());
+    }
+    // 送信ページでは選べないバージョン
+    if block_size_of(parsed.version, parsed.ec_level) < MIN_BLOCK_SIZE {
+        return Err(format!(
+            "バージョン{}(EC {})ではメタデータが1フレームに収まりません",
+            parsed.version, EC_LEVEL_TABLE[parsed.ec_level as usize]
+        ));
+    }
+    Ok(parsed)
+}
+
+fn encode(args: &[String]) -> i32 {
+    let args = match parse_encode_args(args, false) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    let (name, mut encoder) = match load_encoder(&args) {
+        Some(v) => v,
+        None => return EXIT_FAILURE,
+    };
+    let screens = args
+        .screens
+        .unwrap_or_else(|| default_screens(&encoder, args.meta_interval, args.num_tiles()));
+
+    let mut writer: Option<FrameWriter> = None;
+    let output = args.output.as_ref().unwrap();
+    let pixel_size = args.pixel_size as u32;
+    let result = for_each_screen(&args, &mut encoder, pixel_size, screens, |w, h, pixels| {
+        let writer = match writer.as_mut() {
+            Some(wr) => wr,
+            None => writer.insert(FrameWriter::open(output, w, h, args.interval)?),
+        };
+        match writer.write(w, h, pixels) {
+            // パイプ先のデコーダが受信を終えた
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(false),
+            r => r.map(|_| true),
+        }
+    })
+    .and_then(|written| match writer.as_mut().map(|w| w.finish()) {
+        Some(Err(e)) if e.kind() != io::ErrorKind::BrokenPipe => Err(e),
+        _ => Ok(written),
+    });
+    let written = match result {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("書き出せません: {}", e);
+            return EXIT_FAILURE;
+        }
+    };
+    // Y4Mを標準出力に書き出す場合に混ざらないように、標準エラーに表示する
+    eprintln!("ファイル名: {}", name);
+    eprintln!(
+        "送信サイズ: {} bytes (ブロック {}個)",
+        encoder.content_len(),
+        encoder.num_blocks()
+    );
+    eprintln!("書き出した画面: {}", written);
+    0
+}
+
+// 端末にQRコードを表示して送信する
+fn send(args: &[String]) -> i32 {
+    let args = match parse_encode_args(args, true) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    let (name, mut encoder) = match load_encoder(&args) {
+        Some(v) => v,
+        None => return EXIT_FAILURE,
+    };
+    let graphics = args.graphics.unwrap_or_else(Graphics::detect);
+    // ブロック文字では1セルを横1文字・縦半文字で描く
+    let pixel_size = match graphics {
+        Graphics::Blocks => 1,
+        _ => args.pixel_size as u32,
+    };
+    // 送信ページと同じく、逐次以外は止めるまで送り続ける
+    let screens = args.screens.unwrap_or(u64::MAX);
+    let interval = Duration::from_millis(args.interval as u64);
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut next = Instant::now();
+    let result = term::clear(&mut out).and_then(|_| {
+        for_each_screen(&args, &mut encoder, pixel_size, screens, |w, h, pixels| {
+            // 描画にかかった時間も含めて、送信間隔ごとに次の画面を表示する
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+            next = Instant::now() + interval;
+            term::draw(graphics, &mut out, w, h, pixels)?;
+            Ok(true)
+        })
+    });
+    drop(out);
+    let written = match result {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("表示できません: {}", e);
+            return EXIT_FAILURE;
+        }
+    };
+    println!();
+    println!("ファイル名: {}", name);
+    println!(
+        "送信サイズ: {} bytes (ブロック {}個)",
+        encoder.content_len(),
+        encoder.num_blocks()
+    );
+    println!("表示した画面: {}", written);
+    0
+}
+
+// ファイルを読み込んで、送信ページと同じ設定でフレームを構築する
+fn load_encoder(args: &EncodeArgs) -> Option<(String, Encoder)> {
+    let original = match fs::read(&args.input) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("{}: {}", args.input, e);
+            return None;
+        }
+    };
+    // 送信ページと同じく、ファイル名はディレクトリを含めない
+    let name = Path::new(&args.input)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let settings = Settings {
+        block_size: block_size_of(args.version, args.ec_level),
+        mode: args.mode,
+        meta_interval: args.meta_interval,
+        compression: args.compression,
+    };
+    // 他の送信と混ざらないように、送信ごとにセッションIDを振る
+    let mut session = [0u8; 4];
+    getrandom::getrandom(&mut session).unwrap();
+    let session = u32::from_le_bytes(session) | 1;
+    let encoder = Encoder::new(
+        name.as_bytes(),
+        &original,
+        &settings,
+        &args.passphrase,
+        session,
+    );
+    Some((name, encoder))
+}
+
+// 送信ページと同じ順に画面を構築して、パレット番号の画像を渡す。
+// on_screenがfalseを返すと終了する。渡した画面数を返す
+fn for_each_screen(
+    args: &EncodeArgs,
+    encoder: &mut Encoder,
+    pixel_size: u32,
+    screens: u64,
+    mut on_screen: impl FnMut(u32, u32, &[u8]) -> io::Result<bool>,
+) -> io::Result<u64> {
+    let num_tiles = args.num_tiles();
+    // 最初の画面は全ての位置にメタデータを表示する
+    let mut tiles = vec![encoder.meta_frame().to_vec(); num_tiles];
+    let mut written = 0;
+    let mut keep_going = true;
+    while written < screens {
+        let screen = layout(
+            &tiles,
+            args.ec_level,
+            args.grid_rows,
+            args.grid_cols,
+            args.channels(),
+        )
+        .ok_or_else(|| io::Error::other("QRコードを生成できません"))?;
+        let (w, h, pixels) = rasterize(&screen, pixel_size);
+        if !on_screen(w, h, &pixels)? {
+            break;
+        }
+        written += 1;
+        if !keep_going {
+            break;
+        }
+        (tiles, keep_going) = encoder.next_tiles(num_tiles);
+    }
+    Ok(written)
+}
+
+// 繰り返しとファウンテン符号は終わりがないので、書き出す画面数の既定値を決める。
+// メタデータを挟む分を含めて、全ブロックを1周 (ファウンテン符号は取りこぼしを見込んで2周分) 送る
+fn default_screens(encoder: &Encoder, meta_interval: u16, num_tiles: usize) -> u64 {
+    let blocks = match encoder.mode() {
+        Mode::Sequential => return u64::MAX,
+        Mode::Loop => encoder.num_blocks(),
+        Mode::Fountain => encoder.num_blocks() * 2,
+    };
+    let m = meta_interval.max(2) as u64;
+    let frames = blocks + blocks.saturating_sub(1) / (m - 1);
+    1 + frames.div_ceil(num_tiles as u64)
+}
+
+enum FrameWriter {
+    Png { dir: PathBuf, index: usize },
+    // 1画面の表示時間[1/100秒]
+    Gif(gif::Encoder<BufWriter<File>>, u16),
+    Y4m(y4m::Writer<Box<dyn Write>>),
+}
+
+impl FrameWriter {
+    fn open(output: &OutputFormat, w: u32, h: u32, interval: u16) -> io::Result<Self> {
+        Ok(match output {
+            OutputFormat::Png(dir) => {
+                fs::create_dir_all(dir)?;
+                Self::Png {
+                    dir: dir.clone(),
+                    index: 0,
+                }
+            }
+            OutputFormat::Gif(path) => {
+                if w > u16::MAX as u32 || h > u16::MAX as u32 {
+                    return Err(io::Error::other("GIFにするには画像が大きすぎます"));
+                }
+                let palette: Vec<u8> = (0..8).flat_map(cell_color).collect();
+                let file = BufWriter::new(File::create(path)?);
+                let mut encoder = gif::Encoder::new(file, w as u16, h as u16, &palette)?;
+                gif::SetParameter::set(&mut encoder, gif::Repeat::Infinite)?;
+                Self::Gif(encoder, interval.div_ceil(10))
+            }
+            OutputFormat::Y4m(path) => {
+                let inner: Box<dyn Write> = match path {
+                    Some(p) => Box::new(BufWriter::new(File::create(p)?)),
+                    None => Box::new(BufWriter::new(io::stdout())),
+                };
+                let (w, h) = (w as usize, h as usize);
+                Self::Y4m(y4m::Writer::new(inner, w, h, 1000, interval as u32)?)
+            }
+        })
+    }
+
+    // pixelsはパレット番号の画像
+    fn write(&mut self, w: u32, h: u32, pixels: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Png { dir, index } => {
+                // decodeでファイル名順に読み込めるように0埋めする
+                let path = dir.join(format!("frame_{:05}.png", index));
+                *index += 1;
+                image::save_buffer(&path, &to_rgba(pixels), w, h, image::ColorType::RGBA(8))
+            }
+            Self::Gif(encoder, delay) => {
+                let frame = gif::Frame {
+                    width: w as u16,
+                    height: h as u16,
+                    delay: *delay,
+                    buffer: pixels.into(),
+                    ..Default::default()
+                };
+                encoder.write_frame(&frame)
+            }
+            Self::Y4m(writer) => writer.write_frame(&to_rgba(pixels)),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            Self::Y4m(writer) => writer.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+struct SimulateArgs {
+    versions: Vec<i16>,
+    ec_levels: Vec<EcLevel>,
+    pixel_sizes: Vec<u8>,
+    mode: Mode,
+    size: usize,
+    trials: u32,
+    interval: u16,
+    impairments: Impairments,
+}
+
+fn parse_simulate_args(args: &[String]) -> Result<SimulateArgs, String> {
+    let mut parsed = SimulateArgs {
+        versions: vec![10, 20, 40],
+        ec_levels: vec![EcLevel::L, EcLevel::M],
+        pixel_sizes: vec![3, 5],
+        mode: Mode::Fountain,
+        size: 4000,
+        trials: 3,
+        interval: DEFAULT_INTERVAL,
+        impairments: Impairments::NONE,
+    };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || {
+            it.next()
+                .cloned()
+                .ok_or_else(|| format!("{} に値がありません", arg))
+        };
+        let invalid = |v: &str| format!("{} の値が正しくありません: {}", arg, v);
+        let probability = |v: &str| match v.parse() {
+            Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+            _ => Err(invalid(v)),
+        };
+        let imp = &mut parsed.impairments;
+        match arg.as_str() {
+            "--qr-version" => {
+                let v = value()?;
+                parsed.versions = parse_list(&v, |s| match s.parse() {
+                    Ok(n @ 1..=40) => Some(n),
+                    _ => None,
+                })
+                .ok_or_else(|| invalid(&v))?;
+            }
+            "--ec" => {
+                let v = value()?;
+                parsed.ec_levels = parse_list(&v, |s| {
+                    EC_LEVEL_TABLE
+                        .iter()
+                        .position(|l| l.eq_ignore_ascii_case(s))
+                        .and_then(|i| to_ec_level(i as u16))
+                })
+                .ok_or_else(|| invalid(&v))?;
+            }
+            "--pixels" => {
+                let v = value()?;
+                parsed.pixel_sizes = parse_list(&v, |s| {
+                    s.parse()
+                        .ok()
+                        .filter(|n| (MIN_PIXEL_SIZE..=MAX_PIXEL_SIZE).contains(n))
+                })
+                .ok_or_else(|| invalid(&v))?;
+            }
+            "--mode" => {
+                parsed.mode = match value()?.as_str() {
+                    "sequential" => Mode::Sequential,
+                    "fountain" => Mode::Fountain,
+                    "loop" => Mode::Loop,
+                    v => return Err(format!("不明な送信方式: {}", v)),
+                }
+            }
+            "--size" => {
+                let v = value()?;
+                parsed.size = v.parse().map_err(|_| invalid(&v))?;
+            }
+            "--trials" => {
+                let v = value()?;
+                parsed.trials = match v.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(invalid(&v)),
+                };
+            }
+            "--interval" => {
+                let v = value()?;
+                parsed.interval = match v.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(invalid(&v)),
+                };
+            }
+            "--scale" => {
+                let v = value()?;
+                imp.scale = match v.parse() {
+                    Ok(s) if s > 0.0 && s <= 4.0 => s,
+                    _ => return Err(invalid(&v)),
+                };
+            }
+            "--jpeg" => {
+                let v = value()?;
+                imp.jpeg_quality = match v.parse() {
+                    Ok(q @ 1..=100) => Some(q),
+                    _ => return Err(invalid(&v)),
+                };
+            }
+            "--blur" => {
+                let v = value()?;
+                imp.blur = v.parse().map_err(|_| invalid(&v))?;
+            }
+            "--drop" => imp.drop_rate = probability(&value()?)?,
+            "--duplicate" => imp.duplicate_rate = probability(&value()?)?,
+            "--tear" => imp.tear_rate = probability(&value()?)?,
+            a => return Err(format!("不明なオプション: {}", a)),
+        }
+    }
+    Ok(parsed)
+}
+
+// カンマ区切りの値をそれぞれparseで変換する。1つでも変換できなければNone
+fn parse_list<T>(v: &str, parse: impl Fn(&str) -> Option<T>) -> Option<Vec<T>> {
+    v.split(',').map(|s| parse(s.trim())).collect()
+}
+
+// 設定の組み合わせごとにシミュレータで送信して、結果を表にする
+fn simulate(args: &[String]) -> i32 {
+    let args = match parse_simulate_args(args) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    let mut data = vec![0u8; args.size];
+    getrandom::getrandom(&mut data).unwrap();
+
+    println!("{}", sim::REPORT_HEADER);
+    for &version in &args.versions {
+        for &ec_level in &args.ec_levels {
+            // 送信ページでは選べないバージョン
+            if block_size_of(version, ec_level) < MIN_BLOCK_SIZE {
+                continue;
+            }
+            for &pixel_size in &args.pixel_sizes {
+                let case = Case {
+                    version,
+                    ec_level,
+                    pixel_size,
+                    mode: args.mode,
+                };
+                let summary =
+                    sim::evaluate(&case, &args.impairments, &data, args.trials, args.interval);
+                println!("{}", sim::report_line(&case, &summary));
+            }
+        }
+    }
+    0
+}
@@ -26,7 +26,8 @@ impl Component for HomePage {
                     { "リモート側でQRコードを表示し、ローカル側はその画面をキャプチャすることでデータをファイル転送を実現します。" }
                 </p>
                 <p>
-                    { "上部のナビゲーションメニューより、ファイルの送信または受信を選んでください。" }
+                    { "上部のナビゲーションメニューより、ファイルの送信または受信を選んでください。" }<br />
+                    { "読み取れる設定が分からない場合は、キャリブレーションで送信側の設定を測ることができます。" }
                 </p>
             </div>
         }
@@ -1,13 +1,25 @@
-mod header;
+mod calibrate;
+#[cfg(not(target_arch = "wasm32"))]
+mod cli;
 mod home;
 mod recv;
 mod routes;
 mod send;
+#[cfg(not(target_arch = "wasm32"))]
+mod sim;
+mod store;
+#[cfg(not(target_arch = "wasm32"))]
+mod term;
+mod util;
+mod workers;
+#[cfg(not(target_arch = "wasm32"))]
+mod y4m;
 
 use yew::prelude::*;
 use yew_router::agent::RouteRequest;
 use yew_router::prelude::*;
 
+use calibrate::CalibratePage;
 use home::HomePage;
 use recv::RecvPage;
 use routes::{Anchor, AppRoute};
@@ -56,19 +68,27 @@ impl Component for App {
     }
 
     fn view(&self) -> Html {
+        // yew 0.18のhtml!はプロパティを持つコンポーネントの型検査のために値を捨てる文を展開するので、
+        // その部分だけclippyの指摘を抑える
+        #[allow(clippy::unnecessary_operation)]
+        let navbar = html! {
+            <div class="navbar">
+                <Anchor route=AppRoute::Home>{ "HOME" }</Anchor>
+                <Anchor route=AppRoute::Send>{ "送信" }</Anchor>
+                <Anchor route=AppRoute::Receive>{ "受信" }</Anchor>
+                <Anchor route=AppRoute::Calibrate>{ "キャリブレーション" }</Anchor>
+            </div>
+        };
         html! {
             <>
-                <div class="navbar">
-                    <Anchor route=AppRoute::Home>{ "HOME" }</Anchor>
-                    <Anchor route=AppRoute::Send>{ "送信" }</Anchor>
-                    <Anchor route=AppRoute::Receive>{ "受信" }</Anchor>
-                </div>
+                { navbar }
                 {
                     if let Some(route) = &self.current_route {
                         match route {
                             AppRoute::Home => html!{ <HomePage /> },
                             AppRoute::Send => html!{ <SendPage /> },
                             AppRoute::Receive => html!{ <RecvPage /> },
+                            AppRoute::Calibrate => html!{ <CalibratePage /> },
                         }
                     } else {
                         html!{ { "not found" } }
@@ -79,6 +99,13 @@ impl Component for App {
     }
 }
 
+#[cfg(target_arch = "wasm32")]
 fn main() {
     yew::start_app::<App>();
 }
+
+// ネイティブで実行した場合はコマンドラインとして動く
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    cli::main();
+}
@@ -1,33 +1,121 @@
 use std::rc::Rc;
 
-use js_sys::{Array, Uint8Array};
+use js_sys::{Array, Date, Object, Reflect, Uint8Array};
 use quircs::Quirc;
+use sha2::{Digest, Sha256};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use web_sys::{
-    Blob, BlobPropertyBag, CanvasRenderingContext2d, HtmlAnchorElement, HtmlCanvasElement,
-    HtmlVideoElement, MediaStream, TextDecoder, Url,
+    Blob, BlobPropertyBag, CanvasRenderingContext2d, File, HtmlAnchorElement, HtmlCanvasElement,
+    HtmlVideoElement, ImageData, MediaDeviceInfo, MediaDeviceKind, MediaStream,
+    MediaStreamConstraints, TextDecoder, Url,
 };
 use yew::prelude::*;
 use yew::services::console::ConsoleService;
 use yew::utils::window;
 
-use crate::header::{parse_header, Header, HEADER_SIZE};
+use rds_filetransfer_core::blockmap::format_ranges;
+use rds_filetransfer_core::calibration::{self, Calibration};
+use rds_filetransfer_core::codec::{self, Codec};
+use rds_filetransfer_core::detect::{self, Binarize, Detected, Options, Rect};
+use rds_filetransfer_core::header::{
+    parse_header, parse_metadata, to_hex, FrameType, Mode, ParseError, DIGEST_SIZE, EXT_CIPHER,
+    EXT_CODEC,
+};
+use rds_filetransfer_core::resend;
+use rds_filetransfer_core::stats::{Stats, HISTORY_LEN};
+use rds_filetransfer_core::{Decoder, Event, FinishError};
+
+use crate::calibrate;
+use crate::store::{self, Store};
+use crate::util::{error_message, format_seconds};
+use crate::workers::{self, WorkerPool};
 
 type FnCB = Box<dyn FnMut(JsValue)>;
 
+// 同時に読み取りを行うWorkerの数
+const WORKER_POOL_SIZE: usize = 2;
+// 読み取り範囲を絞った後も、このフレーム数ごとに画面全体を読み取る
+const FULL_SCAN_INTERVAL: u64 = 30;
+// 読み取り範囲内でこのフレーム数続けて読み取れなかったら画面全体に戻す
+const ROI_MAX_MISSES: u32 = 5;
+// このフレーム数読み取れるごとに、読み取り範囲をその間に読み取れた範囲まで狭める
+const ROI_SHRINK_HITS: u32 = 10;
+// 動画ファイルから読み取るフレームの間隔 [フレーム/秒]
+const FILE_FRAME_RATES: [u32; 3] = [10, 30, 60];
+// カメラに要求する解像度 (幅, 高さ)
+const CAMERA_RESOLUTIONS: [(u32, u32); 3] = [(1280, 720), (1920, 1080), (3840, 2160)];
+// 受信速度のグラフの大きさ [px]
+const SPARKLINE_SIZE: (u32, u32) = (120, 24);
+
+// キャプチャする映像の入力元
+#[derive(Clone, Copy, PartialEq)]
+pub enum Source {
+    // リモートデスクトップのウィンドウを画面共有する
+    Screen,
+    // リモートデスクトップのモニタをカメラで撮影する
+    Camera,
+    // 録画した動画ファイルを1フレームずつ読み取る
+    File,
+}
+
+// IndexedDBに保存されている受信途中の転送
+struct SavedTransfer {
+    key: String,
+    file_name: String,
+    file_size: u64,
+    meta_frame: Vec<u8>,
+}
+
 pub struct RecvPage {
     link: ComponentLink<RecvPage>,
     start: bool,
     timer_id: i32,
+    capturing: bool,
+    workers: Option<WorkerPool>,
+    // QRコードが表示されている範囲
+    roi: Option<Rect>,
+    roi_misses: u32,
+    // 前回狭めてから読み取れた範囲と、読み取れたフレーム数
+    roi_recent: Option<Rect>,
+    roi_hits: u32,
+    scan_count: u64,
     canvas_element: NodeRef,
     video_element: NodeRef,
     recv_ready: bool,
-    received_bytes: usize,
-    received: Vec<Uint8Array>,
-    qr_decoder: Rc<Quirc>,
-    file_size: u64,
-    file_name: String,
+    decoder: Decoder,
+    // キャリブレーションのテストパターンを読み取った結果
+    calibration: Calibration,
+    // 読み取りと受信の統計。キャプチャを始めた時点から数える
+    stats: Stats,
+    result: Vec<u8>,
+    qr_decoder: Quirc,
+    passphrase: String,
+    // 受信は完了したが、パスフレーズが入力されていないので復号していない
+    sealed: bool,
+    auth_failed: bool,
+    verified: Option<bool>,
+    actual_digest: [u8; DIGEST_SIZE],
+    color_mode: bool,
+    binarize: Binarize,
+    source: Source,
+    // カメラの (deviceId, 名前) の一覧
+    cameras: Vec<(String, String)>,
+    // 空の場合は既定のカメラ (背面カメラを優先する)
+    camera_id: String,
+    camera_resolution: usize,
+    capture_error: Option<String>,
+    video_file: Option<File>,
+    video_url: Option<String>,
+    file_fps: u32,
+    // シーク済みで、まだ読み取りに回していないフレームがある
+    file_frame_ready: bool,
+    file_ended: bool,
+    file_position: f64,
+    file_duration: f64,
+    store: Option<Store>,
+    transfer_key: Option<String>,
+    saved: Vec<SavedTransfer>,
 }
 
 pub enum Msg {
@@ -35,9 +123,27 @@ pub enum Msg {
     InitVideo(MediaStream),
     VideoStart,
     Enqueue,
-    Waiting,
-    RecvFirstData(Vec<u8>),
-    Recognized(Header, Uint8Array),
+    SaveAnyway,
+    UpdatePassphrase(String),
+    Decrypt,
+    ColorMode(bool),
+    UpdateBinarize(Binarize),
+    UpdateSource(Source),
+    Cameras(Vec<(String, String)>),
+    UpdateCamera(String),
+    UpdateCameraResolution(usize),
+    CaptureFailed(String),
+    UpdateVideoFile(File),
+    UpdateFileFps(u32),
+    FileLoaded,
+    FrameReady,
+    StoreOpened(Option<Store>),
+    SavedTransfers(Vec<Vec<u8>>),
+    Resume(usize),
+    Restored(Vec<u8>, Vec<(u64, Vec<u8>)>),
+    Forget(usize),
+    Detected(Option<usize>, Detected),
+    WorkerFailed,
 }
 
 impl RecvPage {
@@ -45,66 +151,534 @@ impl RecvPage {
         link.send_message(Msg::InitVideo(s.clone()));
     }
 
-    fn process(
-        mut decoder: Rc<Quirc>,
-        link: ComponentLink<RecvPage>,
-        video: HtmlVideoElement,
-        context: CanvasRenderingContext2d,
-        w: f64,
-        h: f64,
-    ) {
+    // キャプチャした画像を読み取る。Workerが使えない場合はメインスレッドで読み取る
+    fn capture(&mut self, worker: Option<usize>) {
+        let i = match (&self.workers, worker) {
+            (Some(_), Some(i)) => i,
+            _ => {
+                // 描画の機会を与えるため、次の読み取りはタイマーで行う
+                let link = self.link.clone();
+                let cb = Closure::wrap(Box::new(move || {
+                    link.send_message(Msg::Enqueue);
+                }) as Box<dyn Fn()>);
+                self.timer_id = window()
+                    .set_timeout_with_callback_and_timeout_and_arguments_0(
+                        cb.as_ref().unchecked_ref(),
+                        1,
+                    )
+                    .unwrap();
+                cb.forget();
+                return;
+            }
+        };
+        let opts = self.detect_options();
+        if let Some((img, crop)) = self.grab() {
+            if let Some(pool) = self.workers.as_mut() {
+                pool.post(i, &img, crop.x, crop.y, opts);
+            }
+        }
+    }
+
+    // キャプチャした画像と、切り出した範囲を返す
+    fn grab(&mut self) -> Option<(ImageData, Rect)> {
+        let video = self.video_element.cast::<HtmlVideoElement>()?;
+        let canvas = self.canvas_element.cast::<HtmlCanvasElement>()?;
+        let context = canvas
+            .get_context("2d")
+            .ok()??
+            .dyn_into::<CanvasRenderingContext2d>()
+            .ok()?;
+        let full = Rect {
+            x: 0,
+            y: 0,
+            w: canvas.width(),
+            h: canvas.height(),
+        };
+        self.scan_count += 1;
+        let crop = match self.roi {
+            Some(roi) if !self.scan_count.is_multiple_of(FULL_SCAN_INTERVAL) => roi,
+            _ => full,
+        };
         context
             .draw_image_with_html_video_element(&video, 0.0, 0.0)
-            .unwrap();
-        let img = context.get_image_data(0.0, 0.0, w, h).unwrap();
-        let d = img.data().0;
-        let hi = h as i32;
-        let wi = w as i32;
-        let mut gs: Vec<u8> = Vec::with_capacity((hi as usize) * (wi as usize));
-        gs.resize(gs.capacity(), 0);
-        for y in 0..hi {
-            for x in 0..wi {
-                let j = (y * wi + x) as usize;
-                let i = j * 4;
-                let (r, g, b) = (d[i], d[i + 1], d[i + 2]);
-                gs[j] = ((0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32).clamp(0.0, 1.0)
-                    * 255.0) as u8;
+            .ok()?;
+        let img = context
+            .get_image_data(crop.x as f64, crop.y as f64, crop.w as f64, crop.h as f64)
+            .ok()?;
+        Some((img, crop))
+    }
+
+    fn detect_options(&self) -> Options {
+        Options {
+            binarize: self.binarize,
+            smooth: self.source == Source::Camera,
+        }
+    }
+
+    fn reset_roi(&mut self) {
+        self.roi = None;
+        self.roi_misses = 0;
+        self.roi_recent = None;
+        self.roi_hits = 0;
+    }
+
+    // 読み取れたQRコードの周辺だけを次回以降の読み取り範囲にする
+    fn update_roi(&mut self, bounds: Option<Rect>) {
+        let canvas = match self.canvas_element.cast::<HtmlCanvasElement>() {
+            Some(c) => c,
+            None => return,
+        };
+        match bounds {
+            Some(b) => {
+                // カメラは手ぶれなどで写る位置が動くので余裕を大きく取る
+                let margin = match self.source {
+                    Source::Screen | Source::File => b.w.max(b.h) / 4,
+                    Source::Camera => b.w.max(b.h) / 2,
+                }
+                .max(32);
+                let b = b.expand(margin, canvas.width(), canvas.height());
+                // グリッドで一部のQRコードしか読み取れなかった場合に備えてすぐには狭めず、
+                // 直近ROI_SHRINK_HITS回に読み取れた範囲をまとめて次の範囲にする
+                self.roi = Some(self.roi.map_or(b, |r| r.union(&b)));
+                self.roi_misses = 0;
+                self.roi_recent = Some(self.roi_recent.map_or(b, |r| r.union(&b)));
+                self.roi_hits += 1;
+                if self.roi_hits >= ROI_SHRINK_HITS {
+                    self.roi = self.roi_recent.take();
+                    self.roi_hits = 0;
+                }
+            }
+            None if self.roi.is_some() => {
+                self.roi_misses += 1;
+                if self.roi_misses >= ROI_MAX_MISSES {
+                    self.reset_roi();
+                }
             }
+            None => {}
         }
-        let codes = Rc::make_mut(&mut decoder).identify(w as usize, hi as usize, &gs);
-        for code in codes {
-            let code = code.expect("failed to extract qr code");
-            match code.decode() {
-                Ok(decoded) => {
-                    let d = decoded.payload;
-                    let header = parse_header(&d[..]);
-                    ConsoleService::log(format!("{} {}", header.seq, header.size).as_ref());
-                    if header.seq == 0 {
-                        if header.size == 0 {
-                            link.send_message(Msg::Waiting);
-                        } else {
-                            link.send_message(Msg::RecvFirstData(d));
-                        }
-                    } else {
-                        let buf = Uint8Array::new_with_length(header.size as u32);
-                        buf.copy_from(&d[HEADER_SIZE..HEADER_SIZE + header.size as usize]);
-                        link.send_message(Msg::Recognized(header, buf));
-                    }
+    }
+
+    fn dispatch(&mut self, detected: Detected) -> ShouldRender {
+        for e in detected.errors {
+            ConsoleService::log(format!("ERROR: {}", e).as_ref());
+        }
+        self.update_roi(detected.bounds);
+        let mut render = self.update(Msg::ColorMode(detected.color));
+        let now = Date::now();
+        // 1秒ごとに統計を記録して表示を更新する
+        if self.capturing && self.stats.scan(now) {
+            self.log_stats(now);
+            render = true;
+        }
+        for d in detected.payloads {
+            // 受信を終えた後に届いた読み取り結果は使わない
+            if !self.capturing {
+                break;
+            }
+            // キャリブレーションのテストパターンはファイルの受信とは別に数える
+            if let Some(probe) = calibration::parse_probe(&d) {
+                self.calibration.observe(&probe);
+                render = true;
+                continue;
+            }
+            let incompatible = self.decoder.incompatible;
+            let too_large = self.decoder.too_large;
+            let progress = (
+                self.decoder.received_bytes(),
+                self.decoder.received_symbols(),
+            );
+            let is_data = matches!(
+                parse_header(&d).map(|h| h.frame_type),
+                Ok(FrameType::Data) | Ok(FrameType::Parity)
+            );
+            let events = match self.decoder.push(&d) {
+                Ok(events) => events,
+                Err(ParseError::Incompatible(v)) => {
+                    render |= incompatible != Some(v);
+                    continue;
                 }
                 Err(e) => {
                     ConsoleService::log(format!("ERROR: {:?}", e).as_ref());
+                    continue;
                 }
+            };
+            render |= self.decoder.too_large != too_large;
+            // ファウンテン符号は復元を保留しているシンボルも新しいフレームとして数える
+            let bytes = self.decoder.received_bytes() - progress.0;
+            let fresh = bytes > 0 || self.decoder.received_symbols() > progress.1;
+            if fresh || (is_data && self.decoder.has_metadata()) {
+                self.stats.decoded(now, Some(bytes).filter(|_| fresh));
             }
+            for event in events {
+                ConsoleService::log(format!("{:?}", event).as_ref());
+                render |= self.on_event(event);
+            }
+        }
+        if self.start && self.decoder.is_complete() {
+            self.finish();
+            render = true;
         }
-        link.send_message(Msg::Enqueue);
+        render
     }
 
-    fn start_download(&mut self) {
-        let array = Array::new_with_length(self.received.len() as u32);
-        for i in 0..self.received.len() {
-            let tmp: &JsValue = self.received[i].as_ref();
-            array.set(i as u32, tmp.clone());
+    fn on_event(&mut self, event: Event) -> ShouldRender {
+        match event {
+            Event::Waiting => !std::mem::replace(&mut self.recv_ready, true),
+            Event::Metadata => {
+                self.save_transfer();
+                true
+            }
+            Event::Block(seq) => {
+                // ページを開き直しても再開できるように保存しておく
+                if let (Some(store), Some(key), Some(data)) =
+                    (&self.store, &self.transfer_key, self.decoder.block(seq))
+                {
+                    store.put_block(key, seq, data);
+                }
+                true
+            }
+            Event::Resent | Event::Symbol | Event::Eof => true,
+        }
+    }
+
+    // 受信途中の転送をIndexedDBに保存する。旧形式とファウンテン符号の受信は保存しない
+    fn save_transfer(&mut self) {
+        let (store, frame) = match (&self.store, self.decoder.meta_frame()) {
+            (Some(store), Some(frame)) if self.decoder.mode() != Mode::Fountain => (store, frame),
+            _ => return,
+        };
+        let key = transfer_key(
+            &self.decoder.digest,
+            self.decoder.file_size,
+            self.decoder.block_size,
+            self.decoder.cipher.as_ref().and(self.decoder.session()),
+        );
+        store.put_transfer(&key, frame);
+        self.transfer_key = Some(key);
+    }
+
+    // 受信完了までに必要な残りのフレーム数の目安。
+    // ファウンテン符号はブロック数と同じ数のシンボルが揃えばほぼ復元できるとみなす
+    fn remaining_frames(&self) -> u64 {
+        let (done, total) = self.decoder.progress();
+        if self.decoder.mode() == Mode::Fountain {
+            total.saturating_sub(self.decoder.received_symbols()).max(1) as u64
+        } else {
+            (total - done) as u64
+        }
+    }
+
+    // 送信間隔の調整に使えるように、統計を開発者ツールのコンソールに出力する
+    fn log_stats(&self, now: f64) {
+        if !self.decoder.has_metadata() {
+            return;
+        }
+        let rates = self.stats.rates(now);
+        let eta = self.stats.eta(now, self.remaining_frames());
+        ConsoleService::log(
+            format!(
+                "STATS: elapsed={:.0}s scans/s={:.1} decoded/s={:.1} duplicates/s={:.1} new/s={:.1} bytes/s={:.0} eta={} idle={} reads/frame={}",
+                self.stats.elapsed(now),
+                rates.scans,
+                rates.decoded,
+                rates.duplicates,
+                rates.fresh,
+                rates.bytes,
+                eta.map_or("-".to_string(), |t| format!("{:.0}s", t)),
+                self.stats
+                    .since_fresh(now)
+                    .map_or("-".to_string(), |t| format!("{:.1}s", t)),
+                self.stats
+                    .reads_per_frame()
+                    .map_or("-".to_string(), |r| format!("{:.2}", r)),
+            )
+            .as_ref(),
+        );
+    }
+
+    fn finish(&mut self) {
+        let now = Date::now();
+        let total = self.stats.total;
+        // 保存済みのブロックだけで完了した場合は読み取っていない
+        if total.fresh > 0 {
+            ConsoleService::log(
+                format!(
+                "STATS: completed in {:.1}s scans={} decoded={} duplicates={} new={} bytes/s={:.0}",
+                self.stats.elapsed(now),
+                total.scans,
+                total.decoded,
+                total.duplicates,
+                total.fresh,
+                total.bytes as f64 / self.stats.elapsed(now).max(0.001),
+            )
+                .as_ref(),
+            );
+        }
+        window().clear_timeout_with_handle(self.timer_id);
+        self.timer_id = -1;
+        self.capturing = false;
+        self.start = false;
+        self.complete();
+    }
+
+    // 受信したデータを復号・展開してハッシュ値を検証し、一致した場合のみ保存する
+    fn complete(&mut self) {
+        // 暗号化されている場合は、パスフレーズが入力されるまで復号を待つ
+        let passphrase = Some(self.passphrase.as_str()).filter(|p| !p.is_empty());
+        let (data, ok) = match self.decoder.finish(passphrase) {
+            Ok(data) => (data, true),
+            Err(FinishError::Incomplete) => return,
+            Err(FinishError::PassphraseRequired) => {
+                self.sealed = true;
+                return;
+            }
+            // 認証に失敗した場合は保存させない
+            Err(FinishError::AuthFailed) => {
+                self.sealed = true;
+                self.auth_failed = true;
+                return;
+            }
+            // 展開に失敗した場合は受信したままのデータを保存できるようにする
+            Err(FinishError::InflateFailed(data)) | Err(FinishError::DigestMismatch(_, data)) => {
+                (data, false)
+            }
+        };
+        self.sealed = false;
+        self.auth_failed = false;
+        self.actual_digest = Sha256::digest(&data).into();
+        self.result = data;
+        self.verified = Some(ok);
+        if ok {
+            self.start_download();
+            // 保存できたので再開用のデータは不要
+            if let Some(key) = self.transfer_key.take() {
+                if let Some(store) = &self.store {
+                    store.delete(&key);
+                }
+                self.saved.retain(|t| t.key != key);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.recv_ready = false;
+        self.decoder = Decoder::new();
+        self.calibration = Calibration::new();
+        self.result = Vec::new();
+        self.sealed = false;
+        self.auth_failed = false;
+        self.verified = None;
+        self.transfer_key = None;
+    }
+
+    fn start_capture(&mut self) {
+        if self.source == Source::File {
+            self.open_video_file();
+            return;
+        }
+        let link = self.link.clone();
+        let cb = Closure::wrap(Box::new(move |v: JsValue| {
+            Self::get_display_media_callback(link.clone(), v.dyn_into::<MediaStream>().unwrap());
+        }) as FnCB);
+        let link = self.link.clone();
+        let on_error = Closure::wrap(Box::new(move |e: JsValue| {
+            link.send_message(Msg::CaptureFailed(error_message(&e)));
+        }) as FnCB);
+        let devices = window().navigator().media_devices().unwrap();
+        let promise = match self.source {
+            Source::Camera => devices.get_user_media_with_constraints(&self.camera_constraints()),
+            _ => devices.get_display_media(),
+        };
+        match promise {
+            Ok(p) => {
+                let _ = p.then2(&cb, &on_error);
+            }
+            Err(e) => self
+                .link
+                .send_message(Msg::CaptureFailed(error_message(&e))),
+        }
+        cb.forget();
+        on_error.forget();
+    }
+
+    // 動画ファイルは再生せず、シークしたフレームを順に読み取る
+    fn open_video_file(&mut self) {
+        let (file, video) = match (
+            &self.video_file,
+            self.video_element.cast::<HtmlVideoElement>(),
+        ) {
+            (Some(f), Some(v)) => (f, v),
+            _ => return,
+        };
+        if let Some(url) = self.video_url.take() {
+            let _ = Url::revoke_object_url(&url);
+        }
+        match Url::create_object_url_with_blob(file) {
+            Ok(url) => {
+                video.set_src(&url);
+                self.video_url = Some(url);
+            }
+            Err(e) => self
+                .link
+                .send_message(Msg::CaptureFailed(error_message(&e))),
+        }
+    }
+
+    fn seek_file(&mut self, t: f64) {
+        let video = match self.video_element.cast::<HtmlVideoElement>() {
+            Some(v) => v,
+            None => return,
+        };
+        // MediaRecorderで録画した動画などは長さが不明(Infinity)のことがある
+        if self.file_duration.is_finite() && t > self.file_duration {
+            self.file_ended = true;
+            return;
+        }
+        self.file_position = t;
+        video.set_current_time(t);
+    }
+
+    // シーク済みのフレームを読み取りに回し、次のフレームへシークする
+    fn process_file_frame(&mut self) -> ShouldRender {
+        if !self.capturing || !self.file_frame_ready {
+            return false;
+        }
+        let render = match &self.workers {
+            Some(pool) => match (0..pool.len()).find(|&i| !pool.is_busy(i)) {
+                Some(i) => {
+                    self.capture(Some(i));
+                    false
+                }
+                // 空いているWorkerができるまで待つ
+                None => return false,
+            },
+            None => self.detect_locally(),
+        };
+        self.file_frame_ready = false;
+        if self.capturing && !self.file_ended {
+            self.seek_file(self.file_position + 1.0 / self.file_fps as f64);
+            // 読み取り位置の表示を更新する
+            return true;
+        }
+        render
+    }
+
+    // 最後のフレームの読み取りが全て終わったら停止する
+    fn check_file_end(&mut self) -> ShouldRender {
+        let idle = match &self.workers {
+            Some(pool) => (0..pool.len()).all(|i| !pool.is_busy(i)),
+            None => true,
+        };
+        if self.source != Source::File || !self.capturing || !self.file_ended || !idle {
+            return false;
         }
+        self.capturing = false;
+        true
+    }
+
+    fn detect_locally(&mut self) -> ShouldRender {
+        let (img, crop) = match self.grab() {
+            Some(v) => v,
+            None => return false,
+        };
+        let (w, h) = (img.width() as usize, img.height() as usize);
+        let opts = self.detect_options();
+        let mut detected = detect::detect(&mut self.qr_decoder, w, h, &img.data(), opts);
+        detected.translate(crop.x, crop.y);
+        self.dispatch(detected)
+    }
+
+    fn camera_constraints(&self) -> MediaStreamConstraints {
+        let (w, h) = CAMERA_RESOLUTIONS[self.camera_resolution];
+        let ideal = |v: JsValue| js_object(&[("ideal", v)]);
+        let device = if self.camera_id.is_empty() {
+            ("facingMode", ideal("environment".into()))
+        } else {
+            (
+                "deviceId",
+                js_object(&[("exact", self.camera_id.as_str().into())]),
+            )
+        };
+        // 画面に焦点を合わせ続ける。対応していないブラウザやカメラでは無視される
+        let focus = js_object(&[("focusMode", "continuous".into())]);
+        let video = js_object(&[
+            ("width", ideal(w.into())),
+            ("height", ideal(h.into())),
+            // 動きによるぶれを減らすため、フレームレートは高めに要求する
+            ("frameRate", ideal(30.into())),
+            device,
+            ("advanced", Array::of1(&focus).into()),
+        ]);
+        let mut constraints = MediaStreamConstraints::new();
+        constraints.video(&video).audio(&JsValue::FALSE);
+        constraints
+    }
+
+    // カメラの一覧を取得する。名前はカメラの使用を許可するまで空になる
+    fn list_cameras(&self) {
+        let promise = match window()
+            .navigator()
+            .media_devices()
+            .and_then(|d| d.enumerate_devices())
+        {
+            Ok(p) => p,
+            Err(_) => return,
+        };
+        let link = self.link.clone();
+        let cb = Closure::wrap(Box::new(move |v: JsValue| {
+            let cameras = v
+                .unchecked_into::<Array>()
+                .iter()
+                .map(|d| d.unchecked_into::<MediaDeviceInfo>())
+                .filter(|d| d.kind() == MediaDeviceKind::Videoinput)
+                .map(|d| (d.device_id(), d.label()))
+                .collect();
+            link.send_message(Msg::Cameras(cameras));
+        }) as FnCB);
+        let _ = promise.then(&cb);
+        cb.forget();
+    }
+
+    // 読み取りと受信の速度、残り時間と、直近の受信速度のグラフ
+    fn stats_view(&self) -> Html {
+        let now = Date::now();
+        let rates = self.stats.rates(now);
+        let eta = match self.stats.eta(now, self.remaining_frames()) {
+            Some(t) => format!("残り約{}", format_seconds(t)),
+            None => "残り時間: 不明".to_string(),
+        };
+        let idle = match self.stats.since_fresh(now) {
+            Some(t) => format!("最後の新しいブロックから{:.1}秒", t),
+            None => "新しいブロックをまだ受信していません".to_string(),
+        };
+        let reads = match self.stats.reads_per_frame() {
+            Some(r) => format!("1フレームあたり{:.1}回読み取り", r),
+            None => String::new(),
+        };
+        let (w, h) = SPARKLINE_SIZE;
+        let history: Vec<f64> = self.stats.history().map(|c| c.bytes as f64).collect();
+        html! {
+            <div class="stats">
+                <div>
+                    { format!(
+                        "読み取り {:.1}回/秒 成功 {:.1}個/秒 (新規 {:.1} 重複 {:.1}) 受信速度 {:.1}KB/秒",
+                        rates.scans, rates.decoded, rates.fresh, rates.duplicates, rates.bytes / 1000.0
+                    ) }
+                </div>
+                <div>
+                    <svg class="sparkline" width={ w.to_string() } height={ h.to_string() }>
+                        <title>{ format!("直近{}秒の受信速度", HISTORY_LEN) }</title>
+                        <polyline points={ sparkline_points(&history, w, h) } />
+                    </svg>
+                    { format!(" {} {} {}", eta, idle, reads) }
+                </div>
+            </div>
+        }
+    }
+
+    fn start_download(&mut self) {
+        let array = Array::new_with_length(1);
+        array.set(0, Uint8Array::from(&self.result[..]).into());
         let mut props = BlobPropertyBag::new();
         props.type_("octet/stream");
         let blob = Blob::new_with_blob_sequence_and_options(array.as_ref(), &props).unwrap();
@@ -117,7 +691,7 @@ impl RecvPage {
             .dyn_into::<HtmlAnchorElement>()
             .unwrap();
         a.set_href(&url);
-        a.set_download(self.file_name.as_ref());
+        a.set_download(self.decoder.file_name.as_ref());
         a.click();
         Url::revoke_object_url(&url).unwrap();
     }
@@ -132,136 +706,250 @@ impl Component for RecvPage {
             link,
             start: false,
             timer_id: -1,
+            capturing: false,
+            workers: None,
+            roi: None,
+            roi_misses: 0,
+            roi_recent: None,
+            roi_hits: 0,
+            scan_count: 0,
+            source: Source::Screen,
+            cameras: Vec::new(),
+            camera_id: String::new(),
+            camera_resolution: 1,
+            capture_error: None,
+            video_file: None,
+            video_url: None,
+            file_fps: 30,
+            file_frame_ready: false,
+            file_ended: false,
+            file_position: 0.0,
+            file_duration: 0.0,
             canvas_element: NodeRef::default(),
             video_element: NodeRef::default(),
             recv_ready: false,
-            received_bytes: 0,
-            received: Vec::new(),
-            qr_decoder: Rc::new(Quirc::default()),
-            file_size: 0,
-            file_name: Default::default(),
+            decoder: Decoder::new(),
+            calibration: Calibration::new(),
+            stats: Stats::new(Date::now()),
+            result: Vec::new(),
+            qr_decoder: Quirc::default(),
+            passphrase: String::new(),
+            sealed: false,
+            auth_failed: false,
+            verified: None,
+            actual_digest: [0; DIGEST_SIZE],
+            color_mode: false,
+            binarize: Binarize::LocalMean,
+            store: None,
+            transfer_key: None,
+            saved: Vec::new(),
         }
     }
 
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
             Msg::Start => {
+                self.reset();
                 self.start = true;
-                self.recv_ready = false;
-                self.file_size = 0;
-                self.file_name = Default::default();
-                self.received_bytes = 0;
-                self.received.clear();
-                let link = self.link.clone();
-                let cb = Closure::wrap(Box::new(move |v: JsValue| {
-                    Self::get_display_media_callback(
-                        link.clone(),
-                        v.dyn_into::<MediaStream>().unwrap(),
-                    );
-                }) as FnCB);
-                let _ = window()
-                    .navigator()
-                    .media_devices()
-                    .unwrap()
-                    .get_display_media()
-                    .unwrap()
-                    .then(&cb);
-                cb.forget();
+                self.capture_error = None;
+                self.start_capture();
             }
             Msg::InitVideo(s) => {
                 let video = self.video_element.cast::<HtmlVideoElement>().unwrap();
                 video.set_src_object(Some(&s));
+                let _ = video.play();
+                if self.source == Source::Camera {
+                    self.list_cameras();
+                }
             }
-            Msg::VideoStart | Msg::Enqueue => {
-                let link = self.link.clone();
+            Msg::VideoStart => {
+                if self.source == Source::File {
+                    return false;
+                }
                 let video = self.video_element.cast::<HtmlVideoElement>().unwrap();
                 let canvas = self.canvas_element.cast::<HtmlCanvasElement>().unwrap();
-                let (vw, vh) = (video.video_width() as f64, video.video_height() as f64);
-                match msg {
-                    Msg::VideoStart => {
-                        canvas.set_height(vh as u32);
-                        canvas.set_width(vw as u32);
-                    }
-                    _ => {
-                        if self.timer_id < 0 {
-                            return false;
-                        }
+                canvas.set_height(video.video_height());
+                canvas.set_width(video.video_width());
+                if self.capturing {
+                    return false;
+                }
+                self.capturing = true;
+                self.reset_roi();
+                self.stats = Stats::new(Date::now());
+                // 各Workerに1枚ずつ渡し、読み取りとキャプチャを並行させる
+                match &self.workers {
+                    Some(pool) => {
+                        let idle: Vec<usize> =
+                            (0..pool.len()).filter(|&i| !pool.is_busy(i)).collect();
+                        idle.into_iter().for_each(|i| self.capture(Some(i)));
                     }
+                    None => self.capture(None),
                 }
-                let context = canvas
-                    .get_context("2d")
-                    .unwrap()
-                    .unwrap()
-                    .dyn_into::<CanvasRenderingContext2d>()
-                    .unwrap();
-                let decoder = self.qr_decoder.clone();
-                let cb = Closure::wrap(Box::new(move || {
-                    RecvPage::process(
-                        decoder.clone(),
-                        link.clone(),
-                        video.clone(),
-                        context.clone(),
-                        vw,
-                        vh,
-                    );
-                }) as Box<dyn Fn()>);
-                self.timer_id = window()
-                    .set_timeout_with_callback_and_timeout_and_arguments_0(
-                        cb.as_ref().unchecked_ref(),
-                        1,
-                    )
-                    .unwrap();
-                cb.forget();
             }
-            Msg::Waiting => {
-                if !self.recv_ready {
-                    self.recv_ready = true;
-                    return true;
+            Msg::Enqueue => {
+                if !self.capturing {
+                    return false;
                 }
-                return false;
+                let render = self.detect_locally();
+                if self.capturing {
+                    self.capture(None);
+                }
+                return render;
             }
-            Msg::RecvFirstData(mut data) => {
-                if self.file_size == 0 && self.file_name.is_empty() {
-                    for i in 0..8 {
-                        self.file_size |= (data[HEADER_SIZE + i] as u64) << (i * 8);
+            Msg::Detected(worker, detected) => {
+                if let (Some(pool), Some(i)) = (self.workers.as_mut(), worker) {
+                    pool.done(i);
+                }
+                let render = self.dispatch(detected);
+                if self.source == Source::File {
+                    return render | self.process_file_frame() | self.check_file_end();
+                }
+                if self.capturing {
+                    self.capture(worker);
+                }
+                return render;
+            }
+            Msg::WorkerFailed => {
+                // Workerを読み込めない場合はメインスレッドで読み取る
+                if self.workers.take().is_some() && self.capturing {
+                    ConsoleService::log("Worker is unavailable. Decoding on the main thread.");
+                    if self.source == Source::File {
+                        return self.process_file_frame() | self.check_file_end();
                     }
-                    let decoder = TextDecoder::new().unwrap();
-                    let offset = HEADER_SIZE + 8;
-                    let nullpos = data[offset..].iter().position(|&x| x == 0).unwrap();
-                    self.file_name = decoder
-                        .decode_with_u8_array(&mut data[offset..offset + nullpos])
-                        .unwrap();
-                    return true;
-                } else {
+                    self.capture(None);
+                }
+                return false;
+            }
+            Msg::SaveAnyway => {
+                self.start_download();
+                return false;
+            }
+            Msg::UpdatePassphrase(v) => {
+                self.passphrase = v;
+                return false;
+            }
+            Msg::Decrypt => {
+                if !self.sealed {
+                    return false;
+                }
+                self.complete();
+            }
+            Msg::ColorMode(v) => {
+                if self.color_mode == v {
                     return false;
                 }
+                self.color_mode = v;
+            }
+            Msg::UpdateBinarize(v) => {
+                self.binarize = v;
+                return false;
+            }
+            Msg::UpdateSource(v) => {
+                self.source = v;
+                if v == Source::Camera {
+                    self.list_cameras();
+                }
+            }
+            Msg::Cameras(v) => self.cameras = v,
+            Msg::UpdateCamera(v) => {
+                self.camera_id = v;
+                return false;
+            }
+            Msg::UpdateCameraResolution(v) => {
+                self.camera_resolution = v;
+                return false;
+            }
+            Msg::UpdateVideoFile(f) => self.video_file = Some(f),
+            Msg::UpdateFileFps(v) => {
+                self.file_fps = v;
+                return false;
             }
-            Msg::Recognized(header, buf) => {
-                if self.timer_id < 0 {
+            Msg::FileLoaded => {
+                if self.source != Source::File || !self.start {
                     return false;
                 }
-                if self.received.len() + 1 < header.seq as usize {
-                    window().clear_timeout_with_handle(self.timer_id);
-                    self.timer_id = -1;
-                    let _ = window().alert_with_message("受信に失敗しました: シーケンス番号ずれ");
-                    window().location().reload().unwrap();
+                let video = self.video_element.cast::<HtmlVideoElement>().unwrap();
+                let canvas = self.canvas_element.cast::<HtmlCanvasElement>().unwrap();
+                canvas.set_height(video.video_height());
+                canvas.set_width(video.video_width());
+                self.capturing = true;
+                self.reset_roi();
+                self.stats = Stats::new(Date::now());
+                self.file_frame_ready = false;
+                self.file_ended = false;
+                self.file_duration = video.duration();
+                self.seek_file(0.0);
+            }
+            Msg::FrameReady => {
+                if self.source != Source::File || !self.capturing {
                     return false;
                 }
-                if self.received.len() + 1 == header.seq as usize {
-                    self.received_bytes += buf.byte_length() as usize;
-                    self.received.push(buf);
-                    return true;
+                // 長さが不明な動画では、末尾を超えてシークすると末尾で止まる
+                let video = self.video_element.cast::<HtmlVideoElement>().unwrap();
+                if video.ended()
+                    || video.current_time() + 0.5 / (self.file_fps as f64) < self.file_position
+                {
+                    self.file_ended = true;
                 }
-                if buf.length() == 0 {
-                    // EOF
-                    window().clear_timeout_with_handle(self.timer_id);
-                    self.timer_id = -1;
-                    self.start = false;
-                    self.start_download();
-                    return true;
+                self.file_frame_ready = true;
+                return self.process_file_frame() | self.check_file_end();
+            }
+            Msg::CaptureFailed(e) => {
+                self.start = false;
+                self.capture_error = Some(e);
+            }
+            Msg::StoreOpened(store) => {
+                if let Some(store) = &store {
+                    let link = self.link.clone();
+                    store.list_transfers(move |frames| {
+                        link.send_message(Msg::SavedTransfers(frames));
+                    });
                 }
+                self.store = store;
                 return false;
             }
+            Msg::SavedTransfers(frames) => {
+                self.saved = frames.into_iter().filter_map(to_saved_transfer).collect();
+            }
+            Msg::Resume(i) => {
+                if let (Some(store), Some(t)) = (&self.store, self.saved.get(i)) {
+                    let link = self.link.clone();
+                    let meta_frame = t.meta_frame.clone();
+                    store.load_blocks(&t.key, move |blocks| {
+                        link.send_message(Msg::Restored(meta_frame, blocks));
+                    });
+                }
+                return false;
+            }
+            Msg::Restored(meta_frame, blocks) => {
+                let mut decoder = Decoder::new();
+                match decoder.push(&meta_frame) {
+                    Ok(events) if events.contains(&Event::Metadata) => {}
+                    _ => return false,
+                }
+                self.reset();
+                self.decoder = decoder;
+                self.start = true;
+                self.save_transfer();
+                for (index, data) in blocks {
+                    self.decoder.insert_block(index, &data);
+                }
+                // 全てのブロックが保存済みであればキャプチャせずに完了する
+                if self.decoder.is_complete() {
+                    self.finish();
+                }
+                if self.start {
+                    self.start_capture();
+                }
+            }
+            Msg::Forget(i) => {
+                if i < self.saved.len() {
+                    let t = self.saved.remove(i);
+                    if let Some(store) = &self.store {
+                        store.delete(&t.key);
+                    }
+                }
+            }
         }
         true
     }
@@ -270,45 +958,400 @@ impl Component for RecvPage {
         false
     }
 
+    fn rendered(&mut self, first_render: bool) {
+        if first_render {
+            let link = self.link.clone();
+            store::open(move |store| link.send_message(Msg::StoreOpened(store)));
+            let link = self.link.clone();
+            let link2 = self.link.clone();
+            self.workers = workers::spawn(
+                WORKER_POOL_SIZE,
+                Rc::new(move |i, detected| link.send_message(Msg::Detected(Some(i), detected))),
+                Rc::new(move || link2.send_message(Msg::WorkerFailed)),
+            );
+        }
+    }
+
     fn destroy(&mut self) {
         if self.timer_id > 0 {
             window().clear_timeout_with_handle(self.timer_id);
             self.timer_id = -1;
         }
+        self.capturing = false;
+        self.workers = None;
     }
 
     fn view(&self) -> Html {
         let onclick = self.link.callback(|_| Msg::Start);
         let onplay = self.link.callback(|_| Msg::VideoStart);
+        let onsave = self.link.callback(|_| Msg::SaveAnyway);
+        let onpassphrase = self
+            .link
+            .callback(|e: InputData| Msg::UpdatePassphrase(e.value));
+        let ondecrypt = self.link.callback(|_| Msg::Decrypt);
+        let onchange = self.link.batch_callback(|e: ChangeData| match e {
+            ChangeData::Select(element) => {
+                let v = element.value();
+                match element.id().as_str() {
+                    "binarize" => {
+                        return v
+                            .parse::<u8>()
+                            .ok()
+                            .and_then(Binarize::from_u8)
+                            .map(Msg::UpdateBinarize);
+                    }
+                    "source" => {
+                        return Some(Msg::UpdateSource(match v.as_str() {
+                            "1" => Source::Camera,
+                            "2" => Source::File,
+                            _ => Source::Screen,
+                        }));
+                    }
+                    "camera" => return Some(Msg::UpdateCamera(v)),
+                    "resolution" => {
+                        return v.parse().ok().map(Msg::UpdateCameraResolution);
+                    }
+                    "fps" => return v.parse().ok().map(Msg::UpdateFileFps),
+                    _ => {}
+                }
+                None
+            }
+            ChangeData::Files(files) => files.get(0).map(Msg::UpdateVideoFile),
+            _ => None,
+        });
+        let onloadedmetadata = self.link.callback(|_| Msg::FileLoaded);
+        let onseeked = self.link.callback(|_| Msg::FrameReady);
+        let camera = self.source == Source::Camera;
+        let file = self.source == Source::File;
+        let can_start = (!self.start || self.file_ended) && (!file || self.video_file.is_some());
         let msg = if !self.recv_ready {
             "スクリーンキャプチャ先のQRコードを認識できません。".to_string()
-        } else if self.received_bytes == 0 {
+        } else if self.decoder.received_bytes() == 0 {
             "送信側のデータ送出を待機中...".to_string()
         } else {
-            format!(
-                "{}% ({}/{}) 送受信QRコード数:{}",
-                (self.received_bytes as f32 / self.file_size as f32 * 100.0) as i32,
-                self.received_bytes,
-                self.file_size,
-                self.received.len()
-            )
+            let received = self.decoder.received_bytes();
+            let file_size = self.decoder.file_size;
+            let (done, total) = self.decoder.progress();
+            let percent = (received as f32 / file_size as f32 * 100.0) as i32;
+            if self.decoder.mode() == Mode::Fountain {
+                format!(
+                    "{}% ({}/{}) 復元ブロック数:{}/{} 受信シンボル数:{}",
+                    percent,
+                    received,
+                    file_size,
+                    done,
+                    total,
+                    self.decoder.received_symbols()
+                )
+            } else {
+                format!(
+                    "{}% ({}/{}) 受信ブロック数:{}/{}",
+                    percent, received, file_size, done, total
+                )
+            }
+        };
+        // 欠落ブロックを送信側に入力してもらうための再送コード
+        let (missing, resend_code) = match self.decoder.session() {
+            Some(session) if self.start && self.decoder.eof_seen => {
+                let missing = self.decoder.missing();
+                (
+                    format!(
+                        "欠落ブロック: {} (送信側で再送してください)",
+                        format_ranges(missing.iter().copied(), 10)
+                    ),
+                    resend::encode(session, missing.into_iter()),
+                )
+            }
+            _ => (String::new(), String::new()),
         };
         html! {
             <div class="recv-page">
                 <div>
-                    <button onclick={onclick} disabled={self.start}>{ "受信開始" }</button>
+                    <button onclick={onclick} disabled={!can_start}>{ "受信開始" }</button>
                 </div>
+                <div>
+                    <label for="source">{ "入力: " }</label>
+                    <select id="source" disabled={self.start} onchange={&onchange}>
+                        <option value="0" selected={ !camera }>{ "画面共有" }</option>
+                        <option value="1" selected={ camera }>{ "カメラ" }</option>
+                        <option value="2" selected={ file }>{ "動画ファイル" }</option>
+                    </select>
+                    {
+                        if camera {
+                            html!{
+                                <>
+                                    <select id="camera" disabled={self.start} onchange={&onchange}>
+                                        <option value="" selected={ self.camera_id.is_empty() }>{ "既定のカメラ" }</option>
+                                        {
+                                            for self.cameras.iter().enumerate().map(|(i, (id, label))| {
+                                                let label = if label.is_empty() { format!("カメラ{}", i + 1) } else { label.clone() };
+                                                html!{ <option value={ id.clone() } selected={ self.camera_id == *id }>{ label }</option> }
+                                            })
+                                        }
+                                    </select>
+                                    <select id="resolution" disabled={self.start} onchange={&onchange}>
+                                    {
+                                        for CAMERA_RESOLUTIONS.iter().enumerate().map(|(i, (w, h))| {
+                                            html!{ <option value={ i.to_string() } selected={ self.camera_resolution == i }>{ format!("{}x{}", w, h) }</option> }
+                                        })
+                                    }
+                                    </select>
+                                </>
+                            }
+                        } else if file {
+                            html!{
+                                <>
+                                    <input type="file" accept="video/*" disabled={self.start && !self.file_ended} onchange={&onchange} />
+                                    <label for="fps">{ " 読み取り間隔: " }</label>
+                                    <select id="fps" disabled={self.start && !self.file_ended} onchange={&onchange}>
+                                    {
+                                        for FILE_FRAME_RATES.iter().map(|fps| {
+                                            html!{ <option value={ fps.to_string() } selected={ self.file_fps == *fps }>{ format!("1/{}秒", fps) }</option> }
+                                        })
+                                    }
+                                    </select>
+                                </>
+                            }
+                        } else {
+                            html!{ <></> }
+                        }
+                    }
+                </div>
+                {
+                    if file && self.start {
+                        let status = if self.capturing {
+                            format!("動画を読み取り中: {:.1}/{:.1}秒", self.file_position, self.file_duration)
+                        } else if self.file_ended {
+                            "動画の最後まで読み取りましたが、受信は完了していません".to_string()
+                        } else {
+                            String::new()
+                        };
+                        html!{ <div>{ status }</div> }
+                    } else {
+                        html!{ <></> }
+                    }
+                }
                 {
-                    if !self.file_name.is_empty() {
-                        html!{ <div>{ self.file_name.clone() }</div> }
+                    if let Some(e) = &self.capture_error {
+                        html!{ <div class="corrupted">{ format!("キャプチャを開始できません: {}", e) }</div> }
                     } else {
                         html!{ <></> }
                     }
                 }
-                <div>{ if self.start { &msg } else { "" } }</div>
-                <video ref=self.video_element.clone() muted={true} autoplay="true" onplay={onplay} style="display: none" />
+                <div>
+                    <label for="binarize">{ "二値化: " }</label>
+                    <select id="binarize" onchange={&onchange}>
+                    {
+                        for Binarize::ALL.iter().map(|b| {
+                            html!{ <option value={ (*b as u8).to_string() } selected={ self.binarize == *b }>{ b.name() }</option> }
+                        })
+                    }
+                    </select>
+                </div>
+                {
+                    if !self.start && !self.saved.is_empty() {
+                        html!{
+                            <div class="saved">
+                                <div>{ "受信途中のファイルがあります:" }</div>
+                                {
+                                    for self.saved.iter().enumerate().map(|(i, t)| {
+                                        let onresume = self.link.callback(move |_| Msg::Resume(i));
+                                        let onforget = self.link.callback(move |_| Msg::Forget(i));
+                                        html!{
+                                            <div>
+                                                { format!("{} ({} bytes) ", t.file_name, t.file_size) }
+                                                <button onclick={onresume}>{ "受信を再開" }</button>
+                                                <button onclick={onforget}>{ "削除" }</button>
+                                            </div>
+                                        }
+                                    })
+                                }
+                            </div>
+                        }
+                    } else {
+                        html!{ <></> }
+                    }
+                }
+                {
+                    if !self.decoder.file_name.is_empty() {
+                        let decoder = &self.decoder;
+                        if decoder.codec != Codec::None {
+                            html!{ <div>{ format!("{} ({}圧縮 元のサイズ:{})", decoder.file_name, decoder.codec.name(), decoder.original_size) }</div> }
+                        } else {
+                            html!{ <div>{ decoder.file_name.clone() }</div> }
+                        }
+                    } else {
+                        html!{ <></> }
+                    }
+                }
+                {
+                    if self.calibration.is_started() {
+                        calibrate::results_view(&self.calibration)
+                    } else {
+                        html!{ <div>{ if self.start { &msg } else { "" } }</div> }
+                    }
+                }
+                {
+                    if self.capturing && self.decoder.has_metadata() {
+                        self.stats_view()
+                    } else {
+                        html!{ <></> }
+                    }
+                }
+                {
+                    if self.start && self.color_mode {
+                        html!{ <div>{ "RGB多重化を検出しました" }</div> }
+                    } else {
+                        html!{ <></> }
+                    }
+                }
+                <div class="corrupted">{ missing }</div>
+                {
+                    if !resend_code.is_empty() {
+                        html!{ <div>{ "再送コード: " }<span class="resend-code">{ resend_code }</span></div> }
+                    } else {
+                        html!{ <></> }
+                    }
+                }
+                {
+                    if let Some(v) = self.decoder.incompatible {
+                        html!{ <div class="corrupted">{ format!("未対応のプロトコルバージョン({})のQRコードを検出しました。受信側のページを更新してください。", v) }</div> }
+                    } else {
+                        html!{ <></> }
+                    }
+                }
+                {
+                    if let Some(size) = self.decoder.too_large {
+                        html!{ <div class="corrupted">{ format!("ファイルが大きすぎるため受信できません ({}バイト)", size) }</div> }
+                    } else {
+                        html!{ <></> }
+                    }
+                }
+                {
+                    if self.decoder.cipher.is_some() && self.verified.is_none() {
+                        html!{
+                            <div>
+                                <label for="passphrase">{ "パスフレーズ: " }</label>
+                                <input type="password" id="passphrase" value={self.passphrase.clone()} oninput={onpassphrase} />
+                                <button onclick={ondecrypt} disabled={!self.sealed}>{ "復号" }</button>
+                                {
+                                    if self.auth_failed {
+                                        html!{ <div class="corrupted">{ "復号できません。パスフレーズが違うか、データが破損しています" }</div> }
+                                    } else if !self.sealed {
+                                        html!{ <div>{ "暗号化されています。受信完了後に復号します" }</div> }
+                                    } else {
+                                        html!{ <></> }
+                                    }
+                                }
+                            </div>
+                        }
+                    } else {
+                        html!{ <></> }
+                    }
+                }
+                {
+                    match self.verified {
+                        // 旧形式の送信側はハッシュ値を載せていない
+                        Some(true) if self.decoder.legacy => html!{
+                            <div>
+                                { format!("SHA-256: {} (旧形式の送信のため未検証)", to_hex(&self.actual_digest)) }
+                            </div>
+                        },
+                        Some(true) => html!{
+                            <div class="verified">
+                                { format!("検証OK SHA-256: {}", to_hex(&self.actual_digest)) }
+                            </div>
+                        },
+                        Some(false) => html!{
+                            <div class="corrupted">
+                                <div>{ "破損を検出しました" }</div>
+                                {
+                                    if self.decoder.cipher.is_none() {
+                                        html!{
+                                            <>
+                                                <div>{ format!("期待値 SHA-256: {}", to_hex(&self.decoder.digest)) }</div>
+                                                <div>{ format!("受信値 SHA-256: {}", to_hex(&self.actual_digest)) }</div>
+                                            </>
+                                        }
+                                    } else {
+                                        html!{ <></> }
+                                    }
+                                }
+                                <button onclick={onsave}>{ "破損したまま保存する" }</button>
+                            </div>
+                        },
+                        None => html!{ <></> },
+                    }
+                }
+                // カメラの場合は向きを合わせられるようにプレビューを表示する
+                <video ref=self.video_element.clone() muted={true} playsinline="true" onplay={onplay}
+                    onloadedmetadata={onloadedmetadata} onseeked={onseeked}
+                    class="preview" style={ if camera && self.start { "" } else { "display: none" } } />
                 <canvas ref=self.canvas_element.clone() style="display: none" />
             </div>
         }
     }
 }
+
+// 同じファイルを同じブロックサイズで送っていれば、セッションが変わっても同じキーになる。
+// 暗号化されている場合は送信ごとに暗号文が変わるので、セッションIDも含める
+fn transfer_key(
+    digest: &[u8; DIGEST_SIZE],
+    file_size: u64,
+    block_size: usize,
+    session: Option<u32>,
+) -> String {
+    let mut key = format!("{}-{}-{}", to_hex(digest), file_size, block_size);
+    if let Some(session) = session {
+        key += &format!("-{:08x}", session);
+    }
+    key
+}
+
+fn to_saved_transfer(meta_frame: Vec<u8>) -> Option<SavedTransfer> {
+    let header = parse_header(&meta_frame).ok()?;
+    let mut meta = parse_metadata(&header, header.payload(&meta_frame))?;
+    let session = meta.extension(EXT_CIPHER).map(|_| header.session);
+    let original_size = match meta.extension(EXT_CODEC) {
+        Some(ext) => codec::parse_extension(ext)?.1,
+        None => meta.file_size,
+    };
+    Some(SavedTransfer {
+        key: transfer_key(&meta.digest, meta.file_size, header.size as usize, session),
+        file_name: TextDecoder::new()
+            .ok()?
+            .decode_with_u8_array(&mut meta.name)
+            .ok()?,
+        file_size: original_size,
+        meta_frame,
+    })
+}
+
+fn js_object(entries: &[(&str, JsValue)]) -> JsValue {
+    let obj = Object::new();
+    for (k, v) in entries {
+        let _ = Reflect::set(&obj, &JsValue::from_str(k), v);
+    }
+    obj.into()
+}
+
+// 値の列を、幅w・高さhのグラフの折れ線の座標にする。右端が最新で、最大値を上端に合わせる
+fn sparkline_points(values: &[f64], w: u32, h: u32) -> String {
+    let max = values.iter().cloned().fold(0.0, f64::max);
+    let step = w as f64 / (HISTORY_LEN - 1) as f64;
+    let offset = HISTORY_LEN.saturating_sub(values.len());
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let x = (offset + i) as f64 * step;
+            let y = if max > 0.0 {
+                (1.0 - v / max) * (h - 1) as f64
+            } else {
+                (h - 1) as f64
+            };
+            format!("{:.1},{:.1}", x, y + 0.5)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
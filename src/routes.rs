@@ -7,6 +7,8 @@ pub enum AppRoute {
     Send,
     #[to = "#/receive"]
     Receive,
+    #[to = "#/calibrate"]
+    Calibrate,
     #[to = "#/"]
     Home,
 }
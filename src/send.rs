@@ -1,150 +1,259 @@
-use js_sys::{ArrayBuffer, Uint8Array};
-use qrcode::{Color, EcLevel, QrCode, Version};
-use serde::Serialize;
+use js_sys::{ArrayBuffer, Date, Reflect, Uint8Array};
+use qrcode::{EcLevel, Version};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::{JsCast, JsValue};
 use web_sys::{
-    Blob, File, FileReader, HtmlCanvasElement, HtmlElement, HtmlInputElement, InputEvent,
-    TextEncoder,
+    ContextAttributes2d, File, FileReader, HtmlCanvasElement, HtmlElement, HtmlInputElement,
+    InputEvent, TextEncoder,
 };
 use yew::prelude::*;
+use yew::services::render::{RenderService, RenderTask};
 use yew::utils::window;
 
-use crate::header::{build_header, Header, HEADER_SIZE};
+use rds_filetransfer_core::calibration::Recommendation;
+use rds_filetransfer_core::capacity::{block_size_of, usable_version, MIN_BLOCK_SIZE};
+use rds_filetransfer_core::header::{build_header, FrameType, Header, Mode};
+use rds_filetransfer_core::layout::{self, cell_color, Screen};
+use rds_filetransfer_core::pacing::Pacer;
+use rds_filetransfer_core::resend::{self, CodeError};
+use rds_filetransfer_core::{Encoder, Preparer, Settings, Tile};
 
-const DEFAULT_VERSION: Version = Version::Normal(40);
-const DEFAULT_INTERVAL: u16 = 500;
-const DEFAULT_EC_LEVEL: EcLevel = EcLevel::L;
-const DEFAULT_PIXEL_SIZE: u8 = 5;
-const EC_LEVEL_TABLE: [&str; 4] = ["L", "M", "Q", "H"];
+use crate::util::{error_message, format_seconds};
 
-pub struct SendPage {
+pub const DEFAULT_VERSION: Version = Version::Normal(40);
+pub const DEFAULT_INTERVAL: u16 = 500;
+pub const DEFAULT_EC_LEVEL: EcLevel = EcLevel::L;
+pub const DEFAULT_PIXEL_SIZE: u8 = 5;
+pub const DEFAULT_META_INTERVAL: u16 = 20;
+pub const MIN_PIXEL_SIZE: u8 = 3;
+pub const MAX_PIXEL_SIZE: u8 = 16;
+pub const MAX_GRID_SIZE: u8 = 4;
+// ファイルは一度にすべて読み込まず、この大きさずつ読み込む
+const READ_SIZE: u64 = 1024 * 1024;
+// 送信するデータをすべてメモリに置く方式で扱えるファイルの大きさ
+const MAX_BUFFERED_SIZE: f64 = 1024.0 * 1024.0 * 1024.0;
+pub const EC_LEVEL_TABLE: [&str; 4] = ["L", "M", "Q", "H"];
+const MODE_TABLE: [(Mode, &str); 3] = [
+    (Mode::Sequential, "逐次"),
+    (Mode::Fountain, "ファウンテン符号"),
+    (Mode::Loop, "繰り返し"),
+];
+
+// QRコードの画面をcanvasに描く。塗りつぶす色は描くたびに作らないようにしておく
+pub struct Painter {
     scale: f64,
-    link: ComponentLink<SendPage>,
-    version: Version,
-    ec_level: EcLevel,
-    block_size: u16,
-    send_interval: u16,
-    pixel_size: u8,
-    data: Vec<u8>,
-    canvas: NodeRef,
-    file: Option<File>,
-    file_inflight: Option<Blob>,
-    timeout_id: i32,
-    read_offset: u64,
-    cache_context_attrs: JsValue,
-    cache_black_str: JsValue,
-    cache_white_str: JsValue,
+    context_attrs: JsValue,
+    // [Rが暗 | Gが暗 << 1 | Bが暗 << 2]
+    color_strs: Vec<JsValue>,
+    white_str: JsValue,
 }
 
-#[derive(Debug)]
-pub enum Msg {
-    Start(File),
-    LoadFile(FileReader, ArrayBuffer),
-    Final,
-    UpdateVersion(Version),
-    UpdateECLevel(EcLevel),
-    UpdateInterval(u16),
-    UpdateCellSize(u8),
-}
+impl Painter {
+    pub fn new() -> Self {
+        let mut context_attrs = ContextAttributes2d::new();
+        context_attrs.alpha(false);
+        Self {
+            scale: window().device_pixel_ratio(),
+            context_attrs: context_attrs.into(),
+            color_strs: (0..8)
+                .map(|mask| {
+                    let [r, g, b] = cell_color(mask);
+                    JsValue::from_str(&format!("rgb({},{},{})", r, g, b))
+                })
+                .collect(),
+            white_str: JsValue::from_str("white"),
+        }
+    }
 
-impl SendPage {
-    fn render_qrcode(&self) -> Result<(), ()> {
-        let canvas = self.canvas.cast::<HtmlCanvasElement>().ok_or(())?;
+    // セルあたりpixel_size[CSSピクセル]で描く
+    pub fn draw(&self, canvas: &NodeRef, screen: &Screen, pixel_size: u8) -> Result<(), ()> {
+        let canvas = canvas.cast::<HtmlCanvasElement>().ok_or(())?;
         let context = canvas
-            .get_context_with_context_options("2d", &self.cache_context_attrs)
+            .get_context_with_context_options("2d", &self.context_attrs)
             .map_err(|_| ())?
             .ok_or(())?
             .dyn_into::<web_sys::CanvasRenderingContext2d>()
             .map_err(|_| ())?;
-        let code =
-            QrCode::with_error_correction_level(&self.data, self.ec_level).map_err(|_| ())?;
-        let colors = code.to_colors();
-        let size = (colors.len() as f64).sqrt() as u32;
-        let canvas_size = size * self.pixel_size as u32;
-        let rect_size = self.pixel_size as f64;
-        let scaled_canvas_size = (canvas_size as f64 * self.scale) as u32;
+        let canvas_width = screen.width * pixel_size as u32;
+        let canvas_height = screen.height * pixel_size as u32;
+        let rect_size = pixel_size as f64;
 
         canvas
             .style()
-            .set_property("height", format!("{}px", canvas_size).as_ref())
+            .set_property("height", format!("{}px", canvas_height).as_ref())
             .unwrap();
-        canvas.set_height(scaled_canvas_size);
+        canvas.set_height((canvas_height as f64 * self.scale) as u32);
         canvas
             .style()
-            .set_property("width", format!("{}px", canvas_size).as_ref())
+            .set_property("width", format!("{}px", canvas_width).as_ref())
             .unwrap();
-        canvas.set_width(scaled_canvas_size);
+        canvas.set_width((canvas_width as f64 * self.scale) as u32);
         context.scale(self.scale, self.scale).unwrap();
         context.set_image_smoothing_enabled(false);
 
-        context.set_fill_style(&self.cache_white_str);
-        context.fill_rect(0.0, 0.0, canvas_size as f64, canvas_size as f64);
-        context.set_fill_style(&self.cache_black_str);
+        context.set_fill_style(&self.white_str);
+        context.fill_rect(0.0, 0.0, canvas_width as f64, canvas_height as f64);
 
-        for y in 0..size {
-            for x in 0..size {
-                if colors[(y * size + x) as usize] == Color::Light {
-                    continue;
+        for mask in 1..self.color_strs.len() {
+            context.set_fill_style(&self.color_strs[mask]);
+            for y in 0..screen.height {
+                for x in 0..screen.width {
+                    if screen.cells[(y * screen.width + x) as usize] as usize != mask {
+                        continue;
+                    }
+                    context.fill_rect(
+                        x as f64 * rect_size,
+                        y as f64 * rect_size,
+                        rect_size,
+                        rect_size,
+                    );
                 }
-                context.fill_rect(
-                    x as f64 * rect_size,
-                    y as f64 * rect_size,
-                    rect_size,
-                    rect_size,
-                );
             }
         }
 
         Ok(())
     }
+}
+
+pub struct SendPage {
+    link: ComponentLink<SendPage>,
+    version: Version,
+    ec_level: EcLevel,
+    block_size: u16,
+    send_interval: u16,
+    meta_interval: u16,
+    pixel_size: u8,
+    grid_rows: u8,
+    grid_cols: u8,
+    // R, G, Bの各チャンネルに別々のQRコードを重ねる
+    color: bool,
+    mode: Mode,
+    compression: bool,
+    passphrase: String,
+    // 1画面に表示するフレーム。グリッドの左上から順に並べ、カラーの場合は3フレームずつR, G, Bに割り当てる
+    tiles: Vec<Vec<u8>>,
+    canvas: NodeRef,
+    file: Option<File>,
+    // ファイルを少しずつ読み込む。中止した場合は読み込みも中断する
+    reader: Option<FileReader>,
+    // 読み込みの完了を待っている範囲の先頭
+    reading: Option<u64>,
+    read_error: Option<String>,
+    // 送信前にファイルを先頭から読み込みながら、ハッシュ値を計算して圧縮・暗号化する
+    preparer: Option<Preparer>,
+    loaded: u64,
+    encoder: Option<Encoder>,
+    // 元のファイルのまま送る場合に、最後に読み込んだ範囲の先頭とその内容
+    window: (u64, Vec<u8>),
+    // ブロックを読み込み終えていない次の画面
+    planned: Option<Planned>,
+    // 次に表示する画面のフレームと、その後も続けるか
+    next_screen: Option<(Vec<Vec<u8>>, bool)>,
+    // 次のリフレッシュで呼ばれるコールバック。破棄すると取り消される
+    render_task: Option<RenderTask>,
+    pacer: Pacer,
+    running: bool,
+    // 一時停止中。逐次送信を送り終えた場合はfalseのまま止まる
+    paused: bool,
+    // 一時停止していた時間を除いた送信時間 [ms]。送出中は最後に再開した時刻からの時間を足す
+    elapsed_ms: f64,
+    resumed_at: f64,
+    resend_code: String,
+    resend_error: Option<String>,
+    // キャリブレーションで表示された設定コード
+    settings_code: String,
+    settings_error: Option<String>,
+    painter: Painter,
+}
+
+// 次の画面のフレームを先頭から順に揃えていく
+struct Planned {
+    tiles: Vec<Tile>,
+    frames: Vec<Vec<u8>>,
+    keep_going: bool,
+}
+
+#[derive(Debug)]
+pub enum Msg {
+    Start(File),
+    LoadFile(ArrayBuffer),
+    ReadFailed(String),
+    Frame(f64),
+    Pause,
+    Resume,
+    Cancel,
+    UpdateVersion(Version),
+    UpdateECLevel(EcLevel),
+    UpdateInterval(u16),
+    UpdateMetaInterval(u16),
+    UpdateCellSize(u8),
+    UpdateGridRows(u8),
+    UpdateGridCols(u8),
+    UpdateColor(bool),
+    UpdateMode(Mode),
+    UpdateCompression(bool),
+    UpdatePassphrase(String),
+    UpdateResendCode(String),
+    Resend,
+    UpdateSettingsCode(String),
+    ApplySettings,
+}
+
+impl SendPage {
+    fn render_qrcode(&self) -> Result<(), ()> {
+        let screen = layout::layout(
+            &self.tiles,
+            self.ec_level,
+            self.grid_rows,
+            self.grid_cols,
+            self.channels(),
+        )
+        .ok_or(())?;
+        self.painter.draw(&self.canvas, &screen, self.pixel_size)
+    }
 
     fn start(&mut self, f: File) {
-        let utf8_encoder = TextEncoder::new().unwrap();
-        let utf8_name = utf8_encoder.encode_with_input(f.name().as_ref());
-        let file_size = f.size() as u64;
+        self.read_error = None;
+        let size = f.size();
+        if size > MAX_BUFFERED_SIZE && (self.mode == Mode::Fountain || !self.passphrase.is_empty())
+        {
+            self.read_error =
+                Some("ファウンテン符号と暗号化は1GiBまでのファイルで使えます".to_string());
+            return;
+        }
+        let settings = Settings {
+            block_size: self.block_size,
+            mode: self.mode,
+            meta_interval: self.meta_interval,
+            // 圧縮したデータはメモリに置くので、大きなファイルは圧縮しない
+            compression: self.compression && size <= MAX_BUFFERED_SIZE,
+        };
         let reader = FileReader::new().unwrap();
-        self.file = Some(f);
-        self.read_offset = 0;
         self.setup_reader_callback(&reader);
+        self.reader = Some(reader);
+        self.file = Some(f);
+        self.preparer = Some(Preparer::new(&settings, &self.passphrase, false));
+        self.loaded = 0;
+        self.read_slice(0);
+    }
 
-        // 最初のフレームは、ファイルサイズ(8B) + ファイル名(UTF8)
-        self.data.fill(0);
-        build_header(
-            Header {
-                seq: 0,
-                size: self.block_size - HEADER_SIZE as u16,
-            },
-            &mut self.data[..],
-        );
-        for i in 0..8 {
-            self.data[HEADER_SIZE + i] = ((file_size >> (i * 8)) & 0xff) as u8;
+    // ファイルのoffsetから読み込む。読み込み終わるとMsg::LoadFileが届く
+    fn read_slice(&mut self, offset: u64) {
+        let (file, reader) = match (&self.file, &self.reader) {
+            (Some(f), Some(r)) => (f, r),
+            _ => return,
+        };
+        let end = ((offset + READ_SIZE) as f64).min(file.size());
+        let result = file
+            .slice_with_f64_and_f64(offset as f64, end)
+            .and_then(|blob| reader.read_as_array_buffer(&blob));
+        match result {
+            Ok(()) => self.reading = Some(offset),
+            Err(e) => {
+                self.cancel();
+                self.read_error = Some(format!("ファイルを読み込めません: {}", error_message(&e)));
+            }
         }
-        self.data[HEADER_SIZE + 8..HEADER_SIZE + 8 + utf8_name.len()].copy_from_slice(&utf8_name);
-        self.render_qrcode().unwrap();
-
-        // interval[ms]後にファイル読み込みを実施するようにスケジュール
-        let read_size = (self.block_size - HEADER_SIZE as u16) as f64;
-        self.file_inflight = Some(
-            self.file
-                .as_ref()
-                .unwrap()
-                .slice_with_f64_and_f64(0.0, read_size)
-                .unwrap(),
-        );
-        let file_inflight2 = self.file_inflight.clone();
-        let cb = Closure::wrap(Box::new(move || {
-            reader
-                .read_as_array_buffer(file_inflight2.as_ref().unwrap())
-                .unwrap();
-        }) as Box<dyn Fn()>);
-        window()
-            .set_timeout_with_callback_and_timeout_and_arguments_0(
-                cb.as_ref().unchecked_ref(),
-                self.send_interval as i32,
-            )
-            .unwrap();
-        cb.forget();
     }
 
     fn setup_reader_callback(&self, reader: &FileReader) {
@@ -155,30 +264,329 @@ impl SendPage {
                 return;
             }
             if let Ok(buf) = reader2.result().unwrap().dyn_into::<ArrayBuffer>() {
-                if buf.byte_length() == 0 {
-                    link2.send_message(Msg::Final);
-                    return;
-                }
-                link2.send_message(Msg::LoadFile(reader2.clone(), buf));
+                link2.send_message(Msg::LoadFile(buf));
             }
         }) as Box<dyn Fn()>);
         reader.set_onload(Some(cb.as_ref().unchecked_ref()));
         cb.forget();
+        let reader2 = reader.clone();
+        let link2 = self.link.clone();
+        let on_error = Closure::wrap(Box::new(move || {
+            let e = Reflect::get(&reader2, &JsValue::from_str("error")).unwrap_or(JsValue::NULL);
+            link2.send_message(Msg::ReadFailed(error_message(&e)));
+        }) as Box<dyn Fn()>);
+        reader.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+        on_error.forget();
+    }
+
+    // 読み込んだ部分を圧縮・暗号化に回して続きを読み込む。最後まで読み込んだら送信を始める
+    fn load_file(&mut self, data: Vec<u8>) {
+        let preparer = match self.preparer.as_mut() {
+            Some(p) => p,
+            None => return,
+        };
+        preparer.push(&data);
+        self.loaded += data.len() as u64;
+        let file = self.file.as_ref().unwrap();
+        if self.loaded < file.size() as u64 && !data.is_empty() {
+            self.read_slice(self.loaded);
+            return;
+        }
+        let utf8_encoder = TextEncoder::new().unwrap();
+        let utf8_name = utf8_encoder.encode_with_input(file.name().as_ref());
+        // 他の送信と混ざらないように、送信ごとにセッションIDを振る
+        let session = (js_sys::Math::random() * u32::MAX as f64) as u32 | 1;
+        let encoder = self.preparer.take().unwrap().finish(&utf8_name, session);
+        self.tiles = vec![encoder.meta_frame().to_vec(); self.num_tiles()];
+        self.encoder = Some(encoder);
+        self.render_qrcode().unwrap();
+        self.elapsed_ms = 0.0;
+        self.resume();
+    }
+
+    // 次の画面のフレームを用意する。元のファイルのまま送るブロックは読み込んでから揃う
+    fn prepare_next(&mut self) {
+        let num_tiles = self.num_tiles();
+        let encoder = match self.encoder.as_mut() {
+            Some(e) => e,
+            None => return,
+        };
+        if encoder.is_buffered() {
+            self.next_screen = Some(encoder.next_tiles(num_tiles));
+            return;
+        }
+        let (tiles, keep_going) = encoder.plan_tiles(num_tiles);
+        self.planned = Some(Planned {
+            frames: Vec::with_capacity(tiles.len()),
+            tiles,
+            keep_going,
+        });
+        self.resolve_planned();
+    }
+
+    // 読み込んだ範囲にあるブロックからフレームにしていく。範囲外のブロックがあれば、そこから先を読み込む
+    fn resolve_planned(&mut self) {
+        let (mut planned, encoder) = match (self.planned.take(), &self.encoder) {
+            (Some(p), Some(e)) => (p, e),
+            _ => return,
+        };
+        let (start, window) = (self.window.0, &self.window.1);
+        while planned.frames.len() < planned.tiles.len() {
+            let frame = match &planned.tiles[planned.frames.len()] {
+                Tile::Frame(frame) => frame.clone(),
+                &Tile::Block { seq, offset, len } => {
+                    let end = offset + len as u64;
+                    if offset < start || end > start + window.len() as u64 {
+                        self.planned = Some(planned);
+                        self.read_slice(offset);
+                        return;
+                    }
+                    encoder.block_frame(
+                        seq,
+                        &window[(offset - start) as usize..(end - start) as usize],
+                    )
+                }
+            };
+            planned.frames.push(frame);
+        }
+        self.next_screen = Some((planned.frames, planned.keep_going));
+    }
+
+    // 次のリフレッシュで画面を切り替えるか判断する
+    fn request_frame(&mut self) {
+        self.render_task = Some(RenderService::request_animation_frame(
+            self.link.callback(Msg::Frame),
+        ));
+    }
+
+    // 送出を始める。表示中の画面は送信間隔の間そのまま表示する
+    fn resume(&mut self) {
+        if self.running {
+            return;
+        }
+        self.running = true;
+        self.resumed_at = Date::now();
+        self.pacer.set_interval(self.send_interval);
+        self.pacer.restart();
+        if self.next_screen.is_none() && self.planned.is_none() {
+            self.prepare_next();
+        }
+        self.request_frame();
+    }
+
+    // 送出を止める。止めている間は送信時間に数えない
+    fn halt(&mut self) {
+        self.render_task = None;
+        if self.running {
+            self.elapsed_ms += Date::now() - self.resumed_at;
+        }
+        self.running = false;
+    }
+
+    // 送信を中止して、ファイルを選ぶ前の状態に戻す
+    fn cancel(&mut self) {
+        self.halt();
+        if let Some(reader) = self.reader.take() {
+            reader.abort();
+        }
+        self.reading = None;
+        self.file = None;
+        self.preparer = None;
+        self.encoder = None;
+        self.window = (0, Vec::new());
+        self.planned = None;
+        self.next_screen = None;
+        self.paused = false;
+        self.resend_code.clear();
+        self.resend_error = None;
+        self.update_block_size();
+    }
+
+    // 送信時間 [秒]
+    fn elapsed(&self) -> f64 {
+        let running = if self.running {
+            Date::now() - self.resumed_at
+        } else {
+            0.0
+        };
+        (self.elapsed_ms + running) / 1000.0
+    }
+
+    fn channels(&self) -> usize {
+        if self.color {
+            3
+        } else {
+            1
+        }
+    }
+
+    // 1画面に表示するフレーム数
+    fn num_tiles(&self) -> usize {
+        self.grid_rows as usize * self.grid_cols as usize * self.channels()
+    }
+
+    // グリッドの各位置に別々のフレームを並べる。終端に達した場合は残りを空けておく
+    fn send_next(&mut self) {
+        let (tiles, keep_going) = match self.next_screen.take() {
+            Some(v) => v,
+            None => return,
+        };
+        self.tiles = tiles;
+        self.render_qrcode().unwrap();
+        if keep_going {
+            self.prepare_next();
+            self.request_frame();
+        } else {
+            self.halt();
+        }
+    }
+
+    // 受信側に表示された再送コードのブロックを送信キューに積む
+    fn resend(&mut self) {
+        let (session, ranges) = match resend::decode(&self.resend_code) {
+            Ok(v) => v,
+            Err(CodeError::InvalidChar(c)) => {
+                self.resend_error =
+                    Some(format!("再送コードに使えない文字が含まれています: {}", c));
+                return;
+            }
+            Err(CodeError::Checksum) => {
+                self.resend_error = Some("再送コードが正しくありません".to_string());
+                return;
+            }
+        };
+        let encoder = match self.encoder.as_mut() {
+            Some(s) => s,
+            None => return,
+        };
+        if session != encoder.session() as u16 {
+            self.resend_error = Some("別の送信の再送コードです".to_string());
+            return;
+        }
+        encoder.queue_resend(&ranges);
+        self.resend_code.clear();
+        self.resend_error = None;
+        // 送信が終わっていれば再開する。最後に終端を送り直すので受信側は残りの欠落を再表示できる
+        if !self.paused {
+            self.resume();
+        }
+    }
+
+    // キャリブレーションで測った設定にする
+    fn apply_settings(&mut self) {
+        let r = match Recommendation::decode(&self.settings_code) {
+            Ok(r) => r,
+            Err(CodeError::InvalidChar(c)) => {
+                self.settings_error =
+                    Some(format!("設定コードに使えない文字が含まれています: {}", c));
+                return;
+            }
+            Err(CodeError::Checksum) => {
+                self.settings_error = Some("設定コードが正しくありません".to_string());
+                return;
+            }
+        };
+        self.version = Version::Normal(r.version);
+        self.ec_level = r.ec_level;
+        self.pixel_size = r.pixel_size.clamp(MIN_PIXEL_SIZE, MAX_PIXEL_SIZE);
+        self.send_interval = r.interval;
+        self.settings_code.clear();
+        self.settings_error = None;
+        self.update_block_size();
     }
 
     fn update_block_size_only(&mut self) {
-        let v_idx = match self.version {
-            Version::Normal(v) => (v - 1) as usize,
+        let v = match self.version {
+            Version::Normal(v) => v,
             _ => return,
         };
-        self.block_size = BINARY_SIZE_TABLE[v_idx][self.ec_level as usize];
-        self.data.resize(self.block_size as usize, 0);
+        // メタデータが1フレームに収まらないバージョンは使えない
+        let v = match usable_version(v, self.ec_level) {
+            Some(v) => v,
+            None => return,
+        };
+        self.version = Version::Normal(v);
+        self.block_size = block_size_of(v, self.ec_level);
+
+        // 送信開始前は待機中を表すフレームを表示する
+        let mut idle = vec![0; self.block_size as usize];
+        build_header(Header::new(FrameType::Control, 0, 0, 0), &mut idle[..]);
+        self.tiles = vec![idle; self.num_tiles()];
     }
 
     fn update_block_size(&mut self) {
         self.update_block_size_only();
         self.render_qrcode().unwrap();
     }
+
+    // 送信の進み具合と経過時間、残り時間
+    fn progress_view(&self) -> Html {
+        let encoder = match &self.encoder {
+            Some(e) => e,
+            None => {
+                let size = self.file.as_ref().map_or(0.0, |f| f.size());
+                let percent = (self.loaded as f64 * 100.0 / size.max(1.0)).min(100.0);
+                return html! { <div class="progress">{ format!("ファイルを読み込み中... {:.0}%", percent) }</div> };
+            }
+        };
+        let total = encoder.num_blocks();
+        let sent = encoder.sent_blocks();
+        let state = if self.paused {
+            "一時停止中"
+        } else if self.running {
+            "送信中"
+        } else {
+            "送信完了"
+        };
+        let mode = encoder.mode();
+        // 残りのフレーム数。ファウンテン符号は受信側が揃うまで送り続けるので求めない
+        let (text, remaining) = match mode {
+            Mode::Fountain => (format!("シンボル {} (ブロック数 {})", sent, total), None),
+            Mode::Loop => (
+                format!("ブロック {}/{} (繰り返し)", sent, total),
+                Some(total - sent),
+            ),
+            Mode::Sequential => {
+                let queued = encoder.queued_resends() as u64;
+                let resends = if queued > 0 {
+                    format!(" 再送待ち {}", queued)
+                } else {
+                    String::new()
+                };
+                // 最後にメタデータと終端のフレームを送る
+                let remaining = Some(total - sent + queued + 2).filter(|_| self.running);
+                (format!("ブロック {}/{}{}", sent, total, resends), remaining)
+            }
+        };
+        let eta = match remaining {
+            Some(n) => {
+                let screens = n.div_ceil(self.num_tiles() as u64);
+                let seconds = screens as f64 * self.pacer.effective_interval() / 1000.0;
+                if mode == Mode::Loop {
+                    format!(" この周の残り約{}", format_seconds(seconds))
+                } else {
+                    format!(" 残り約{}", format_seconds(seconds))
+                }
+            }
+            None => String::new(),
+        };
+        // 送信間隔はリフレッシュの整数倍に丸めるので、実際に表示した時間も示す
+        let displayed = match self.pacer.displayed_ms() {
+            Some(ms) => format!(
+                " 表示時間 約{:.0}ms (リフレッシュ{}回 × {:.1}ms)",
+                ms,
+                self.pacer.hold(),
+                self.pacer.refresh_ms()
+            ),
+            None => String::new(),
+        };
+        html! {
+            <div class="progress">
+                <progress max={ total.max(1).to_string() } value={ sent.min(total).to_string() } />
+                { format!(" {} {} 経過 {}{}{}", state, text, format_seconds(self.elapsed()), eta, displayed) }
+            </div>
+        }
+    }
 }
 
 impl Component for SendPage {
@@ -186,25 +594,43 @@ impl Component for SendPage {
     type Properties = ();
 
     fn create(_props: Self::Properties, link: ComponentLink<Self>) -> Self {
-        let attrs = Context2DAttributes { alpha: false };
-        let context_attrs = JsValue::from_serde(&attrs).unwrap();
         let mut ret = Self {
-            scale: window().device_pixel_ratio(),
             link,
             version: DEFAULT_VERSION,
             ec_level: DEFAULT_EC_LEVEL,
             block_size: 0,
             send_interval: DEFAULT_INTERVAL,
+            meta_interval: DEFAULT_META_INTERVAL,
             pixel_size: DEFAULT_PIXEL_SIZE,
-            data: Vec::new(),
+            grid_rows: 1,
+            grid_cols: 1,
+            color: false,
+            mode: Mode::Sequential,
+            compression: true,
+            passphrase: String::new(),
+            tiles: Vec::new(),
             canvas: NodeRef::default(),
             file: None,
-            file_inflight: None,
-            timeout_id: -1,
-            read_offset: 0,
-            cache_context_attrs: context_attrs,
-            cache_black_str: JsValue::from_str("black"),
-            cache_white_str: JsValue::from_str("white"),
+            reader: None,
+            reading: None,
+            read_error: None,
+            preparer: None,
+            loaded: 0,
+            encoder: None,
+            window: (0, Vec::new()),
+            planned: None,
+            next_screen: None,
+            render_task: None,
+            pacer: Pacer::new(DEFAULT_INTERVAL),
+            running: false,
+            paused: false,
+            elapsed_ms: 0.0,
+            resumed_at: 0.0,
+            resend_code: String::new(),
+            resend_error: None,
+            settings_code: String::new(),
+            settings_error: None,
+            painter: Painter::new(),
         };
         ret.update_block_size_only();
         ret
@@ -215,60 +641,49 @@ impl Component for SendPage {
             Msg::Start(f) => {
                 self.start(f);
             }
-            Msg::LoadFile(reader, buf) => {
-                let read_size = buf.byte_length();
-                if read_size == 0 {
-                    self.file_inflight = None;
-                    return true;
+            Msg::LoadFile(buf) => {
+                // 読み込み中に中止した場合は使わない
+                let offset = match self.reading.take() {
+                    Some(offset) => offset,
+                    None => return false,
+                };
+                let data = Uint8Array::new(&buf).to_vec();
+                if self.preparer.is_some() {
+                    self.load_file(data);
+                } else {
+                    self.window = (offset, data);
+                    self.resolve_planned();
+                    return false;
                 }
-                let seq =
-                    1 + (self.read_offset / (self.block_size - HEADER_SIZE as u16) as u64) as u32;
-                self.read_offset += read_size as u64;
-                self.file_inflight = Some(
-                    self.file
-                        .as_ref()
-                        .unwrap()
-                        .slice_with_f64_and_f64(
-                            self.read_offset as f64,
-                            (self.read_offset + (self.block_size - HEADER_SIZE as u16) as u64)
-                                as f64,
-                        )
-                        .unwrap(),
-                );
-
-                Uint8Array::new(&buf)
-                    .copy_to(&mut self.data[HEADER_SIZE..HEADER_SIZE + read_size as usize]);
-                build_header(
-                    Header {
-                        seq,
-                        size: read_size as u16,
-                    },
-                    &mut self.data[..],
-                );
-                self.render_qrcode().unwrap();
-
-                let file_inflight2 = self.file_inflight.clone();
-                let cb = Closure::wrap(Box::new(move || {
-                    reader
-                        .read_as_array_buffer(file_inflight2.as_ref().unwrap())
-                        .unwrap();
-                }) as Box<dyn Fn()>);
-                window()
-                    .set_timeout_with_callback_and_timeout_and_arguments_0(
-                        cb.as_ref().unchecked_ref(),
-                        self.send_interval as i32,
-                    )
-                    .unwrap();
-                cb.forget();
             }
-            Msg::Final => {
-                let seq =
-                    (self.read_offset / (self.block_size - HEADER_SIZE as u16) as u64) as u32 + 1;
-                self.data.clear();
-                self.data.resize(self.data.capacity(), 0);
-                build_header(Header { seq, size: 0 }, &mut self.data[..]);
-                self.render_qrcode().unwrap();
+            Msg::ReadFailed(e) => {
+                if self.reading.is_none() {
+                    return false;
+                }
+                self.cancel();
+                self.read_error = Some(format!("ファイルを読み込めません: {}", e));
+            }
+            Msg::Frame(now) => {
+                if !self.running {
+                    return false;
+                }
+                // 表示中の画面を表示し終えるまでは描き直さない。
+                // 次の画面のブロックを読み込み中であれば、読み込み終えてから切り替える
+                if self.next_screen.is_none() || !self.pacer.tick(now) {
+                    self.request_frame();
+                    return false;
+                }
+                self.send_next();
+            }
+            Msg::Pause => {
+                self.halt();
+                self.paused = true;
+            }
+            Msg::Resume => {
+                self.paused = false;
+                self.resume();
             }
+            Msg::Cancel => self.cancel(),
             Msg::UpdateVersion(v) => {
                 self.version = v;
                 self.update_block_size();
@@ -281,7 +696,27 @@ impl Component for SendPage {
                 self.pixel_size = v;
                 self.render_qrcode().unwrap();
             }
+            Msg::UpdateGridRows(v) => {
+                self.grid_rows = v;
+                self.update_block_size();
+            }
+            Msg::UpdateGridCols(v) => {
+                self.grid_cols = v;
+                self.update_block_size();
+            }
+            Msg::UpdateColor(v) => {
+                self.color = v;
+                self.update_block_size();
+            }
             Msg::UpdateInterval(v) => self.send_interval = v,
+            Msg::UpdateMetaInterval(v) => self.meta_interval = v,
+            Msg::UpdateMode(v) => self.mode = v,
+            Msg::UpdateCompression(v) => self.compression = v,
+            Msg::UpdatePassphrase(v) => self.passphrase = v,
+            Msg::UpdateResendCode(v) => self.resend_code = v,
+            Msg::Resend => self.resend(),
+            Msg::UpdateSettingsCode(v) => self.settings_code = v,
+            Msg::ApplySettings => self.apply_settings(),
         }
         true
     }
@@ -297,19 +732,27 @@ impl Component for SendPage {
     }
 
     fn destroy(&mut self) {
-        if self.timeout_id > 0 {
-            window().clear_timeout_with_handle(self.timeout_id);
-            self.timeout_id = -1;
+        self.halt();
+        if let Some(reader) = self.reader.take() {
+            reader.abort();
         }
     }
 
     fn view(&self) -> Html {
         let send_interval = self.send_interval;
+        let meta_interval = self.meta_interval;
         let oninput = self.link.batch_callback(move |e: InputData| {
             let v = e.value.parse::<u16>();
             if let Some(id) = get_event_target_element_id(e.event) {
-                if id == "interval" {
-                    return Some(Msg::UpdateInterval(v.unwrap_or(send_interval)));
+                match id.as_str() {
+                    "interval" => return Some(Msg::UpdateInterval(v.unwrap_or(send_interval))),
+                    "meta-interval" => {
+                        return Some(Msg::UpdateMetaInterval(v.unwrap_or(meta_interval)))
+                    }
+                    "resend-code" => return Some(Msg::UpdateResendCode(e.value)),
+                    "settings-code" => return Some(Msg::UpdateSettingsCode(e.value)),
+                    "passphrase" => return Some(Msg::UpdatePassphrase(e.value)),
+                    _ => {}
                 }
             }
             None
@@ -329,6 +772,23 @@ impl Component for SendPage {
                     "pixel" => {
                         return Some(Msg::UpdateCellSize(v as u8));
                     }
+                    "grid-rows" => {
+                        return Some(Msg::UpdateGridRows(v as u8));
+                    }
+                    "grid-cols" => {
+                        return Some(Msg::UpdateGridCols(v as u8));
+                    }
+                    "color" => {
+                        return Some(Msg::UpdateColor(v == 1));
+                    }
+                    "mode" => {
+                        if let Some(m) = Mode::from_u8(v as u8) {
+                            return Some(Msg::UpdateMode(m));
+                        }
+                    }
+                    "compression" => {
+                        return Some(Msg::UpdateCompression(v == 0));
+                    }
                     _ => {}
                 }
             }
@@ -339,6 +799,8 @@ impl Component for SendPage {
                 if let Some(element) = tgt.dyn_ref::<HtmlInputElement>() {
                     if let Some(files) = element.files() {
                         if let Some(f) = files.item(0) {
+                            // 中止した後に同じファイルを選び直せるように選択を空にしておく
+                            element.set_value("");
                             return Some(Msg::Start(f));
                         }
                     }
@@ -346,7 +808,16 @@ impl Component for SendPage {
             }
             None
         });
+        let onresend = self.link.callback(|_| Msg::Resend);
+        let onapply = self.link.callback(|_| Msg::ApplySettings);
+        let onpause = self.link.callback(|_| Msg::Pause);
+        let onresume = self.link.callback(|_| Msg::Resume);
+        let oncancel = self.link.callback(|_| Msg::Cancel);
         let in_progress = self.file.is_some();
+        let can_resend = self
+            .encoder
+            .as_ref()
+            .is_some_and(|e| e.content_len() > 0 && e.mode() != Mode::Fountain);
         let selected_version = if let Version::Normal(v) = self.version {
             v
         } else {
@@ -362,7 +833,8 @@ impl Component for SendPage {
                         {
                             for (1..=40).map(|version| {
                                 let vs = version.to_string();
-                                html!{ <option value={ vs.clone() } selected={ selected_version == version }>{ vs }</option> }
+                                let usable = block_size_of(version, self.ec_level) >= MIN_BLOCK_SIZE;
+                                html!{ <option value={ vs.clone() } selected={ selected_version == version } disabled={ !usable }>{ vs }</option> }
                             })
                         }
                         </select>
@@ -381,26 +853,132 @@ impl Component for SendPage {
                         <label for="pixel">{ "pixels/cell:"}</label>
                         <select id="pixel" disabled={in_progress} onchange={&onchange}>
                         {
-                            for (3..=16).map(|s| {
-                                html!{ <option value={ s.to_string() } selected={ self.pixel_size as usize == s }>{ s.to_string() }</option> }
+                            for (MIN_PIXEL_SIZE..=MAX_PIXEL_SIZE).map(|s| {
+                                html!{ <option value={ s.to_string() } selected={ self.pixel_size == s }>{ s.to_string() }</option> }
+                            })
+                        }
+                        </select>
+                    </div>
+                    <div class="form-block">
+                        <label for="grid-rows">{ "並べる数(縦×横):"}</label>
+                        <div>
+                            <select id="grid-rows" disabled={in_progress} onchange={&onchange}>
+                            {
+                                for (1..=MAX_GRID_SIZE).map(|n| {
+                                    html!{ <option value={ n.to_string() } selected={ self.grid_rows == n }>{ n.to_string() }</option> }
+                                })
+                            }
+                            </select>
+                            { "×" }
+                            <select id="grid-cols" disabled={in_progress} onchange={&onchange}>
+                            {
+                                for (1..=MAX_GRID_SIZE).map(|n| {
+                                    html!{ <option value={ n.to_string() } selected={ self.grid_cols == n }>{ n.to_string() }</option> }
+                                })
+                            }
+                            </select>
+                        </div>
+                    </div>
+                    <div class="form-block">
+                        <label for="color">{ "色:"}</label>
+                        <select id="color" disabled={in_progress} onchange={&onchange}>
+                            <option value="0" selected={ !self.color }>{ "モノクロ" }</option>
+                            <option value="1" selected={ self.color }>{ "RGB多重化" }</option>
+                        </select>
+                    </div>
+                    <div class="form-block">
+                        <label for="mode">{ "送信方式:"}</label>
+                        <select id="mode" disabled={in_progress} onchange={&onchange}>
+                        {
+                            for MODE_TABLE.iter().map(|(m, name)| {
+                                html!{ <option value={ (*m as u8).to_string() } selected={ self.mode == *m }>{ name }</option> }
                             })
                         }
                         </select>
                     </div>
+                    <div class="form-block">
+                        <label for="compression">{ "圧縮:"}</label>
+                        <select id="compression" disabled={in_progress} onchange={&onchange}>
+                            <option value="0" selected={ self.compression }>{ "自動" }</option>
+                            <option value="1" selected={ !self.compression }>{ "しない" }</option>
+                        </select>
+                    </div>
+                    <div class="form-block">
+                        <label for="passphrase">{ "パスフレーズ(任意):"}</label>
+                        <input type="password" id="passphrase" value={self.passphrase.clone()} oninput={&oninput} disabled={in_progress} />
+                    </div>
                     <div class="form-block">
                         <label for="interval">{ "送信間隔[ms]:"}</label>
                         <input type="number" id="interval" value={self.send_interval.to_string()} oninput={&oninput} disabled={in_progress} />
                     </div>
+                    <div class="form-block">
+                        <label for="meta-interval">{ "メタデータ間隔[フレーム]:"}</label>
+                        <input type="number" id="meta-interval" value={self.meta_interval.to_string()} oninput={&oninput} disabled={in_progress || self.mode == Mode::Sequential} />
+                    </div>
+                    <div class="form-block">
+                        <label for="settings-code">{ "設定コード:" }</label>
+                        <div>
+                            <input type="text" id="settings-code" value={self.settings_code.clone()} oninput={&oninput} disabled={in_progress} />
+                            <button onclick={onapply} disabled={in_progress || self.settings_code.is_empty()}>{ "適用" }</button>
+                        </div>
+                    </div>
                     <input type="file" id="input-file" oninput={onstart} disabled={in_progress} />
                     <label for="input-file" class="send-file-label" disabled={in_progress}>{ "ファイルを選んで送信を開始する" }</label>
+                    {
+                        if in_progress {
+                            html!{
+                                <div class="controls">
+                                    {
+                                        if self.paused {
+                                            html!{ <button onclick={onresume}>{ "再開" }</button> }
+                                        } else {
+                                            html!{ <button onclick={onpause} disabled={!self.running}>{ "一時停止" }</button> }
+                                        }
+                                    }
+                                    <button onclick={oncancel}>{ if self.running || self.paused || self.encoder.is_none() { "中止" } else { "終了" } }</button>
+                                </div>
+                            }
+                        } else {
+                            html!{ <></> }
+                        }
+                    }
+                    {
+                        if can_resend {
+                            html!{
+                                <div class="form-block resend">
+                                    <label for="resend-code">{ "再送コード:" }</label>
+                                    <div>
+                                        <input type="text" id="resend-code" value={self.resend_code.clone()} oninput={&oninput} />
+                                        <button onclick={onresend} disabled={self.resend_code.is_empty()}>{ "欠落ブロックを再送" }</button>
+                                    </div>
+                                </div>
+                            }
+                        } else {
+                            html!{ <></> }
+                        }
+                    }
                 </div>
+                {
+                    // 暗号化するのはファイルの内容だけで、メタデータは受信側が読めるように平文で送る
+                    if self.passphrase.is_empty() {
+                        html!{ <></> }
+                    } else {
+                        html!{ <div class="note">{ "ファイル名とサイズは暗号化されずに送信されます" }</div> }
+                    }
+                }
+                { if in_progress { self.progress_view() } else { html!{ <></> } } }
+                {
+                    for self.resend_error.iter().chain(&self.settings_error).chain(&self.read_error).map(|e| {
+                        html!{ <div class="error">{ e }</div> }
+                    })
+                }
                 <canvas id="qrcode" ref=self.canvas.clone() />
             </div>
         }
     }
 }
 
-fn to_ec_level(v: u16) -> Option<EcLevel> {
+pub fn to_ec_level(v: u16) -> Option<EcLevel> {
     match v {
         0 => Some(EcLevel::L),
         1 => Some(EcLevel::M),
@@ -418,52 +996,3 @@ fn get_event_target_element_id(event: InputEvent) -> Option<String> {
     }
     None
 }
-
-// [version - 1][ec level]
-const BINARY_SIZE_TABLE: [[u16; 4]; 40] = [
-    [17, 14, 11, 7],
-    [32, 26, 20, 14],
-    [53, 42, 32, 24],
-    [78, 62, 46, 34],
-    [106, 84, 60, 44],
-    [134, 106, 74, 58],
-    [154, 122, 86, 64],
-    [192, 152, 108, 84],
-    [230, 180, 130, 98],
-    [271, 213, 151, 119],
-    [321, 251, 177, 137],
-    [367, 287, 203, 155],
-    [425, 311, 241, 177],
-    [458, 362, 258, 194],
-    [520, 412, 292, 220],
-    [586, 450, 322, 250],
-    [644, 504, 364, 280],
-    [718, 560, 394, 310],
-    [792, 624, 442, 338],
-    [858, 666, 482, 382],
-    [929, 711, 509, 403],
-    [1003, 779, 565, 439],
-    [1091, 857, 611, 461],
-    [1171, 911, 661, 511],
-    [1273, 997, 715, 535],
-    [1367, 1059, 751, 593],
-    [1465, 1125, 805, 625],
-    [1528, 1190, 868, 658],
-    [1628, 1264, 908, 698],
-    [1732, 1370, 982, 742],
-    [1840, 1452, 1030, 790],
-    [1952, 1538, 1112, 842],
-    [2068, 1628, 1168, 898],
-    [2188, 1722, 1228, 958],
-    [2303, 1809, 1283, 983],
-    [2431, 1911, 1351, 1051],
-    [2563, 1989, 1423, 1093],
-    [2699, 2099, 1499, 1139],
-    [2809, 2213, 1579, 1219],
-    [2953 - 2, 2331 - 2, 1663 - 2, 1273 - 2],
-];
-
-#[derive(Serialize)]
-pub struct Context2DAttributes {
-    alpha: bool,
-}
@@ -0,0 +1,551 @@
+// 送信ページと同じ画面を、画面共有やキャプチャで起こる劣化を加えてから受信ページと同じ手順で読み取る
+// ループバックのシミュレータ。ブラウザを使わずに、バージョン・誤り訂正レベル・ピクセル数ごとの
+// 成功率と実効速度を比べる
+
+use qrcode::EcLevel;
+use quircs::Quirc;
+
+use rds_filetransfer_core::capacity::block_size_of;
+use rds_filetransfer_core::detect::{self, Binarize, Options};
+use rds_filetransfer_core::header::Mode;
+use rds_filetransfer_core::layout::{layout, rasterize, to_rgba};
+use rds_filetransfer_core::resend;
+use rds_filetransfer_core::{Decoder, Encoder, Settings};
+
+use crate::send::{DEFAULT_META_INTERVAL, EC_LEVEL_TABLE};
+
+// 諦めるまでに表示する画面数 [ブロックあたり]。
+// これに加えて、メタデータを取りこぼした場合に次のメタデータを待つ分だけ表示する
+const MAX_SCREENS_PER_BLOCK: u64 = 8;
+
+// 受信ページの既定と同じ読み取りの設定
+const DETECT_OPTIONS: Options = Options {
+    binarize: Binarize::LocalMean,
+    smooth: false,
+};
+
+// 送信側の設定
+#[derive(Debug, Clone, Copy)]
+pub struct Case {
+    pub version: i16,
+    pub ec_level: EcLevel,
+    pub pixel_size: u8,
+    pub mode: Mode,
+}
+
+// 画面からキャプチャまでの間に加える劣化
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Impairments {
+    // 拡大率 (DPIの違いによるリサンプリング)。1.0はそのまま
+    pub scale: f32,
+    // JPEGの品質 (1-100)。Noneは圧縮しない
+    pub jpeg_quality: Option<u8>,
+    // ぼかしの半径 [px]
+    pub blur: u32,
+    // 画面をキャプチャし損ねる確率
+    pub drop_rate: f64,
+    // 同じ画面を2回キャプチャする確率
+    pub duplicate_rate: f64,
+    // 画面の上側だけが次の画面に書き換わった状態でキャプチャする確率
+    pub tear_rate: f64,
+}
+
+impl Impairments {
+    pub const NONE: Impairments = Impairments {
+        scale: 1.0,
+        jpeg_quality: None,
+        blur: 0,
+        drop_rate: 0.0,
+        duplicate_rate: 0.0,
+        tear_rate: 0.0,
+    };
+
+    // キャプチャした画像に拡大縮小・ぼかし・JPEG圧縮の順に加える
+    fn degrade(&self, mut image: Image) -> Image {
+        if self.scale != 1.0 {
+            image = image.resample(self.scale);
+        }
+        if self.blur > 0 {
+            image = image.blur(self.blur);
+        }
+        if let Some(quality) = self.jpeg_quality {
+            image = image.jpeg(quality);
+        }
+        image
+    }
+}
+
+// 1回の送信の結果
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Outcome {
+    // 元のファイルと同じ内容が復元できた
+    pub completed: bool,
+    // 表示した画面数
+    pub screens: u64,
+    // キャプチャした画像の数 (取りこぼしと重複を含む)
+    pub captures: u64,
+    // 読み取れたQRコードの数
+    pub decoded: u64,
+}
+
+// 送信ページと同じ画面を1枚ずつ表示して、劣化させたキャプチャを受信側で読み取る。
+// 逐次送信は終端まで送った後、受信側の再送コードで欠落したブロックを再送する
+pub fn run(case: &Case, imp: &Impairments, data: &[u8], seed: u64) -> Outcome {
+    let mut rng = Rng::new(seed);
+    let settings = Settings {
+        block_size: block_size_of(case.version, case.ec_level),
+        mode: case.mode,
+        meta_interval: DEFAULT_META_INTERVAL,
+        compression: false,
+    };
+    let session = rng.next() as u32 | 1;
+    let mut encoder = Encoder::new(b"sim.bin", data, &settings, "", session);
+    let mut decoder = Decoder::new();
+    let mut qr_decoder = Quirc::default();
+    let max_screens =
+        (encoder.num_blocks() + 1) * MAX_SCREENS_PER_BLOCK + DEFAULT_META_INTERVAL as u64 * 2;
+
+    let mut outcome = Outcome::default();
+    let mut tiles = vec![encoder.meta_frame().to_vec()];
+    let mut keep_going = true;
+    let mut previous: Option<Image> = None;
+    while outcome.screens < max_screens {
+        let screen = layout(&tiles, case.ec_level, 1, 1, 1).expect("QRコードを生成できません");
+        let (w, h, pixels) = rasterize(&screen, case.pixel_size as u32);
+        let shown = Image::from_cells(w as usize, h as usize, &pixels);
+        outcome.screens += 1;
+
+        if !rng.chance(imp.drop_rate) {
+            let captured = match &previous {
+                Some(p) if rng.chance(imp.tear_rate) => {
+                    let rows = (rng.next() % shown.h as u64) as usize;
+                    p.tear(&shown, rows)
+                }
+                _ => shown.clone(),
+            };
+            let captured = imp.degrade(captured);
+            let rgba = captured.to_rgba();
+            let times = if rng.chance(imp.duplicate_rate) { 2 } else { 1 };
+            for _ in 0..times {
+                let detected = detect::detect(
+                    &mut qr_decoder,
+                    captured.w,
+                    captured.h,
+                    &rgba,
+                    DETECT_OPTIONS,
+                );
+                outcome.captures += 1;
+                for payload in &detected.payloads {
+                    if decoder.push(payload).is_ok() {
+                        outcome.decoded += 1;
+                    }
+                }
+            }
+        }
+        previous = Some(shown);
+
+        if decoder.is_complete() {
+            outcome.completed = decoder.finish(None).is_ok_and(|d| d == data);
+            break;
+        }
+        // 逐次送信の終端を表示した後は、受信側が表示する再送コードを入力したものとする。
+        // 終端の直前のメタデータも取りこぼした場合は受信側が再送コードを表示できないので失敗とする
+        if !keep_going {
+            let Some(session) = decoder.session() else {
+                break;
+            };
+            let code = resend::encode(session, decoder.missing().into_iter());
+            let (_, ranges) = resend::decode(&code).expect("再送コードを解析できません");
+            encoder.queue_resend(&ranges);
+        }
+        (tiles, keep_going) = encoder.next_tiles(1);
+    }
+    outcome
+}
+
+// 同じ設定で何回か送信した結果のまとめ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub trials: u32,
+    pub successes: u32,
+    // 成功した送信の平均画面数
+    pub screens: f64,
+    // キャプチャした画像のうち、QRコードが読み取れた割合
+    pub decode_rate: f64,
+    // 成功した送信の実効速度 [バイト/秒]
+    pub throughput: f64,
+}
+
+// シードを変えてtrials回送信する。intervalは1画面の表示時間 [ミリ秒]
+pub fn evaluate(
+    case: &Case,
+    imp: &Impairments,
+    data: &[u8],
+    trials: u32,
+    interval: u16,
+) -> Summary {
+    let outcomes: Vec<Outcome> = (0..trials as u64)
+        .map(|seed| run(case, imp, data, seed))
+        .collect();
+    let succeeded: Vec<&Outcome> = outcomes.iter().filter(|o| o.completed).collect();
+    let screens = if succeeded.is_empty() {
+        0.0
+    } else {
+        succeeded.iter().map(|o| o.screens).sum::<u64>() as f64 / succeeded.len() as f64
+    };
+    let captures: u64 = outcomes.iter().map(|o| o.captures).sum();
+    let decoded: u64 = outcomes.iter().map(|o| o.decoded).sum();
+    Summary {
+        trials,
+        successes: succeeded.len() as u32,
+        screens,
+        decode_rate: if captures == 0 {
+            0.0
+        } else {
+            decoded as f64 / captures as f64
+        },
+        throughput: if screens == 0.0 {
+            0.0
+        } else {
+            data.len() as f64 / (screens * interval as f64 / 1000.0)
+        },
+    }
+}
+
+pub const REPORT_HEADER: &str = "バージョン  EC  ピクセル  成功      読み取り率  画面数   実効速度";
+
+// REPORT_HEADERに合わせた1行
+pub fn report_line(case: &Case, s: &Summary) -> String {
+    format!(
+        "{:>10}  {:>2}  {:>8}  {:>3}/{:<3}  {:>9.0}%  {:>7.1}  {:>6.0} B/s",
+        case.version,
+        EC_LEVEL_TABLE[case.ec_level as usize],
+        case.pixel_size,
+        s.successes,
+        s.trials,
+        s.decode_rate * 100.0,
+        s.screens,
+        s.throughput,
+    )
+}
+
+// キャプチャした画像 (RGB)
+#[derive(Debug, Clone, PartialEq)]
+struct Image {
+    w: usize,
+    h: usize,
+    rgb: Vec<u8>,
+}
+
+impl Image {
+    fn from_cells(w: usize, h: usize, pixels: &[u8]) -> Self {
+        let rgb = to_rgba(pixels)
+            .chunks(4)
+            .flat_map(|p| [p[0], p[1], p[2]])
+            .collect();
+        Image { w, h, rgb }
+    }
+
+    fn to_rgba(&self) -> Vec<u8> {
+        self.rgb
+            .chunks(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect()
+    }
+
+    // 上からrows行をnextに置き換える (表示の更新途中でキャプチャした画面)
+    fn tear(&self, next: &Image, rows: usize) -> Image {
+        let mut torn = next.clone();
+        if self.w == next.w && self.h == next.h {
+            let split = rows.min(self.h) * self.w * 3;
+            torn.rgb[split..].copy_from_slice(&self.rgb[split..]);
+        }
+        torn
+    }
+
+    // 双線形補間で拡大縮小する
+    fn resample(&self, scale: f32) -> Image {
+        let w = ((self.w as f32 * scale).round() as usize).max(1);
+        let h = ((self.h as f32 * scale).round() as usize).max(1);
+        let source = |v: usize, n: usize| {
+            let s = ((v as f32 + 0.5) / scale - 0.5).clamp(0.0, (n - 1) as f32);
+            let i = s.floor() as usize;
+            (i, (i + 1).min(n - 1), s - i as f32)
+        };
+        let mut rgb = Vec::with_capacity(w * h * 3);
+        for y in 0..h {
+            let (y0, y1, fy) = source(y, self.h);
+            for x in 0..w {
+                let (x0, x1, fx) = source(x, self.w);
+                for c in 0..3 {
+                    let at = |x: usize, y: usize| self.rgb[(y * self.w + x) * 3 + c] as f32;
+                    let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+                    let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+                    rgb.push((top * (1.0 - fy) + bottom * fy).round() as u8);
+                }
+            }
+        }
+        Image { w, h, rgb }
+    }
+
+    // 半径radiusの箱型フィルタで横、縦の順にぼかす。端は端の画素を繰り返す
+    fn blur(&self, radius: u32) -> Image {
+        let r = radius as isize;
+        // startから間隔strideで並ぶlen画素を、窓をずらしながら合計を更新してぼかす
+        let pass = |src: &[u8], dst: &mut [u8], start: usize, len: usize, stride: usize| {
+            let at = |i: isize| src[start + i.clamp(0, len as isize - 1) as usize * stride] as u32;
+            let mut sum: u32 = (-r..=r).map(at).sum();
+            for i in 0..len as isize {
+                dst[start + i as usize * stride] = (sum / (2 * radius + 1)) as u8;
+                sum = sum + at(i + r + 1) - at(i - r);
+            }
+        };
+        let (w, h) = (self.w, self.h);
+        let mut horizontal = vec![0; self.rgb.len()];
+        for y in 0..h {
+            for c in 0..3 {
+                pass(&self.rgb, &mut horizontal, y * w * 3 + c, w, 3);
+            }
+        }
+        let mut rgb = vec![0; self.rgb.len()];
+        for x in 0..w {
+            for c in 0..3 {
+                pass(&horizontal, &mut rgb, x * 3 + c, h, w * 3);
+            }
+        }
+        Image { w, h, rgb }
+    }
+
+    // JPEGに圧縮して戻す
+    fn jpeg(&self, quality: u8) -> Image {
+        let mut buf = Vec::new();
+        image::jpeg::JPEGEncoder::new_with_quality(&mut buf, quality.clamp(1, 100))
+            .encode(
+                &self.rgb,
+                self.w as u32,
+                self.h as u32,
+                image::ColorType::RGB(8),
+            )
+            .expect("JPEGに圧縮できません");
+        let decoded = image::load_from_memory_with_format(&buf, image::ImageFormat::JPEG)
+            .expect("JPEGを展開できません")
+            .to_rgb();
+        Image {
+            w: decoded.width() as usize,
+            h: decoded.height() as usize,
+            rgb: decoded.into_raw(),
+        }
+    }
+}
+
+// 結果を再現できるように、シードから決まる乱数 (xorshift64*) を使う
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // 確率pで真
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(len: usize) -> Vec<u8> {
+        let mut rng = Rng::new(42);
+        (0..len).map(|_| rng.next() as u8).collect()
+    }
+
+    fn gradient(w: usize, h: usize) -> Image {
+        let rgb = (0..w * h)
+            .flat_map(|i| [(i % w * 255 / w) as u8, (i / w * 255 / h) as u8, 128])
+            .collect();
+        Image { w, h, rgb }
+    }
+
+    #[test]
+    fn resample_size() {
+        let image = gradient(100, 60);
+        let scaled = image.resample(1.5);
+        assert_eq!(
+            (scaled.w, scaled.h, scaled.rgb.len()),
+            (150, 90, 150 * 90 * 3)
+        );
+        let scaled = image.resample(0.5);
+        assert_eq!((scaled.w, scaled.h), (50, 30));
+        assert_eq!(image.resample(1.0), image);
+    }
+
+    #[test]
+    fn blur_keeps_uniform_image() {
+        let image = Image {
+            w: 20,
+            h: 10,
+            rgb: vec![77; 20 * 10 * 3],
+        };
+        assert_eq!(image.blur(3), image);
+        // 境界はなだらかになる
+        let mut edge = image.clone();
+        edge.rgb[..10 * 10 * 3].fill(0);
+        let blurred = Image {
+            w: 10,
+            h: 20,
+            rgb: edge.rgb,
+        }
+        .blur(2);
+        let at = |y: usize| blurred.rgb[y * 10 * 3];
+        assert!(at(0) == 0 && at(19) == 77);
+        assert!(at(9) > 0 && at(9) < 77);
+    }
+
+    #[test]
+    fn tear_mixes_rows() {
+        let old = Image {
+            w: 4,
+            h: 4,
+            rgb: vec![0; 48],
+        };
+        let new = Image {
+            w: 4,
+            h: 4,
+            rgb: vec![255; 48],
+        };
+        let torn = old.tear(&new, 1);
+        assert!(torn.rgb[..12].iter().all(|&v| v == 255));
+        assert!(torn.rgb[12..].iter().all(|&v| v == 0));
+        assert_eq!(old.tear(&new, 10), new);
+    }
+
+    #[test]
+    fn jpeg_keeps_size() {
+        let image = gradient(33, 17);
+        let decoded = image.jpeg(50);
+        assert_eq!((decoded.w, decoded.h), (33, 17));
+        let diff = image
+            .rgb
+            .iter()
+            .zip(&decoded.rgb)
+            .map(|(&a, &b)| a.abs_diff(b) as u32)
+            .max()
+            .unwrap();
+        assert!(diff < 64);
+    }
+
+    #[test]
+    fn rng_is_deterministic() {
+        let (mut a, mut b) = (Rng::new(7), Rng::new(7));
+        assert!((0..100).all(|_| a.next() == b.next()));
+        assert!(!Rng::new(1).chance(0.0));
+        assert!(Rng::new(1).chance(1.0));
+    }
+
+    // 劣化なしではどの設定でも全ての画面が読み取れる
+    #[test]
+    fn lossless_channel() {
+        let data = file(1500);
+        for mode in [Mode::Sequential, Mode::Fountain, Mode::Loop] {
+            let case = Case {
+                version: 10,
+                ec_level: EcLevel::M,
+                pixel_size: 3,
+                mode,
+            };
+            let outcome = run(&case, &Impairments::NONE, &data, 0);
+            assert!(outcome.completed, "{:?}", mode);
+            assert_eq!(outcome.captures, outcome.screens);
+            assert_eq!(outcome.decoded, outcome.screens);
+        }
+    }
+
+    // 取りこぼしや更新途中のキャプチャがあっても、再送で揃う
+    #[test]
+    fn sequential_recovers_from_loss() {
+        let data = file(3000);
+        let case = Case {
+            version: 8,
+            ec_level: EcLevel::L,
+            pixel_size: 3,
+            mode: Mode::Sequential,
+        };
+        let imp = Impairments {
+            drop_rate: 0.2,
+            duplicate_rate: 0.2,
+            tear_rate: 0.2,
+            ..Impairments::NONE
+        };
+        // このシードでは最初のメタデータの画面を取りこぼすが、終端の直前のメタデータで再送コードを表示できる
+        let outcome = run(&case, &imp, &data, 2);
+        assert!(outcome.completed);
+        assert!(outcome.captures < outcome.screens * 2);
+        assert!(outcome.decoded < outcome.captures);
+    }
+
+    // 表示倍率が違っても、セルが十分大きければ読み取れる
+    #[test]
+    fn resampled_screen() {
+        let data = file(400);
+        let case = Case {
+            version: 10,
+            ec_level: EcLevel::M,
+            pixel_size: 6,
+            mode: Mode::Fountain,
+        };
+        for scale in [0.75, 1.5] {
+            let imp = Impairments {
+                scale,
+                ..Impairments::NONE
+            };
+            assert!(run(&case, &imp, &data, 0).completed, "{}", scale);
+        }
+    }
+
+    // 画面共有程度の劣化
+    fn screen_share() -> Impairments {
+        Impairments {
+            jpeg_quality: Some(85),
+            blur: 1,
+            drop_rate: 0.1,
+            duplicate_rate: 0.1,
+            tear_rate: 0.1,
+            ..Impairments::NONE
+        }
+    }
+
+    // 画面共有程度の劣化での設定ごとの成功率と実効速度。表は cargo test -- --nocapture で表示される。
+    // 3ピクセルではぼかしでセルが潰れて読み取れないことがあるので、成功を確かめるのは5ピクセルだけ
+    #[test]
+    fn impairment_matrix() {
+        let data = file(300);
+        println!("{}", REPORT_HEADER);
+        for version in [5, 10] {
+            for ec_level in [EcLevel::L, EcLevel::M] {
+                for pixel_size in [3, 5] {
+                    let case = Case {
+                        version,
+                        ec_level,
+                        pixel_size,
+                        mode: Mode::Fountain,
+                    };
+                    let summary = evaluate(&case, &screen_share(), &data, 2, 500);
+                    println!("{}", report_line(&case, &summary));
+                    if pixel_size >= 5 {
+                        assert_eq!(summary.successes, summary.trials, "{:?}", case);
+                        assert!(summary.throughput > 0.0);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,171 @@
+// 受信途中のブロックをIndexedDBに保存し、ページを開き直しても受信を再開できるようにする
+//
+// transfers: 転送キー -> メタデータフレーム (ヘッダ + ペイロード)
+// blocks: "転送キー/ブロック番号" -> ブロック番号(8B) + ブロックのデータ
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use js_sys::{Array, Uint8Array};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{IdbDatabase, IdbKeyRange, IdbObjectStore, IdbRequest, IdbTransactionMode};
+use yew::utils::window;
+
+const DB_NAME: &str = "rds-filetransfer";
+const DB_VERSION: u32 = 1;
+const TRANSFERS: &str = "transfers";
+const BLOCKS: &str = "blocks";
+
+#[derive(Clone)]
+pub struct Store {
+    db: IdbDatabase,
+}
+
+// IndexedDBが使えない場合はNoneを渡す
+pub fn open(cb: impl FnOnce(Option<Store>) + 'static) {
+    let factory = match window().indexed_db() {
+        Ok(Some(f)) => f,
+        _ => return cb(None),
+    };
+    let request = match factory.open_with_u32(DB_NAME, DB_VERSION) {
+        Ok(r) => r,
+        Err(_) => return cb(None),
+    };
+    let request2 = request.clone();
+    let on_upgrade = Closure::once(move || {
+        if let Ok(db) = request2.result().and_then(|r| r.dyn_into::<IdbDatabase>()) {
+            let _ = db.create_object_store(TRANSFERS);
+            let _ = db.create_object_store(BLOCKS);
+        }
+    });
+    request.set_onupgradeneeded(Some(on_upgrade.as_ref().unchecked_ref()));
+    on_upgrade.forget();
+    on_result(&request, move |v| {
+        cb(v.and_then(|r| r.dyn_into::<IdbDatabase>().ok())
+            .map(|db| Store { db }))
+    });
+}
+
+// 成功した場合は結果を、失敗した場合はNoneを渡す
+fn on_result(request: &IdbRequest, cb: impl FnOnce(Option<JsValue>) + 'static) {
+    let request2 = request.clone();
+    let cb = Rc::new(RefCell::new(Some(cb)));
+    let cb2 = cb.clone();
+    let on_success = Closure::once(move || {
+        if let Some(cb) = cb.borrow_mut().take() {
+            cb(request2.result().ok());
+        }
+    });
+    let on_error = Closure::once(move || {
+        if let Some(cb) = cb2.borrow_mut().take() {
+            cb(None);
+        }
+    });
+    request.set_onsuccess(Some(on_success.as_ref().unchecked_ref()));
+    request.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+    on_success.forget();
+    on_error.forget();
+}
+
+fn block_key(key: &str, index: u64) -> JsValue {
+    // 辞書順で範囲指定できるように桁を揃える
+    JsValue::from_str(&format!("{}/{:016x}", key, index))
+}
+
+fn block_range(key: &str) -> Option<IdbKeyRange> {
+    IdbKeyRange::bound(
+        &JsValue::from_str(&format!("{}/", key)),
+        &JsValue::from_str(&format!("{}/g", key)),
+    )
+    .ok()
+}
+
+impl Store {
+    fn object_store(&self, name: &str, mode: IdbTransactionMode) -> Option<IdbObjectStore> {
+        self.db
+            .transaction_with_str_and_mode(name, mode)
+            .ok()?
+            .object_store(name)
+            .ok()
+    }
+
+    pub fn put_transfer(&self, key: &str, meta_frame: &[u8]) {
+        if let Some(store) = self.object_store(TRANSFERS, IdbTransactionMode::Readwrite) {
+            let _ = store.put_with_key(
+                &Uint8Array::from(meta_frame).into(),
+                &JsValue::from_str(key),
+            );
+        }
+    }
+
+    pub fn put_block(&self, key: &str, index: u64, data: &[u8]) {
+        if let Some(store) = self.object_store(BLOCKS, IdbTransactionMode::Readwrite) {
+            let mut value = index.to_le_bytes().to_vec();
+            value.extend_from_slice(data);
+            let _ =
+                store.put_with_key(&Uint8Array::from(&value[..]).into(), &block_key(key, index));
+        }
+    }
+
+    // 保存されている全ての転送のメタデータフレームを返す
+    pub fn list_transfers(&self, cb: impl FnOnce(Vec<Vec<u8>>) + 'static) {
+        let request = match self
+            .object_store(TRANSFERS, IdbTransactionMode::Readonly)
+            .and_then(|s| s.get_all().ok())
+        {
+            Some(r) => r,
+            None => return cb(Vec::new()),
+        };
+        on_result(&request, move |v| cb(to_byte_arrays(v)));
+    }
+
+    // 保存されているブロックを (ブロック番号, データ) の一覧で返す
+    pub fn load_blocks(&self, key: &str, cb: impl FnOnce(Vec<(u64, Vec<u8>)>) + 'static) {
+        let request = match (
+            self.object_store(BLOCKS, IdbTransactionMode::Readonly),
+            block_range(key),
+        ) {
+            (Some(s), Some(range)) => match s.get_all_with_key(&range) {
+                Ok(r) => r,
+                Err(_) => return cb(Vec::new()),
+            },
+            _ => return cb(Vec::new()),
+        };
+        on_result(&request, move |v| {
+            let blocks = to_byte_arrays(v)
+                .into_iter()
+                .filter(|b| b.len() >= 8)
+                .map(|b| {
+                    let mut index = [0u8; 8];
+                    index.copy_from_slice(&b[..8]);
+                    (u64::from_le_bytes(index), b[8..].to_vec())
+                })
+                .collect();
+            cb(blocks)
+        });
+    }
+
+    pub fn delete(&self, key: &str) {
+        if let Some(store) = self.object_store(TRANSFERS, IdbTransactionMode::Readwrite) {
+            let _ = store.delete(&JsValue::from_str(key));
+        }
+        if let (Some(store), Some(range)) = (
+            self.object_store(BLOCKS, IdbTransactionMode::Readwrite),
+            block_range(key),
+        ) {
+            let _ = store.delete(&range);
+        }
+    }
+}
+
+fn to_byte_arrays(v: Option<JsValue>) -> Vec<Vec<u8>> {
+    match v.and_then(|v| v.dyn_into::<Array>().ok()) {
+        Some(array) => array
+            .iter()
+            .filter_map(|x| x.dyn_into::<Uint8Array>().ok())
+            .map(|x| x.to_vec())
+            .collect(),
+        None => Vec::new(),
+    }
+}
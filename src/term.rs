@@ -0,0 +1,189 @@
+// 端末へのQRコードの表示
+//
+// sixelやkittyの画像表示に対応していない端末でも表示できるように、上半分のブロック文字(▀)の
+// 前景色と背景色で縦2ピクセルを1文字に描く方法を用意する。
+// 画像はセルの値 [Rが暗 | Gが暗 << 1 | Bが暗 << 2] をピクセルにしたものを受け取る。
+
+use std::io::{self, Write};
+
+use miniz_oxide::deflate::compress_to_vec_zlib;
+
+use rds_filetransfer_core::layout::cell_color;
+
+// kittyの画像データは4096バイトずつに分けて送る
+const KITTY_CHUNK_SIZE: usize = 4096;
+const BASE64_TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Graphics {
+    Blocks,
+    Sixel,
+    Kitty,
+}
+
+impl Graphics {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "blocks" => Some(Self::Blocks),
+            "sixel" => Some(Self::Sixel),
+            "kitty" => Some(Self::Kitty),
+            _ => None,
+        }
+    }
+
+    // 端末への問い合わせは応答を読むのに端末の設定を変える必要があるので、環境変数から推測する
+    pub fn detect() -> Self {
+        let var = |name: &str| std::env::var(name).unwrap_or_default();
+        let term = var("TERM");
+        let program = var("TERM_PROGRAM");
+        if term == "xterm-kitty"
+            || !var("KITTY_WINDOW_ID").is_empty()
+            || ["ghostty", "WezTerm"].contains(&program.as_str())
+        {
+            Self::Kitty
+        } else if term.contains("sixel") || ["mlterm", "foot"].contains(&term.as_str()) {
+            Self::Sixel
+        } else {
+            Self::Blocks
+        }
+    }
+}
+
+// 画面を消して、カーソルを左上に移動する
+pub fn clear(out: &mut impl Write) -> io::Result<()> {
+    out.write_all(b"\x1b[2J\x1b[H")
+}
+
+// カーソルを左上に戻して、前の画面に上書きする
+pub fn draw(
+    graphics: Graphics,
+    out: &mut impl Write,
+    w: u32,
+    h: u32,
+    pixels: &[u8],
+) -> io::Result<()> {
+    out.write_all(b"\x1b[H")?;
+    match graphics {
+        Graphics::Blocks => half_blocks(out, w, h, pixels)?,
+        Graphics::Sixel => sixel(out, w, h, pixels)?,
+        Graphics::Kitty => kitty(out, w, h, pixels)?,
+    }
+    out.flush()
+}
+
+fn half_blocks(out: &mut impl Write, w: u32, h: u32, pixels: &[u8]) -> io::Result<()> {
+    let (w, h) = (w as usize, h as usize);
+    for y in (0..h).step_by(2) {
+        // 色が変わった場合だけエスケープシーケンスを出す
+        let mut current = None;
+        for x in 0..w {
+            let top = pixels[y * w + x];
+            // 高さが奇数の場合、最後の行の下半分は余白と同じ白にする
+            let bottom = if y + 1 < h {
+                pixels[(y + 1) * w + x]
+            } else {
+                0
+            };
+            if current != Some((top, bottom)) {
+                let [fr, fg, fb] = cell_color(top);
+                let [br, bg, bb] = cell_color(bottom);
+                write!(
+                    out,
+                    "\x1b[38;2;{};{};{};48;2;{};{};{}m",
+                    fr, fg, fb, br, bg, bb
+                )?;
+                current = Some((top, bottom));
+            }
+            out.write_all("▀".as_bytes())?;
+        }
+        out.write_all(b"\x1b[0m\r\n")?;
+    }
+    Ok(())
+}
+
+fn sixel(out: &mut impl Write, w: u32, h: u32, pixels: &[u8]) -> io::Result<()> {
+    let (w, h) = (w as usize, h as usize);
+    write!(out, "\x1bPq\"1;1;{};{}", w, h)?;
+    for mask in 0..8u8 {
+        // sixelの色は0〜100の割合で指定する
+        let [r, g, b] = cell_color(mask).map(|c| c as u32 * 100 / 255);
+        write!(out, "#{};2;{};{};{}", mask, r, g, b)?;
+    }
+    // 縦6ピクセルを1行として、色ごとに重ねて描く
+    for top in (0..h).step_by(6) {
+        let rows = (h - top).min(6);
+        let mut first = true;
+        for mask in 0..8u8 {
+            let band: Vec<u8> = (0..w)
+                .map(|x| {
+                    (0..rows).fold(0, |bits, k| {
+                        bits | (((pixels[(top + k) * w + x] == mask) as u8) << k)
+                    })
+                })
+                .collect();
+            if band.iter().all(|&b| b == 0) {
+                continue;
+            }
+            if !first {
+                // 同じ行の先頭に戻る
+                out.write_all(b"$")?;
+            }
+            first = false;
+            write!(out, "#{}", mask)?;
+            // 同じ文字の繰り返しはランレングスで短くする
+            let mut x = 0;
+            while x < w {
+                let run = band[x..].iter().take_while(|&&b| b == band[x]).count();
+                let c = (band[x] + 63) as char;
+                if run > 3 {
+                    write!(out, "!{}{}", run, c)?;
+                } else {
+                    (0..run).try_for_each(|_| write!(out, "{}", c))?;
+                }
+                x += run;
+            }
+        }
+        out.write_all(b"-")?;
+    }
+    out.write_all(b"\x1b\\")
+}
+
+fn kitty(out: &mut impl Write, w: u32, h: u32, pixels: &[u8]) -> io::Result<()> {
+    let rgb: Vec<u8> = pixels.iter().flat_map(|&p| cell_color(p)).collect();
+    let encoded = base64(&compress_to_vec_zlib(&rgb, 6));
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = (i + 1 < chunks.len()) as u8;
+        if i == 0 {
+            // 同じ画像ID・配置IDで送り直すと前の画面が置き換わる。カーソルは動かさない
+            write!(
+                out,
+                "\x1b_Ga=T,f=24,o=z,s={},v={},i=1,p=1,q=2,C=1,m={};",
+                w, h, more
+            )?;
+        } else {
+            write!(out, "\x1b_Gm={};", more)?;
+        }
+        out.write_all(chunk)?;
+        out.write_all(b"\x1b\\")?;
+    }
+    Ok(())
+}
+
+fn base64(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(BASE64_TABLE[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
@@ -0,0 +1,21 @@
+// 送信ページと受信ページで共通に使う表示用の関数
+
+use js_sys::Reflect;
+use wasm_bindgen::JsValue;
+
+pub fn error_message(e: &JsValue) -> String {
+    Reflect::get(e, &JsValue::from_str("message"))
+        .ok()
+        .and_then(|m| m.as_string())
+        .or_else(|| e.as_string())
+        .unwrap_or_else(|| "不明なエラー".to_string())
+}
+
+pub fn format_seconds(t: f64) -> String {
+    let t = t.ceil() as u64;
+    if t >= 60 {
+        format!("{}分{}秒", t / 60, t % 60)
+    } else {
+        format!("{}秒", t)
+    }
+}
@@ -0,0 +1,130 @@
+// QRコードの読み取りを行うWeb Worker(src/bin/worker.rs)のプール
+//
+// キャプチャした画像のバッファは転送(transfer)するので、メインスレッドではコピーしない。
+
+use std::rc::Rc;
+
+use js_sys::{Array, ArrayBuffer, Uint8Array, Uint8ClampedArray};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{Blob, BlobPropertyBag, ImageData, MessageEvent, Url, Worker};
+use yew::utils::window;
+
+use rds_filetransfer_core::detect::{Detected, Options, Rect};
+
+pub struct WorkerPool {
+    workers: Vec<Worker>,
+    // 読み取り中の画像があるか
+    busy: Vec<bool>,
+}
+
+// on_resultには読み取り結果とWorkerの番号を、on_errorにはWorkerを起動できなかったことを通知する
+pub fn spawn(
+    size: usize,
+    on_result: Rc<dyn Fn(usize, Detected)>,
+    on_error: Rc<dyn Fn()>,
+) -> Option<WorkerPool> {
+    let url = worker_script_url()?;
+    let mut workers = Vec::with_capacity(size);
+    for i in 0..size {
+        let worker = Worker::new(&url).ok()?;
+        let on_result = on_result.clone();
+        let onmessage = Closure::wrap(Box::new(move |e: MessageEvent| {
+            on_result(i, to_detected(e.data()));
+        }) as Box<dyn FnMut(MessageEvent)>);
+        worker.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+        onmessage.forget();
+        let on_error = on_error.clone();
+        let onerror = Closure::wrap(Box::new(move || on_error()) as Box<dyn Fn()>);
+        worker.set_onerror(Some(onerror.as_ref().unchecked_ref()));
+        onerror.forget();
+        workers.push(worker);
+    }
+    Some(WorkerPool {
+        busy: vec![false; workers.len()],
+        workers,
+    })
+}
+
+// wasm-bindgenのno-modules形式で出力されたworker.jsを読み込むスクリプト
+fn worker_script_url() -> Option<String> {
+    let base = window().location().href().ok()?;
+    let js = Url::new_with_base("worker.js", &base).ok()?.href();
+    let wasm = Url::new_with_base("worker_bg.wasm", &base).ok()?.href();
+    let script = format!("importScripts({:?});wasm_bindgen({:?});", js, wasm);
+    let array = Array::of1(&JsValue::from_str(&script));
+    let mut props = BlobPropertyBag::new();
+    props.type_("text/javascript");
+    let blob = Blob::new_with_str_sequence_and_options(&array, &props).ok()?;
+    Url::create_object_url_with_blob(&blob).ok()
+}
+
+fn to_detected(v: JsValue) -> Detected {
+    let res = v.unchecked_into::<Array>();
+    Detected {
+        color: res.get(0).as_bool().unwrap_or(false),
+        payloads: res
+            .get(1)
+            .unchecked_into::<Array>()
+            .iter()
+            .map(|p| p.unchecked_into::<Uint8Array>().to_vec())
+            .collect(),
+        errors: res
+            .get(2)
+            .unchecked_into::<Array>()
+            .iter()
+            .filter_map(|e| e.as_string())
+            .collect(),
+        bounds: res.get(3).dyn_into::<Array>().ok().map(|b| {
+            let v = |i| b.get(i).as_f64().unwrap_or(0.0) as u32;
+            Rect {
+                x: v(0),
+                y: v(1),
+                w: v(2),
+                h: v(3),
+            }
+        }),
+    }
+}
+
+impl WorkerPool {
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_busy(&self, i: usize) -> bool {
+        self.busy[i]
+    }
+
+    pub fn done(&mut self, i: usize) {
+        self.busy[i] = false;
+    }
+
+    // (x, y)は画像を切り出した位置
+    pub fn post(&mut self, i: usize, img: &ImageData, x: u32, y: u32, opts: Options) {
+        self.busy[i] = true;
+        let data = js_sys::Reflect::get(img, &JsValue::from_str("data"))
+            .unwrap()
+            .unchecked_into::<Uint8ClampedArray>();
+        let buffer: ArrayBuffer = data.buffer();
+        let req = Array::new();
+        req.push(&JsValue::from(img.width()));
+        req.push(&JsValue::from(img.height()));
+        req.push(&buffer);
+        req.push(&JsValue::from(x));
+        req.push(&JsValue::from(y));
+        req.push(&JsValue::from(opts.binarize as u8));
+        req.push(&JsValue::from(opts.smooth));
+        self.workers[i]
+            .post_message_with_transfer(&req, &Array::of1(&buffer))
+            .unwrap();
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        for worker in &self.workers {
+            worker.terminate();
+        }
+    }
+}
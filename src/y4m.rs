@@ -0,0 +1,199 @@
+// YUV4MPEG2 (Y4M) 形式の非圧縮動画の読み書き
+//
+// ffmpegなどで録画を変換したものを標準入力から受け取るために使う。
+//   ffmpeg -i rec.mp4 -f yuv4mpegpipe - | rds-filetransfer decode -
+// エンコーダの出力はffmpegでほかの形式に変換できる。
+//   rds-filetransfer encode -o - file.zip | ffmpeg -i - rec.mp4
+
+use std::io::{self, BufRead, Write};
+
+const SIGNATURE: &str = "YUV4MPEG2";
+
+// 色差の間引き方
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Chroma {
+    C420,
+    C422,
+    C444,
+    Mono,
+}
+
+impl Chroma {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            s if s.starts_with("420") => Some(Self::C420),
+            "422" => Some(Self::C422),
+            "444" => Some(Self::C444),
+            "mono" => Some(Self::Mono),
+            _ => None,
+        }
+    }
+
+    // 色差平面の (幅, 高さ) の縮小率
+    fn subsampling(&self) -> (usize, usize) {
+        match self {
+            Self::C420 => (2, 2),
+            Self::C422 => (2, 1),
+            Self::C444 | Self::Mono => (1, 1),
+        }
+    }
+}
+
+pub struct Reader<R: BufRead> {
+    inner: R,
+    pub width: usize,
+    pub height: usize,
+    chroma: Chroma,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_line<R: BufRead>(r: &mut R) -> io::Result<Option<String>> {
+    let mut buf = Vec::new();
+    if r.read_until(b'\n', &mut buf)? == 0 {
+        return Ok(None);
+    }
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    }
+    Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let line = read_line(&mut inner)?.ok_or_else(|| invalid("empty stream"))?;
+        let mut tokens = line.split(' ');
+        if tokens.next() != Some(SIGNATURE) {
+            return Err(invalid("not a YUV4MPEG2 stream"));
+        }
+        let (mut width, mut height, mut chroma) = (0, 0, Chroma::C420);
+        for t in tokens.filter(|t| !t.is_empty()) {
+            let (key, value) = t.split_at(1);
+            match key {
+                "W" => width = value.parse().map_err(|_| invalid("invalid width"))?,
+                "H" => height = value.parse().map_err(|_| invalid("invalid height"))?,
+                "C" => {
+                    chroma =
+                        Chroma::parse(value).ok_or_else(|| invalid("unsupported colorspace"))?
+                }
+                _ => {}
+            }
+        }
+        if width == 0 || height == 0 {
+            return Err(invalid("missing frame size"));
+        }
+        Ok(Self {
+            inner,
+            width,
+            height,
+            chroma,
+        })
+    }
+
+    fn chroma_size(&self) -> (usize, usize) {
+        let (sx, sy) = self.chroma.subsampling();
+        (self.width.div_ceil(sx), self.height.div_ceil(sy))
+    }
+
+    // 次のフレームをRGBAに変換して返す。終端ではNoneを返す
+    pub fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let line = match read_line(&mut self.inner)? {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        if !line.starts_with("FRAME") {
+            return Err(invalid("missing FRAME marker"));
+        }
+        let (w, h) = (self.width, self.height);
+        let mut y = vec![0u8; w * h];
+        self.inner.read_exact(&mut y)?;
+        if self.chroma == Chroma::Mono {
+            return Ok(Some(y.iter().flat_map(|&v| rgb(v, 128, 128)).collect()));
+        }
+        let (cw, ch) = self.chroma_size();
+        let (mut u, mut v) = (vec![0u8; cw * ch], vec![0u8; cw * ch]);
+        self.inner.read_exact(&mut u)?;
+        self.inner.read_exact(&mut v)?;
+        let (sx, sy) = self.chroma.subsampling();
+        let mut rgba = Vec::with_capacity(w * h * 4);
+        for row in 0..h {
+            for col in 0..w {
+                let c = (row / sy) * cw + col / sx;
+                rgba.extend_from_slice(&rgb(y[row * w + col], u[c], v[c]));
+            }
+        }
+        Ok(Some(rgba))
+    }
+}
+
+// 色を正確に残すため、書き込みは色差を間引かない4:4:4で行う
+pub struct Writer<W: Write> {
+    inner: W,
+    width: usize,
+    height: usize,
+}
+
+impl<W: Write> Writer<W> {
+    // フレームレートは fps_num / fps_den
+    pub fn new(
+        mut inner: W,
+        width: usize,
+        height: usize,
+        fps_num: u32,
+        fps_den: u32,
+    ) -> io::Result<Self> {
+        writeln!(
+            inner,
+            "{} W{} H{} F{}:{} Ip A1:1 C444",
+            SIGNATURE, width, height, fps_num, fps_den
+        )?;
+        Ok(Self {
+            inner,
+            width,
+            height,
+        })
+    }
+
+    pub fn write_frame(&mut self, rgba: &[u8]) -> io::Result<()> {
+        let n = self.width * self.height;
+        let mut planes = vec![0u8; n * 3];
+        for (i, px) in rgba.chunks_exact(4).take(n).enumerate() {
+            let [y, u, v] = yuv(px[0], px[1], px[2]);
+            planes[i] = y;
+            planes[n + i] = u;
+            planes[2 * n + i] = v;
+        }
+        self.inner.write_all(b"FRAME\n")?;
+        self.inner.write_all(&planes)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// RGBをBT.601 (リミテッドレンジ) のYCbCrに変換する
+fn yuv(r: u8, g: u8, b: u8) -> [u8; 3] {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let scale = |x: i32, offset: i32| (((x + 128) >> 8) + offset).clamp(0, 255) as u8;
+    [
+        scale(66 * r + 129 * g + 25 * b, 16),
+        scale(-38 * r - 74 * g + 112 * b, 128),
+        scale(112 * r - 94 * g - 18 * b, 128),
+    ]
+}
+
+// BT.601 (リミテッドレンジ) のYCbCrをRGBAに変換する
+fn rgb(y: u8, u: u8, v: u8) -> [u8; 4] {
+    let c = 298 * (y as i32 - 16);
+    let (d, e) = (u as i32 - 128, v as i32 - 128);
+    let clamp = |x: i32| ((x + 128) >> 8).clamp(0, 255) as u8;
+    [
+        clamp(c + 409 * e),
+        clamp(c - 100 * d - 208 * e),
+        clamp(c + 516 * d),
+        255,
+    ]
+}